@@ -0,0 +1,292 @@
+use crate::forwarder::forward_packet_async;
+use crate::hotplug::{Reattach, device_available};
+use crate::queue::PacketQueue;
+use crate::runner::{CaptureCounts, FLUSH_TIMEOUT, RunnerConfig, RunnerStats, open_capture};
+use crate::shutdown::ShutdownHandle;
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, timeout};
+
+/// Copies each captured packet out of libpcap's buffer so it can cross an `.await`.
+struct OwnedPacketCodec;
+
+impl pcap::PacketCodec for OwnedPacketCodec {
+    type Item = Vec<u8>;
+
+    fn decode(&mut self, packet: pcap::Packet<'_>) -> Self::Item {
+        packet.data.to_vec()
+    }
+}
+
+/// Async counterpart of [`crate::runner::forward_captured_packets_until`].
+///
+/// Capture uses pcap's nonblocking stream, so no thread is parked on the read timeout. The
+/// capture and sink halves run concurrently on the calling task and share the same bounded
+/// queue, drop policy and stats as the blocking runner. Flushing and closing the sink are
+/// bounded by [`FLUSH_TIMEOUT`] the same way, so a stalled receiver cannot hold the runner.
+pub async fn forward_captured_packets_async(
+    cfg: RunnerConfig<'_>,
+    max_packets: Option<usize>,
+    max_duration: Option<Duration>,
+    ready: Option<mpsc::Sender<()>>,
+    shutdown: &ShutdownHandle,
+) -> Result<RunnerStats> {
+    let stream = TcpStream::connect(cfg.tunnel_target)
+        .await
+        .context("failed to connect TCP tunnel target")?;
+    forward_captured_packets_to_sink(cfg, stream, max_packets, max_duration, ready, shutdown).await
+}
+
+/// Like [`forward_captured_packets_async`], but writes to a caller-provided tokio sink.
+pub async fn forward_captured_packets_to_sink<W>(
+    cfg: RunnerConfig<'_>,
+    mut sink: W,
+    max_packets: Option<usize>,
+    max_duration: Option<Duration>,
+    ready: Option<mpsc::Sender<()>>,
+    shutdown: &ShutdownHandle,
+) -> Result<RunnerStats>
+where
+    W: AsyncWrite + Unpin,
+{
+    let packets = open_packet_stream(&cfg)?;
+    if let Some(tx) = ready {
+        let _ = tx.send(());
+    }
+
+    let queue = PacketQueue::new(cfg.queue_capacity, cfg.drop_policy);
+    let deadline = max_duration.map(|limit| Instant::now() + limit);
+    let (counts, (forwarded, send_errors)) = tokio::join!(
+        async {
+            let counts = capture_with_reattach(packets, &cfg, &queue, deadline, shutdown).await;
+            queue.close();
+            counts
+        },
+        send_queued_packets(&mut sink, &queue, max_packets),
+    );
+
+    close_sink(&mut sink).await?;
+
+    let counts = counts?;
+    Ok(RunnerStats {
+        captured: counts.captured,
+        forwarded,
+        send_errors,
+        reattached: counts.reattached,
+        queue: queue.stats(),
+    })
+}
+
+type PacketStream = pcap::PacketStream<pcap::Active, OwnedPacketCodec>;
+
+fn open_packet_stream(cfg: &RunnerConfig<'_>) -> Result<PacketStream> {
+    open_capture(cfg.device_name, cfg.filter, cfg.read_timeout_ms)?
+        .setnonblock()
+        .with_context(|| format!("failed to make capture on {} nonblocking", cfg.device_name))?
+        .stream(OwnedPacketCodec)
+        .with_context(|| format!("failed to create packet stream on {}", cfg.device_name))
+}
+
+async fn capture_with_reattach(
+    mut packets: PacketStream,
+    cfg: &RunnerConfig<'_>,
+    queue: &PacketQueue,
+    deadline: Option<Instant>,
+    shutdown: &ShutdownHandle,
+) -> Result<CaptureCounts> {
+    let mut counts = CaptureCounts::default();
+    loop {
+        let err = match capture_into_queue(&mut packets, queue, deadline, shutdown, &mut counts.captured).await {
+            Ok(()) => return Ok(counts),
+            Err(err) => err,
+        };
+        let Reattach::Poll(poll) = cfg.reattach else {
+            return Err(err);
+        };
+        // Only a vanished device is worth waiting for; an error on one that is still listed
+        // would fail again right away.
+        if device_available(cfg.device_name) {
+            return Err(err);
+        }
+        // Drop the dead stream before polling so its file descriptor is released.
+        drop(packets);
+        match reopen_when_available(cfg, poll, queue, deadline, shutdown).await {
+            Some(reopened) => {
+                packets = reopened;
+                counts.reattached += 1;
+            }
+            None => return Ok(counts),
+        }
+    }
+}
+
+async fn reopen_when_available(
+    cfg: &RunnerConfig<'_>,
+    poll: Duration,
+    queue: &PacketQueue,
+    deadline: Option<Instant>,
+    shutdown: &ShutdownHandle,
+) -> Option<PacketStream> {
+    loop {
+        if device_available(cfg.device_name)
+            && let Ok(packets) = open_packet_stream(cfg)
+        {
+            return Some(packets);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(poll) => {}
+            _ = queue.closed() => return None,
+            _ = shutdown.cancelled() => return None,
+            _ = sleep_until(deadline) => return None,
+        }
+    }
+}
+
+async fn capture_into_queue(
+    packets: &mut PacketStream,
+    queue: &PacketQueue,
+    deadline: Option<Instant>,
+    shutdown: &ShutdownHandle,
+    captured: &mut usize,
+) -> Result<()> {
+    loop {
+        let next = tokio::select! {
+            next = packets.next() => next,
+            _ = queue.closed() => return Ok(()),
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = sleep_until(deadline) => return Ok(()),
+        };
+
+        match next {
+            Some(Ok(packet)) => {
+                *captured += 1;
+                // Packets are already filtered by libpcap's compiled BPF. A full `Block` queue
+                // parks the push, so cancellation has to be able to interrupt it too.
+                let pushed = tokio::select! {
+                    pushed = queue.push_async(packet) => pushed,
+                    _ = shutdown.cancelled() => false,
+                };
+                if !pushed {
+                    return Ok(());
+                }
+            }
+            Some(Err(pcap::Error::TimeoutExpired)) => continue,
+            Some(Err(err)) => return Err(err).context("packet capture failed"),
+            None => return Ok(()),
+        }
+    }
+}
+
+async fn send_queued_packets<W>(sink: &mut W, queue: &PacketQueue, max_packets: Option<usize>) -> (usize, usize)
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut forwarded = 0_usize;
+    let mut send_errors = 0_usize;
+    // Async counterpart of `bound_flush`: once capture has stopped and closed the queue, the
+    // sender gets `FLUSH_TIMEOUT` to drain it. After that the stalled write is dropped and
+    // whatever is left counts as send errors.
+    let flush_expired = async {
+        queue.closed().await;
+        tokio::time::sleep(FLUSH_TIMEOUT).await;
+    };
+    tokio::pin!(flush_expired);
+    let mut stalled = false;
+    loop {
+        if max_packets.is_some_and(|limit| forwarded >= limit) {
+            // Closing the queue tells the capture half to stop as well.
+            queue.close();
+            break;
+        }
+        let Some(packet) = queue.pop_async().await else {
+            break;
+        };
+        if stalled {
+            send_errors += 1;
+            continue;
+        }
+        let sent = tokio::select! {
+            sent = forward_packet_async(sink, &packet) => sent.is_ok(),
+            _ = &mut flush_expired => {
+                stalled = true;
+                false
+            }
+        };
+        if sent {
+            forwarded += 1;
+        } else {
+            send_errors += 1;
+        }
+    }
+    (forwarded, send_errors)
+}
+
+/// Async counterpart of [`crate::runner::close_tunnel`], with each step bounded by
+/// [`FLUSH_TIMEOUT`].
+async fn close_sink<W>(sink: &mut W) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    timeout(FLUSH_TIMEOUT, sink.flush())
+        .await
+        .context("timed out flushing TCP tunnel")?
+        .context("failed to flush TCP tunnel")?;
+    timeout(FLUSH_TIMEOUT, sink.shutdown())
+        .await
+        .context("timed out closing TCP tunnel")?
+        .context("failed to close TCP tunnel")
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{close_sink, send_queued_packets};
+    use crate::queue::{DropPolicy, PacketQueue};
+    use crate::runner::FLUSH_TIMEOUT;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::AsyncWrite;
+    use tokio::time::timeout;
+
+    /// A receiver that never takes anything.
+    struct StalledSink;
+
+    impl AsyncWrite for StalledSink {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn bound_flush_gives_up_on_a_stalled_sink() {
+        let queue = PacketQueue::new(4, DropPolicy::Block);
+        assert!(queue.push(vec![1]));
+        assert!(queue.push(vec![2]));
+        queue.close();
+        let mut sink = StalledSink;
+
+        let sent = timeout(2 * FLUSH_TIMEOUT, send_queued_packets(&mut sink, &queue, None)).await;
+        assert_eq!(sent.expect("sender hung on a stalled sink"), (0, 2));
+        let closed = timeout(4 * FLUSH_TIMEOUT, close_sink(&mut sink)).await;
+        assert!(closed.expect("close hung on a stalled sink").is_err());
+    }
+}
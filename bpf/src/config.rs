@@ -1,8 +1,14 @@
+use crate::hotplug::Reattach;
+use crate::queue::DropPolicy;
 use std::net::Ipv4Addr;
+use std::time::Duration;
 
 pub const PREFERRED_INTERFACE: &str = "veth0";
 pub const MONITORED_IP: &str = "192.168.1.10";
 pub const TUNNEL_TARGET: &str = "127.0.0.1:4002";
+pub const QUEUE_CAPACITY: usize = 4096;
+pub const DROP_POLICY: DropPolicy = DropPolicy::DropNewest;
+pub const REATTACH: Reattach = Reattach::Poll(Duration::from_millis(500));
 
 pub fn build_bpf_filter(ip: Ipv4Addr) -> String {
     format!("ip and host {ip}")
@@ -5,12 +5,16 @@ pub fn choose_pcap_device_name<'a>(
     preferred_name: &'a str,
     ip_fallback: Ipv4Addr,
 ) -> Option<&'a str> {
-    if devices.iter().any(|d| d.name == preferred_name) {
+    if is_device_listed(devices, preferred_name) {
         return Some(preferred_name);
     }
     select_pcap_device_name_by_ipv4(devices, ip_fallback)
 }
 
+pub fn is_device_listed(devices: &[pcap::Device], name: &str) -> bool {
+    devices.iter().any(|d| d.name == name)
+}
+
 pub fn select_pcap_device_name_by_ipv4(devices: &[pcap::Device], ip: Ipv4Addr) -> Option<&str> {
     devices
         .iter()
@@ -20,7 +24,7 @@ pub fn select_pcap_device_name_by_ipv4(devices: &[pcap::Device], ip: Ipv4Addr) -
 
 #[cfg(test)]
 mod tests {
-    use super::{choose_pcap_device_name, select_pcap_device_name_by_ipv4};
+    use super::{choose_pcap_device_name, is_device_listed, select_pcap_device_name_by_ipv4};
     use std::net::{IpAddr, Ipv4Addr};
 
     #[test]
@@ -92,4 +96,17 @@ mod tests {
             .expect("device should be selected");
         assert_eq!(selected, "utun5");
     }
+
+    #[test]
+    fn reports_whether_a_device_is_listed() {
+        let devices = vec![pcap::Device {
+            name: "veth0".to_string(),
+            desc: None,
+            addresses: vec![],
+            flags: pcap::DeviceFlags::empty(),
+        }];
+
+        assert!(is_device_listed(&devices, "veth0"));
+        assert!(!is_device_listed(&devices, "veth1"));
+    }
 }
@@ -1,17 +1,63 @@
 use std::io;
-use std::net::TcpStream;
 use std::io::Write;
 
-pub fn forward_packet(stream: &mut TcpStream, packet: &[u8]) -> io::Result<usize> {
+pub fn forward_packet<W: Write>(sink: &mut W, packet: &[u8]) -> io::Result<usize> {
+    let frame = frame_packet(packet)?;
+    sink.write_all(frame)?;
+    Ok(frame.len())
+}
+
+#[cfg(feature = "async")]
+pub async fn forward_packet_async<W>(sink: &mut W, packet: &[u8]) -> io::Result<usize>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let frame = frame_packet(packet)?;
+    sink.write_all(frame).await?;
+    Ok(frame.len())
+}
+
+/// Writes a packet tagged with the interface it was captured on.
+///
+/// Unlike [`forward_packet`], tagged frames are length-prefixed so a receiver can split a stream
+/// that interleaves several interfaces:
+/// `name_len: u8 | name: [u8; name_len] | packet_len: u32 (big endian) | packet`.
+pub fn forward_tagged_packet<W: Write>(sink: &mut W, interface: &str, packet: &[u8]) -> io::Result<usize> {
+    let frame = encode_tagged_frame(interface, packet)?;
+    sink.write_all(&frame)?;
+    Ok(frame.len())
+}
+
+pub fn encode_tagged_frame(interface: &str, packet: &[u8]) -> io::Result<Vec<u8>> {
+    let packet = frame_packet(packet)?;
+    let name_len = u8::try_from(interface.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("interface name too long to tag: {interface}"),
+        )
+    })?;
+    let packet_len = u32::try_from(packet.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too large to tag"))?;
+
+    let mut frame = Vec::with_capacity(1 + interface.len() + 4 + packet.len());
+    frame.push(name_len);
+    frame.extend_from_slice(interface.as_bytes());
+    frame.extend_from_slice(&packet_len.to_be_bytes());
+    frame.extend_from_slice(packet);
+    Ok(frame)
+}
+
+/// Packets go on the wire as raw captured bytes; both sink flavours share this check.
+fn frame_packet(packet: &[u8]) -> io::Result<&[u8]> {
     if packet.is_empty() {
         return Err(io::Error::new(
             io::ErrorKind::InvalidInput,
             "cannot forward empty packet",
         ));
     }
-
-    stream.write_all(packet)?;
-    Ok(packet.len())
+    Ok(packet)
 }
 
 #[cfg(test)]
@@ -53,4 +99,31 @@ mod tests {
             .expect_err("empty packet should be rejected");
         assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
     }
+
+    #[test]
+    fn tagged_frame_carries_interface_name_and_length() -> io::Result<()> {
+        let frame = super::encode_tagged_frame("eth0.10", &[0xAA, 0xBB])?;
+        assert_eq!(frame[0], 7);
+        assert_eq!(&frame[1..8], b"eth0.10");
+        assert_eq!(&frame[8..12], &[0, 0, 0, 2]);
+        assert_eq!(&frame[12..], &[0xAA, 0xBB]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_interface_names_longer_than_the_tag_allows() {
+        let name = "x".repeat(256);
+        let err = super::encode_tagged_frame(&name, &[0x01]).expect_err("long name should be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_forwarder_writes_the_same_bytes() -> io::Result<()> {
+        let mut sink = Vec::new();
+        let sent = super::forward_packet_async(&mut sink, &[0x01, 0x02]).await?;
+        assert_eq!(sent, 2);
+        assert_eq!(sink, [0x01, 0x02]);
+        Ok(())
+    }
 }
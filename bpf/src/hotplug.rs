@@ -0,0 +1,50 @@
+use crate::device_select::is_device_listed;
+use crate::runner::open_capture;
+use crate::shutdown::ShutdownHandle;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a runner does when its capture device disappears (e.g. `veth0` deleted, USB NIC unplugged).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reattach {
+    /// Fail with "packet capture failed", as before.
+    Disabled,
+    /// If a capture error comes with the device gone from `pcap::Device::list`, poll the list at
+    /// this interval until the device is back, then reopen the capture with the same filter.
+    /// Errors on a device that is still listed fail as with `Disabled`.
+    Poll(Duration),
+}
+
+/// Returns whether `device_name` is currently listed by libpcap.
+pub fn device_available(device_name: &str) -> bool {
+    pcap::Device::list()
+        .map(|devices| is_device_listed(&devices, device_name))
+        .unwrap_or(false)
+}
+
+/// Blocks until `device_name` is listed again and a capture can be reopened on it.
+///
+/// Returns `None` if `shutdown` is cancelled, `deadline` passes or `stop` returns true first.
+pub(crate) fn reopen_when_available(
+    device_name: &str,
+    filter: &str,
+    read_timeout_ms: i32,
+    poll: Duration,
+    deadline: Option<Instant>,
+    shutdown: &ShutdownHandle,
+    stop: impl Fn() -> bool,
+) -> Option<pcap::Capture<pcap::Active>> {
+    loop {
+        if shutdown.is_cancelled() || stop() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return None;
+        }
+        // The device can be listed before it is usable (e.g. still down), so keep polling until
+        // the capture actually opens.
+        if device_available(device_name)
+            && let Ok(capture) = open_capture(device_name, filter, read_timeout_ms)
+        {
+            return Some(capture);
+        }
+        thread::sleep(poll);
+    }
+}
@@ -1,5 +1,11 @@
+#[cfg(feature = "async")]
+pub mod async_runner;
 pub mod config;
 pub mod device_select;
 pub mod forwarder;
+pub mod hotplug;
+pub mod multi_runner;
 pub mod packet;
+pub mod queue;
 pub mod runner;
+pub mod shutdown;
@@ -1,7 +1,10 @@
 use anyhow::{Context, Result};
-use macos_bpf_tunnel::config::{MONITORED_IP, PREFERRED_INTERFACE, TUNNEL_TARGET, build_bpf_filter};
+use macos_bpf_tunnel::config::{
+    DROP_POLICY, MONITORED_IP, PREFERRED_INTERFACE, QUEUE_CAPACITY, REATTACH, TUNNEL_TARGET, build_bpf_filter,
+};
 use macos_bpf_tunnel::device_select::choose_pcap_device_name;
-use macos_bpf_tunnel::runner::{RunnerConfig, forward_captured_packets};
+use macos_bpf_tunnel::runner::{RunnerConfig, forward_captured_packets_until};
+use macos_bpf_tunnel::shutdown::ShutdownHandle;
 use std::net::{Ipv4Addr, SocketAddr};
 
 fn main() -> Result<()> {
@@ -31,7 +34,14 @@ fn main() -> Result<()> {
         filter: &filter,
         tunnel_target,
         read_timeout_ms: 250,
+        queue_capacity: QUEUE_CAPACITY,
+        drop_policy: DROP_POLICY,
+        reattach: REATTACH,
     };
-    let _ = forward_captured_packets(cfg, None, None)?;
+    let shutdown = ShutdownHandle::new();
+    shutdown.cancel_on_signals()?;
+
+    let stats = forward_captured_packets_until(cfg, None, None, None, &shutdown)?;
+    println!("tunnel closed: {stats}");
     Ok(())
 }
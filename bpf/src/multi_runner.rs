@@ -0,0 +1,196 @@
+use crate::forwarder::forward_tagged_packet;
+use crate::hotplug::Reattach;
+use crate::queue::{DropPolicy, PacketQueue, QueueStats};
+use crate::runner::{bound_flush, capture_with_reattach, close_on_cancel, close_tunnel, open_capture};
+use crate::shutdown::ShutdownHandle;
+use anyhow::{Context, Result};
+use std::fmt;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// One capture source: an interface and the BPF filter applied to it.
+pub struct CaptureInterface<'a> {
+    pub device_name: &'a str,
+    pub filter: &'a str,
+}
+
+pub struct MultiRunnerConfig<'a> {
+    pub interfaces: &'a [CaptureInterface<'a>],
+    pub tunnel_target: SocketAddr,
+    pub read_timeout_ms: i32,
+    pub queue_capacity: usize,
+    pub drop_policy: DropPolicy,
+    /// Applied to each interface independently.
+    pub reattach: Reattach,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceStats {
+    pub device_name: String,
+    pub captured: usize,
+    pub forwarded: usize,
+    pub send_errors: usize,
+    pub reattached: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MultiRunnerStats {
+    /// Same order as [`MultiRunnerConfig::interfaces`].
+    pub interfaces: Vec<InterfaceStats>,
+    pub queue: QueueStats,
+}
+
+impl MultiRunnerStats {
+    pub fn forwarded(&self) -> usize {
+        self.interfaces.iter().map(|iface| iface.forwarded).sum()
+    }
+}
+
+impl fmt::Display for MultiRunnerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for iface in &self.interfaces {
+            write!(
+                f,
+                "{}: captured {}, forwarded {}, send errors {}, reattached {}; ",
+                iface.device_name, iface.captured, iface.forwarded, iface.send_errors, iface.reattached
+            )?;
+        }
+        write!(
+            f,
+            "queue dropped {}, queue high-water {}",
+            self.queue.dropped, self.queue.high_water_mark
+        )
+    }
+}
+
+/// Captures from every configured interface at once and feeds one shared tunnel.
+///
+/// Each interface gets its own capture thread; all of them push into one bounded queue that a
+/// single sender drains. Frames are written with [`forward_tagged_packet`] so the receiver can
+/// tell which interface a packet came from. `max_packets` counts forwarded packets across all
+/// interfaces. If any capture fails the whole runner stops and returns that error.
+pub fn forward_multi_captured_packets(
+    cfg: MultiRunnerConfig<'_>,
+    max_packets: Option<usize>,
+    max_duration: Option<Duration>,
+    ready: Option<mpsc::Sender<()>>,
+    shutdown: &ShutdownHandle,
+) -> Result<MultiRunnerStats> {
+    anyhow::ensure!(!cfg.interfaces.is_empty(), "no capture interfaces configured");
+
+    let captures = cfg
+        .interfaces
+        .iter()
+        .map(|iface| open_capture(iface.device_name, iface.filter, cfg.read_timeout_ms))
+        .collect::<Result<Vec<_>>>()?;
+    let mut stream = TcpStream::connect(cfg.tunnel_target).context("failed to connect TCP tunnel target")?;
+    let tunnel = stream.try_clone().context("failed to clone TCP tunnel")?;
+    if let Some(tx) = ready {
+        let _ = tx.send(());
+    }
+
+    let mut stats = MultiRunnerStats {
+        interfaces: cfg
+            .interfaces
+            .iter()
+            .map(|iface| InterfaceStats {
+                device_name: iface.device_name.to_string(),
+                ..InterfaceStats::default()
+            })
+            .collect(),
+        queue: QueueStats::default(),
+    };
+
+    let queue = PacketQueue::<(usize, Vec<u8>)>::new(cfg.queue_capacity, cfg.drop_policy);
+    let deadline = max_duration.map(|limit| Instant::now() + limit);
+    let (flushed, flushing) = mpsc::channel();
+    let (captured, sent) = thread::scope(|scope| {
+        let sender = scope.spawn(|| {
+            let sent = send_tagged_packets(&mut stream, &queue, max_packets, cfg.interfaces);
+            let _ = flushed.send(());
+            sent
+        });
+        scope.spawn(|| close_on_cancel(&queue, shutdown));
+        let capturers: Vec<_> = captures
+            .into_iter()
+            .zip(cfg.interfaces)
+            .enumerate()
+            .map(|(index, (capture, iface))| {
+                let queue = &queue;
+                scope.spawn(move || {
+                    let captured = capture_with_reattach(
+                        capture,
+                        iface,
+                        cfg.read_timeout_ms,
+                        cfg.reattach,
+                        queue,
+                        deadline,
+                        shutdown,
+                        |packet| (index, packet),
+                    );
+                    if captured.is_err() {
+                        // One failed interface stops the others instead of leaving them running.
+                        queue.close();
+                    }
+                    captured
+                })
+            })
+            .collect();
+
+        let captured: Vec<_> = capturers
+            .into_iter()
+            .map(|capturer| {
+                capturer
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("capture thread panicked"))
+                    .and_then(|captured| captured)
+            })
+            .collect();
+        queue.close();
+        bound_flush(&flushing, &tunnel);
+        (captured, sender.join())
+    });
+    let sent = sent.map_err(|_| anyhow::anyhow!("tunnel sender thread panicked"))?;
+    close_tunnel(&mut stream)?;
+
+    for ((iface, captured), (forwarded, send_errors)) in stats.interfaces.iter_mut().zip(captured).zip(sent) {
+        let counts = captured.with_context(|| format!("capture on {} failed", iface.device_name))?;
+        iface.captured = counts.captured;
+        iface.reattached = counts.reattached;
+        iface.forwarded = forwarded;
+        iface.send_errors = send_errors;
+    }
+    stats.queue = queue.stats();
+    Ok(stats)
+}
+
+/// Returns `(forwarded, send_errors)` per interface index.
+fn send_tagged_packets(
+    stream: &mut TcpStream,
+    queue: &PacketQueue<(usize, Vec<u8>)>,
+    max_packets: Option<usize>,
+    interfaces: &[CaptureInterface<'_>],
+) -> Vec<(usize, usize)> {
+    let mut counts = vec![(0_usize, 0_usize); interfaces.len()];
+    let mut forwarded = 0_usize;
+    loop {
+        if max_packets.is_some_and(|limit| forwarded >= limit) {
+            // Closing the queue tells every capture thread to stop as well.
+            queue.close();
+            break;
+        }
+        let Some((index, packet)) = queue.pop() else {
+            break;
+        };
+        match forward_tagged_packet(stream, interfaces[index].device_name, &packet) {
+            Ok(_) => {
+                counts[index].0 += 1;
+                forwarded += 1;
+            }
+            Err(_) => counts[index].1 += 1,
+        }
+    }
+    counts
+}
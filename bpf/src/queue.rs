@@ -0,0 +1,318 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+/// What to do with a captured packet when the queue is already full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the packet that was just captured.
+    DropNewest,
+    /// Evict the oldest queued packet to make room for the new one.
+    DropOldest,
+    /// Block the capture thread until the sender frees a slot.
+    Block,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub enqueued: usize,
+    pub dropped: usize,
+    pub high_water_mark: usize,
+}
+
+/// Bounded multi-producer/multi-consumer queue of owned packet buffers (or tagged packets).
+///
+/// Closing the queue wakes every blocked producer and consumer. Consumers keep
+/// draining packets that were queued before the close. With the `async` feature the
+/// same queue can be used from tokio tasks through the `*_async` methods.
+pub struct PacketQueue<T = Vec<u8>> {
+    capacity: usize,
+    policy: DropPolicy,
+    state: Mutex<QueueState<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    #[cfg(feature = "async")]
+    changed: tokio::sync::Notify,
+}
+
+struct QueueState<T> {
+    packets: VecDeque<T>,
+    closed: bool,
+    stats: QueueStats,
+}
+
+impl<T> PacketQueue<T> {
+    pub fn new(capacity: usize, policy: DropPolicy) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            policy,
+            state: Mutex::new(QueueState {
+                packets: VecDeque::with_capacity(capacity),
+                closed: false,
+                stats: QueueStats::default(),
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            #[cfg(feature = "async")]
+            changed: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Queues a packet according to the drop policy.
+    ///
+    /// Returns `false` once the queue has been closed; the packet is discarded.
+    pub fn push(&self, packet: T) -> bool {
+        let mut state = self.lock();
+        let mut packet = packet;
+        loop {
+            match self.offer(&mut state, packet) {
+                Ok(accepted) => {
+                    drop(state);
+                    self.not_empty.notify_one();
+                    self.notify_async();
+                    return accepted;
+                }
+                Err(rejected) => {
+                    packet = rejected;
+                    state = self
+                        .not_full
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+            }
+        }
+    }
+
+    /// Blocks until a packet is available.
+    ///
+    /// Returns `None` once the queue is closed and fully drained.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.lock();
+        loop {
+            if let Some(next) = Self::take(&mut state) {
+                drop(state);
+                self.not_full.notify_one();
+                self.notify_async();
+                return next;
+            }
+            state = self
+                .not_empty
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+        self.notify_async();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.lock().stats
+    }
+
+    /// Async counterpart of [`PacketQueue::push`]; `Block` waits without parking the thread.
+    #[cfg(feature = "async")]
+    pub async fn push_async(&self, packet: T) -> bool {
+        let mut pending = Some(packet);
+        let accepted = self
+            .wait_async(|| {
+                let packet = pending.take()?;
+                match self.offer(&mut self.lock(), packet) {
+                    Ok(accepted) => Some(accepted),
+                    Err(rejected) => {
+                        pending = Some(rejected);
+                        None
+                    }
+                }
+            })
+            .await;
+        self.not_empty.notify_one();
+        self.notify_async();
+        accepted
+    }
+
+    /// Async counterpart of [`PacketQueue::pop`].
+    #[cfg(feature = "async")]
+    pub async fn pop_async(&self) -> Option<T> {
+        let next = self.wait_async(|| Self::take(&mut self.lock())).await;
+        self.not_full.notify_one();
+        self.notify_async();
+        next
+    }
+
+    /// Resolves once the queue has been closed.
+    #[cfg(feature = "async")]
+    pub async fn closed(&self) {
+        self.wait_async(|| self.is_closed().then_some(())).await
+    }
+
+    /// Applies the drop policy; hands the packet back when `Block` has to wait for room.
+    fn offer(&self, state: &mut QueueState<T>, packet: T) -> Result<bool, T> {
+        if state.closed {
+            return Ok(false);
+        }
+        if state.packets.len() >= self.capacity {
+            match self.policy {
+                DropPolicy::DropNewest => {
+                    state.stats.dropped += 1;
+                    return Ok(true);
+                }
+                DropPolicy::DropOldest => {
+                    state.packets.pop_front();
+                    state.stats.dropped += 1;
+                }
+                DropPolicy::Block => return Err(packet),
+            }
+        }
+
+        state.packets.push_back(packet);
+        state.stats.enqueued += 1;
+        state.stats.high_water_mark = state.stats.high_water_mark.max(state.packets.len());
+        Ok(true)
+    }
+
+    /// `Some(next)` when a pop can complete now, `None` when the caller has to wait.
+    fn take(state: &mut QueueState<T>) -> Option<Option<T>> {
+        match state.packets.pop_front() {
+            Some(packet) => Some(Some(packet)),
+            None if state.closed => Some(None),
+            None => None,
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn wait_async<R>(&self, mut ready: impl FnMut() -> Option<R>) -> R {
+        loop {
+            // Register interest before checking so a concurrent change can't be missed.
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if let Some(value) = ready() {
+                return value;
+            }
+            changed.await;
+        }
+    }
+
+    fn notify_async(&self) {
+        #[cfg(feature = "async")]
+        self.changed.notify_waiters();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState<T>> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DropPolicy, PacketQueue};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn drop_newest_keeps_queued_packets_when_full() {
+        let queue = PacketQueue::new(2, DropPolicy::DropNewest);
+        assert!(queue.push(vec![1]));
+        assert!(queue.push(vec![2]));
+        assert!(queue.push(vec![3]));
+
+        assert_eq!(queue.pop(), Some(vec![1]));
+        assert_eq!(queue.pop(), Some(vec![2]));
+        let stats = queue.stats();
+        assert_eq!(stats.enqueued, 2);
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.high_water_mark, 2);
+    }
+
+    #[test]
+    fn drop_oldest_evicts_head_when_full() {
+        let queue = PacketQueue::new(2, DropPolicy::DropOldest);
+        queue.push(vec![1]);
+        queue.push(vec![2]);
+        queue.push(vec![3]);
+
+        assert_eq!(queue.pop(), Some(vec![2]));
+        assert_eq!(queue.pop(), Some(vec![3]));
+        assert_eq!(queue.stats().dropped, 1);
+    }
+
+    #[test]
+    fn block_waits_for_consumer_to_free_a_slot() {
+        let queue = Arc::new(PacketQueue::new(1, DropPolicy::Block));
+        queue.push(vec![1]);
+
+        let producer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push(vec![2]))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(queue.pop(), Some(vec![1]));
+        assert!(producer.join().expect("producer thread panicked"));
+        assert_eq!(queue.pop(), Some(vec![2]));
+        assert_eq!(queue.stats().dropped, 0);
+    }
+
+    #[test]
+    fn close_drains_remaining_packets_then_ends() {
+        let queue = PacketQueue::new(4, DropPolicy::DropNewest);
+        queue.push(vec![1]);
+        queue.close();
+
+        assert!(!queue.push(vec![2]));
+        assert_eq!(queue.pop(), Some(vec![1]));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn close_wakes_blocked_producer() {
+        let queue = Arc::new(PacketQueue::new(1, DropPolicy::Block));
+        queue.push(vec![1]);
+
+        let producer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push(vec![2]))
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert!(!producer.join().expect("producer thread panicked"));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_block_waits_for_consumer_and_drains_after_close() {
+        let queue = Arc::new(PacketQueue::new(1, DropPolicy::Block));
+        assert!(queue.push_async(vec![1]).await);
+
+        let producer = {
+            let queue = Arc::clone(&queue);
+            tokio::spawn(async move { queue.push_async(vec![2]).await })
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(queue.pop_async().await, Some(vec![1]));
+        assert!(producer.await.expect("producer task panicked"));
+
+        queue.close();
+        queue.closed().await;
+        assert_eq!(queue.pop_async().await, Some(vec![2]));
+        assert_eq!(queue.pop_async().await, None);
+    }
+}
@@ -1,21 +1,69 @@
 use crate::forwarder::forward_packet;
+use crate::hotplug::{Reattach, device_available, reopen_when_available};
+use crate::multi_runner::CaptureInterface;
+use crate::queue::{DropPolicy, PacketQueue, QueueStats};
+use crate::shutdown::ShutdownHandle;
 use anyhow::{Context, Result};
-use std::net::{SocketAddr, TcpStream};
-use std::sync::mpsc;
+use std::fmt;
+use std::io::Write;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
 use std::time::{Duration, Instant};
 
+/// How long the runners keep flushing already queued packets to the tunnel once capture stops.
+pub const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the runners check whether `shutdown` was cancelled while capture may be parked.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
 pub struct RunnerConfig<'a> {
     pub device_name: &'a str,
     pub filter: &'a str,
     pub tunnel_target: SocketAddr,
     pub read_timeout_ms: i32,
+    pub queue_capacity: usize,
+    pub drop_policy: DropPolicy,
+    pub reattach: Reattach,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunnerStats {
+    pub captured: usize,
+    pub forwarded: usize,
+    pub send_errors: usize,
+    /// How many times the capture was reopened after the device disappeared.
+    pub reattached: usize,
+    pub queue: QueueStats,
+}
+
+/// Per-capture counters shared by the runners.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct CaptureCounts {
+    pub captured: usize,
+    pub reattached: usize,
+}
+
+impl fmt::Display for RunnerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "captured {}, forwarded {}, send errors {}, reattached {}, queue dropped {}, queue high-water {}",
+            self.captured,
+            self.forwarded,
+            self.send_errors,
+            self.reattached,
+            self.queue.dropped,
+            self.queue.high_water_mark
+        )
+    }
 }
 
 pub fn forward_captured_packets(
     cfg: RunnerConfig<'_>,
     max_packets: Option<usize>,
     max_duration: Option<Duration>,
-) -> Result<usize> {
+) -> Result<RunnerStats> {
     forward_captured_packets_with_ready(cfg, max_packets, max_duration, None)
 }
 
@@ -24,42 +72,164 @@ pub fn forward_captured_packets_with_ready(
     max_packets: Option<usize>,
     max_duration: Option<Duration>,
     ready: Option<mpsc::Sender<()>>,
-) -> Result<usize> {
-    let mut capture = pcap::Capture::from_device(cfg.device_name)
-        .with_context(|| format!("failed to open interface {}", cfg.device_name))?
-        .promisc(true)
-        .immediate_mode(true)
-        .timeout(cfg.read_timeout_ms)
-        .open()
-        .with_context(|| format!("failed to activate capture on {}", cfg.device_name))?;
-    capture
-        .filter(cfg.filter, true)
-        .with_context(|| format!("failed to apply BPF filter: {}", cfg.filter))?;
+) -> Result<RunnerStats> {
+    forward_captured_packets_until(cfg, max_packets, max_duration, ready, &ShutdownHandle::new())
+}
 
+/// Runs until a limit is reached, capture fails, or `shutdown` is cancelled.
+///
+/// With [`Reattach::Poll`] a vanished device is not a failure: the runner waits for it to come
+/// back and reopens the capture, while queued packets keep draining to the tunnel.
+///
+/// On every exit path the packets already queued are flushed to the tunnel, for at most
+/// [`FLUSH_TIMEOUT`], and the TCP write side is shut down so the receiver sees a clean EOF.
+/// Packets a stalled receiver did not take by then count as send errors.
+pub fn forward_captured_packets_until(
+    cfg: RunnerConfig<'_>,
+    max_packets: Option<usize>,
+    max_duration: Option<Duration>,
+    ready: Option<mpsc::Sender<()>>,
+    shutdown: &ShutdownHandle,
+) -> Result<RunnerStats> {
+    let capture = open_capture(cfg.device_name, cfg.filter, cfg.read_timeout_ms)?;
     let mut stream = TcpStream::connect(cfg.tunnel_target).context("failed to connect TCP tunnel target")?;
+    let tunnel = stream.try_clone().context("failed to clone TCP tunnel")?;
     if let Some(tx) = ready {
         let _ = tx.send(());
     }
 
-    let start = Instant::now();
-    let mut forwarded = 0_usize;
+    // Capture and the (possibly slow) TCP write run on separate threads so a stalled
+    // receiver fills the queue instead of stalling libpcap's kernel buffer.
+    let queue = PacketQueue::new(cfg.queue_capacity, cfg.drop_policy);
+    let deadline = max_duration.map(|limit| Instant::now() + limit);
+    let (flushed, flushing) = mpsc::channel();
+    let (counts, sent) = thread::scope(|scope| {
+        let sender = scope.spawn(|| {
+            let sent = send_queued_packets(&mut stream, &queue, max_packets);
+            let _ = flushed.send(());
+            sent
+        });
+        scope.spawn(|| close_on_cancel(&queue, shutdown));
+        let iface = CaptureInterface {
+            device_name: cfg.device_name,
+            filter: cfg.filter,
+        };
+        let counts = capture_with_reattach(
+            capture,
+            &iface,
+            cfg.read_timeout_ms,
+            cfg.reattach,
+            &queue,
+            deadline,
+            shutdown,
+            |packet| packet,
+        );
+        queue.close();
+        bound_flush(&flushing, &tunnel);
+        (counts, sender.join())
+    });
+    let (forwarded, send_errors) = sent.map_err(|_| anyhow::anyhow!("tunnel sender thread panicked"))?;
+    close_tunnel(&mut stream)?;
+
+    let counts = counts?;
+    Ok(RunnerStats {
+        captured: counts.captured,
+        forwarded,
+        send_errors,
+        reattached: counts.reattached,
+        queue: queue.stats(),
+    })
+}
+
+pub(crate) fn open_capture(
+    device_name: &str,
+    filter: &str,
+    read_timeout_ms: i32,
+) -> Result<pcap::Capture<pcap::Active>> {
+    let mut capture = pcap::Capture::from_device(device_name)
+        .with_context(|| format!("failed to open interface {device_name}"))?
+        .promisc(true)
+        .immediate_mode(true)
+        .timeout(read_timeout_ms)
+        .open()
+        .with_context(|| format!("failed to activate capture on {device_name}"))?;
+    capture
+        .filter(filter, true)
+        .with_context(|| format!("failed to apply BPF filter: {filter}"))?;
+    Ok(capture)
+}
+
+/// Runs [`capture_into_queue`] and, if `reattach` allows, reopens the capture when it fails
+/// because the device disappeared. Any other capture error is returned.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn capture_with_reattach<T>(
+    mut capture: pcap::Capture<pcap::Active>,
+    iface: &CaptureInterface<'_>,
+    read_timeout_ms: i32,
+    reattach: Reattach,
+    queue: &PacketQueue<T>,
+    deadline: Option<Instant>,
+    shutdown: &ShutdownHandle,
+    tag: impl Fn(Vec<u8>) -> T,
+) -> Result<CaptureCounts> {
+    let mut counts = CaptureCounts::default();
     loop {
-        if let Some(limit) = max_packets {
-            if forwarded >= limit {
-                return Ok(forwarded);
-            }
+        let err = match capture_into_queue(&mut capture, queue, deadline, shutdown, &mut counts.captured, &tag) {
+            Ok(()) => return Ok(counts),
+            Err(err) => err,
+        };
+        let Reattach::Poll(poll) = reattach else {
+            return Err(err);
+        };
+        // Only a vanished device is worth waiting for; an error on one that is still listed
+        // would fail again right away.
+        if device_available(iface.device_name) {
+            return Err(err);
         }
-        if let Some(limit) = max_duration {
-            if start.elapsed() >= limit {
-                return Ok(forwarded);
+        let reopened = reopen_when_available(
+            iface.device_name,
+            iface.filter,
+            read_timeout_ms,
+            poll,
+            deadline,
+            shutdown,
+            || queue.is_closed(),
+        );
+        match reopened {
+            Some(reopened) => {
+                capture = reopened;
+                counts.reattached += 1;
             }
+            None => return Ok(counts),
+        }
+    }
+}
+
+/// Pushes captured packets into `queue` until it closes, `shutdown` fires or `deadline` passes.
+///
+/// `tag` turns the owned packet bytes into the queue's item type.
+pub(crate) fn capture_into_queue<T>(
+    capture: &mut pcap::Capture<pcap::Active>,
+    queue: &PacketQueue<T>,
+    deadline: Option<Instant>,
+    shutdown: &ShutdownHandle,
+    captured: &mut usize,
+    tag: impl Fn(Vec<u8>) -> T,
+) -> Result<()> {
+    loop {
+        if queue.is_closed() || shutdown.is_cancelled() {
+            return Ok(());
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(());
         }
 
         match capture.next_packet() {
             Ok(packet) => {
+                *captured += 1;
                 // Packets are already filtered by libpcap's compiled BPF.
-                if let Ok(_) = forward_packet(&mut stream, packet.data) {
-                    forwarded += 1;
+                if !queue.push(tag(packet.data.to_vec())) {
+                    return Ok(());
                 }
             }
             Err(pcap::Error::TimeoutExpired) => continue,
@@ -67,3 +237,93 @@ pub fn forward_captured_packets_with_ready(
         }
     }
 }
+
+fn send_queued_packets(
+    stream: &mut TcpStream,
+    queue: &PacketQueue,
+    max_packets: Option<usize>,
+) -> (usize, usize) {
+    let mut forwarded = 0_usize;
+    let mut send_errors = 0_usize;
+    loop {
+        if max_packets.is_some_and(|limit| forwarded >= limit) {
+            // Closing the queue tells the capture loop to stop as well.
+            queue.close();
+            break;
+        }
+        let Some(packet) = queue.pop() else {
+            break;
+        };
+        match forward_packet(stream, &packet) {
+            Ok(_) => forwarded += 1,
+            Err(_) => send_errors += 1,
+        }
+    }
+    (forwarded, send_errors)
+}
+
+/// Closes `queue` once `shutdown` is cancelled, so a capture thread parked in a full
+/// [`DropPolicy::Block`] queue wakes up. Returns as soon as the queue is closed either way.
+pub(crate) fn close_on_cancel<T>(queue: &PacketQueue<T>, shutdown: &ShutdownHandle) {
+    while !queue.is_closed() {
+        if shutdown.is_cancelled() {
+            queue.close();
+            return;
+        }
+        thread::sleep(CANCEL_POLL);
+    }
+}
+
+/// Gives the sender [`FLUSH_TIMEOUT`] to drain the closed queue, signalled through `flushed`,
+/// then shuts `tunnel` down so a write stuck on a stalled receiver fails instead of blocking
+/// the runner; whatever is left then fails fast.
+pub(crate) fn bound_flush(flushed: &mpsc::Receiver<()>, tunnel: &TcpStream) {
+    if let Err(RecvTimeoutError::Timeout) = flushed.recv_timeout(FLUSH_TIMEOUT) {
+        let _ = tunnel.shutdown(Shutdown::Both);
+    }
+}
+
+pub(crate) fn close_tunnel(stream: &mut TcpStream) -> Result<()> {
+    stream.flush().context("failed to flush TCP tunnel")?;
+    match stream.shutdown(Shutdown::Write) {
+        // The receiver may already have gone away; there is nothing left to deliver.
+        Err(err) if err.kind() == std::io::ErrorKind::NotConnected => Ok(()),
+        other => other.context("failed to close TCP tunnel"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bound_flush, close_on_cancel};
+    use crate::queue::{DropPolicy, PacketQueue};
+    use crate::shutdown::ShutdownHandle;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn cancel_wakes_a_capture_parked_in_a_full_block_queue() {
+        let queue = PacketQueue::new(1, DropPolicy::Block);
+        let shutdown = ShutdownHandle::new();
+        assert!(queue.push(vec![1]));
+
+        thread::scope(|scope| {
+            scope.spawn(|| close_on_cancel(&queue, &shutdown));
+            let parked = scope.spawn(|| queue.push(vec![2]));
+            shutdown.cancel();
+            assert!(!parked.join().expect("push thread panicked"));
+        });
+        assert!(queue.is_closed());
+    }
+
+    #[test]
+    fn bound_flush_shuts_a_stalled_tunnel_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tunnel = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _receiver = listener.accept().unwrap();
+        let (_flushed, flushing) = mpsc::channel();
+
+        bound_flush(&flushing, &tunnel);
+        assert!(std::io::Write::write(&mut &tunnel, b"late").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Cloneable cancellation flag shared between the runner and whoever wants to stop it.
+///
+/// The runner polls the flag between capture reads, so cancellation takes effect within one
+/// `read_timeout_ms`.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        #[cfg(feature = "async")]
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the handle has been cancelled.
+    #[cfg(feature = "async")]
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Cancels this handle on SIGINT or SIGTERM.
+    ///
+    /// Only one process-wide signal handler can be installed.
+    pub fn cancel_on_signals(&self) -> Result<()> {
+        let handle = self.clone();
+        ctrlc::set_handler(move || handle.cancel()).context("failed to install SIGINT/SIGTERM handler")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShutdownHandle;
+    use std::thread;
+
+    #[test]
+    fn cancellation_is_visible_through_clones_on_other_threads() {
+        let handle = ShutdownHandle::new();
+        assert!(!handle.is_cancelled());
+
+        let remote = handle.clone();
+        thread::spawn(move || remote.cancel())
+            .join()
+            .expect("cancel thread panicked");
+        assert!(handle.is_cancelled());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn cancelled_future_resolves_after_cancel() {
+        let handle = ShutdownHandle::new();
+        let waiter = {
+            let handle = handle.clone();
+            tokio::spawn(async move { handle.cancelled().await })
+        };
+        tokio::task::yield_now().await;
+        handle.cancel();
+        waiter.await.expect("waiter task panicked");
+    }
+}
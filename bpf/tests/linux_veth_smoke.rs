@@ -1,15 +1,22 @@
+#[cfg(target_os = "linux")]
+#[path = "support/tcp_server.rs"]
+mod tcp_server;
+
 #[cfg(target_os = "linux")]
 mod linux_only {
     use macos_bpf_tunnel::config::{PREFERRED_INTERFACE, build_bpf_filter};
-    use macos_bpf_tunnel::runner::{RunnerConfig, forward_captured_packets_with_ready};
+    use macos_bpf_tunnel::hotplug::Reattach;
+    use macos_bpf_tunnel::multi_runner::{CaptureInterface, MultiRunnerConfig, forward_multi_captured_packets};
+    use macos_bpf_tunnel::queue::DropPolicy;
+    use macos_bpf_tunnel::runner::{RunnerConfig, forward_captured_packets_until, forward_captured_packets_with_ready};
+    use macos_bpf_tunnel::shutdown::ShutdownHandle;
     use std::io;
     use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
     use std::process::Command;
     use std::sync::mpsc;
     use std::time::Duration;
 
-    #[path = "support/tcp_server.rs"]
-    mod tcp_server;
+    use super::tcp_server;
 
     struct VethPair;
 
@@ -30,6 +37,7 @@ mod linux_only {
         }
     }
 
+    #[allow(clippy::io_other_error)]
     fn run_ip(args: &[&str]) -> io::Result<()> {
         let status = Command::new("ip").args(args).status()?;
         if !status.success() {
@@ -63,6 +71,9 @@ mod linux_only {
                 filter: &filter,
                 tunnel_target,
                 read_timeout_ms: 250,
+                queue_capacity: 16,
+                drop_policy: DropPolicy::Block,
+                reattach: Reattach::Disabled,
             };
             forward_captured_packets_with_ready(cfg, Some(1), Some(Duration::from_secs(2)), Some(ready_tx))
         });
@@ -82,10 +93,214 @@ mod linux_only {
             "expected forwarded raw packet bytes to contain UDP payload"
         );
 
-        let forwarded = handle
+        let stats = handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("capture thread panicked"))??;
+        assert_eq!(stats.forwarded, 1);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    fn cancelled_runner_flushes_and_closes_the_tunnel() -> anyhow::Result<()> {
+        // Run: sudo -E cargo test --test linux_veth_smoke -- --ignored
+        let _veth = VethPair::create()?;
+
+        let filter = build_bpf_filter(Ipv4Addr::new(192, 168, 1, 10));
+        let server = tcp_server::TcpTestServer::spawn(Duration::from_secs(5))?;
+        let tunnel_target: SocketAddr = server.address();
+
+        let shutdown = ShutdownHandle::new();
+        let runner_shutdown = shutdown.clone();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let cfg = RunnerConfig {
+                device_name: PREFERRED_INTERFACE,
+                filter: &filter,
+                tunnel_target,
+                read_timeout_ms: 250,
+                queue_capacity: 16,
+                drop_policy: DropPolicy::Block,
+                reattach: Reattach::Disabled,
+            };
+            forward_captured_packets_until(cfg, None, None, Some(ready_tx), &runner_shutdown)
+        });
+
+        ready_rx
+            .recv_timeout(Duration::from_secs(2))
+            .map_err(|_| anyhow::anyhow!("timed out waiting for capture to be ready"))?;
+
+        let sender = UdpSocket::bind("192.168.1.11:0")?;
+        let payload: [u8; 6] = [0xCA, 0xFE, 0xBA, 0xBE, 0x03, 0x04];
+        sender.send_to(&payload, "192.168.1.10:5555")?;
+        std::thread::sleep(Duration::from_millis(300));
+        shutdown.cancel();
+
+        // The server only returns once the runner has shut down the write side of the tunnel.
+        let got = server.recv(Duration::from_secs(2))?;
+        assert!(got.windows(payload.len()).any(|w| w == payload));
+
+        let stats = handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("capture thread panicked"))??;
+        assert!(stats.forwarded >= 1);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    fn multi_runner_tags_frames_from_both_veth_ends() -> anyhow::Result<()> {
+        // Run: sudo -E cargo test --test linux_veth_smoke -- --ignored
+        let _veth = VethPair::create()?;
+
+        let filter = build_bpf_filter(Ipv4Addr::new(192, 168, 1, 10));
+        let server = tcp_server::TcpTestServer::spawn(Duration::from_secs(2))?;
+        let tunnel_target: SocketAddr = server.address();
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let interfaces = [
+                CaptureInterface { device_name: "veth0", filter: &filter },
+                CaptureInterface { device_name: "veth1", filter: &filter },
+            ];
+            let cfg = MultiRunnerConfig {
+                interfaces: &interfaces,
+                tunnel_target,
+                read_timeout_ms: 250,
+                queue_capacity: 16,
+                drop_policy: DropPolicy::Block,
+                reattach: Reattach::Disabled,
+            };
+            forward_multi_captured_packets(
+                cfg,
+                Some(2),
+                Some(Duration::from_secs(2)),
+                Some(ready_tx),
+                &ShutdownHandle::new(),
+            )
+        });
+
+        ready_rx
+            .recv_timeout(Duration::from_secs(2))
+            .map_err(|_| anyhow::anyhow!("timed out waiting for capture to be ready"))?;
+
+        // The datagram leaves through veth1 and arrives on veth0, so both captures see it.
+        let sender = UdpSocket::bind("192.168.1.11:0")?;
+        sender.send_to(&[0xDE, 0xAD, 0xBE, 0xEF], "192.168.1.10:5555")?;
+
+        let got = server.recv(Duration::from_secs(2))?;
+        assert!(got.windows(6).any(|w| w == b"\x05veth0"));
+        assert!(got.windows(6).any(|w| w == b"\x05veth1"));
+
+        let stats = handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("capture thread panicked"))??;
+        assert_eq!(stats.forwarded(), 2);
+        assert!(stats.interfaces.iter().all(|iface| iface.forwarded == 1));
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    fn reattaches_after_veth0_is_deleted_and_recreated() -> anyhow::Result<()> {
+        // Run: sudo -E cargo test --test linux_veth_smoke -- --ignored
+        let veth = VethPair::create()?;
+
+        let filter = build_bpf_filter(Ipv4Addr::new(192, 168, 1, 10));
+        let server = tcp_server::TcpTestServer::spawn(Duration::from_secs(10))?;
+        let tunnel_target: SocketAddr = server.address();
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let cfg = RunnerConfig {
+                device_name: PREFERRED_INTERFACE,
+                filter: &filter,
+                tunnel_target,
+                read_timeout_ms: 250,
+                queue_capacity: 16,
+                drop_policy: DropPolicy::Block,
+                reattach: Reattach::Poll(Duration::from_millis(100)),
+            };
+            forward_captured_packets_with_ready(cfg, Some(1), Some(Duration::from_secs(10)), Some(ready_tx))
+        });
+
+        ready_rx
+            .recv_timeout(Duration::from_secs(2))
+            .map_err(|_| anyhow::anyhow!("timed out waiting for capture to be ready"))?;
+
+        // Pull the device out from under the capture, then bring it back.
+        drop(veth);
+        std::thread::sleep(Duration::from_millis(500));
+        let _veth = VethPair::create()?;
+
+        // The runner reopens the capture asynchronously, so keep sending until it has forwarded one.
+        let sender = UdpSocket::bind("192.168.1.11:0")?;
+        let payload: [u8; 6] = [0xDE, 0xAD, 0xBE, 0xEF, 0x07, 0x08];
+        while !handle.is_finished() {
+            sender.send_to(&payload, "192.168.1.10:5555")?;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        let got = server.recv(Duration::from_secs(2))?;
+        assert!(got.windows(payload.len()).any(|w| w == payload));
+
+        let stats = handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("capture thread panicked"))??;
+        assert_eq!(stats.forwarded, 1);
+        assert!(stats.reattached >= 1, "expected at least one reattach, got {stats}");
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    #[ignore]
+    fn async_runner_tunnels_captured_packet_to_tcp_server() -> anyhow::Result<()> {
+        // Run: sudo -E cargo test --features async --test linux_veth_smoke -- --ignored
+        use macos_bpf_tunnel::async_runner::forward_captured_packets_async;
+
+        let _veth = VethPair::create()?;
+
+        let filter = build_bpf_filter(Ipv4Addr::new(192, 168, 1, 10));
+        let server = tcp_server::TcpTestServer::spawn(Duration::from_secs(2))?;
+        let tunnel_target: SocketAddr = server.address();
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            let cfg = RunnerConfig {
+                device_name: PREFERRED_INTERFACE,
+                filter: &filter,
+                tunnel_target,
+                read_timeout_ms: 250,
+                queue_capacity: 16,
+                drop_policy: DropPolicy::Block,
+                reattach: Reattach::Disabled,
+            };
+            runtime.block_on(forward_captured_packets_async(
+                cfg,
+                Some(1),
+                Some(Duration::from_secs(2)),
+                Some(ready_tx),
+                &ShutdownHandle::new(),
+            ))
+        });
+
+        ready_rx
+            .recv_timeout(Duration::from_secs(2))
+            .map_err(|_| anyhow::anyhow!("timed out waiting for capture to be ready"))?;
+
+        let sender = UdpSocket::bind("192.168.1.11:0")?;
+        let payload: [u8; 6] = [0xDE, 0xAD, 0xBE, 0xEF, 0x05, 0x06];
+        sender.send_to(&payload, "192.168.1.10:5555")?;
+
+        let got = server.recv(Duration::from_secs(2))?;
+        assert!(got.windows(payload.len()).any(|w| w == payload));
+
+        let stats = handle
             .join()
             .map_err(|_| anyhow::anyhow!("capture thread panicked"))??;
-        assert_eq!(forwarded, 1);
+        assert_eq!(stats.forwarded, 1);
         Ok(())
     }
 }
@@ -1,6 +1,8 @@
 #[cfg(target_os = "macos")]
 mod macos_only {
     use macos_bpf_tunnel::config::{MONITORED_IP, TUNNEL_TARGET, build_bpf_filter};
+    use macos_bpf_tunnel::hotplug::Reattach;
+    use macos_bpf_tunnel::queue::DropPolicy;
     use macos_bpf_tunnel::runner::{RunnerConfig, forward_captured_packets_with_ready};
     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
     use std::sync::mpsc;
@@ -55,6 +57,9 @@ mod macos_only {
                 filter: &filter,
                 tunnel_target,
                 read_timeout_ms: 250,
+                queue_capacity: 16,
+                drop_policy: DropPolicy::Block,
+                reattach: Reattach::Disabled,
             };
             forward_captured_packets_with_ready(cfg, Some(1), Some(Duration::from_secs(2)), Some(ready_tx))
         });
@@ -77,10 +82,10 @@ mod macos_only {
             got.len()
         );
 
-        let forwarded = handle
+        let stats = handle
             .join()
             .map_err(|_| anyhow::anyhow!("capture thread panicked"))??;
-        assert_eq!(forwarded, 1);
+        assert_eq!(stats.forwarded, 1);
 
         drop(pair);
         Ok(())
@@ -0,0 +1,174 @@
+//! The encrypted data channel as a library API: datagrams in, datagrams out.
+//!
+//! A [`DataChannel`] owns a UDP socket that already reaches the peer (typically one a session
+//! punched through) and runs the Noise IK handshake of [`crate::noise`] over it. As in the client,
+//! the side with the larger client id initiates. Payloads are sealed into peer-wire `Data`
+//! packets, so each datagram is authenticated twice: by the pair key's tag and by the AEAD.
+//! Hellos from a peer still finishing its rendezvous are acknowledged, never delivered.
+
+use crate::noise::{self, StaticKeypair};
+use crate::probe::PairKey;
+use crate::session::{Session, SessionState};
+use anyhow::{Context, Result, bail};
+use peer_wire::{Message, Packet};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+use x25519_dalek::PublicKey;
+
+/// How often a waiting initiator checks whether its initiation is due again.
+const HANDSHAKE_POLL: Duration = Duration::from_millis(200);
+
+pub struct DataChannel {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    local: StaticKeypair,
+    prologue: Vec<u8>,
+    initiator: bool,
+    session: Mutex<Session>,
+}
+
+impl DataChannel {
+    /// Runs the handshake with `peer_id` at `peer`, whose static key is `remote`, and returns the
+    /// open channel. Fails if it is not open within `wait`.
+    pub async fn connect(
+        socket: UdpSocket,
+        peer: SocketAddr,
+        key: PairKey,
+        ids: (&str, &str),
+        local: StaticKeypair,
+        remote: PublicKey,
+        wait: Duration,
+    ) -> Result<Self> {
+        let (client_id, peer_id) = ids;
+        let initiator = client_id > peer_id;
+        let mut session = Session::new(initiator, &[]);
+        session.key = Some(key);
+        session.static_key = Some(remote);
+        session.state = SessionState::Established;
+        session.endpoint = Some(peer);
+        let channel = Self::open(socket, peer, session, ids, local);
+
+        let deadline = Instant::now() + wait;
+        let mut buf = vec![0_u8; 2048];
+        while !channel.session.lock().unwrap().is_channel_open() {
+            let init = channel
+                .session
+                .lock()
+                .unwrap()
+                .initiation_due(&channel.local, &channel.prologue, std::time::Instant::now())
+                .filter(|_| initiator);
+            if let Some(init) = init {
+                channel.send_message(init).await?;
+            }
+            let poll = deadline.min(Instant::now() + HANDSHAKE_POLL);
+            match timeout_at(poll, channel.socket.recv_from(&mut buf)).await {
+                Ok(received) => {
+                    let (n, from) = received.context("data channel recv_from failed")?;
+                    channel.handle(&buf[..n], from).await?;
+                }
+                Err(_) if Instant::now() >= deadline => bail!("no handshake with {peer_id} at {peer} within {wait:?}"),
+                Err(_) => {}
+            }
+        }
+        Ok(channel)
+    }
+
+    /// Takes over `session`, whose handshake with `peer` (if any) ran elsewhere, e.g. in a
+    /// rendezvous. `ids` are ours and the peer's, as in [`DataChannel::connect`].
+    pub fn open(socket: UdpSocket, peer: SocketAddr, session: Session, ids: (&str, &str), local: StaticKeypair) -> Self {
+        let (client_id, peer_id) = ids;
+        let initiator = client_id > peer_id;
+        let prologue = if initiator {
+            noise::prologue(client_id, peer_id)
+        } else {
+            noise::prologue(peer_id, client_id)
+        };
+        Self {
+            socket,
+            peer,
+            local,
+            prologue,
+            initiator,
+            session: Mutex::new(session),
+        }
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Sends one datagram's worth of `payload` to the peer.
+    pub async fn send(&self, payload: &[u8]) -> Result<()> {
+        let message = self
+            .session
+            .lock()
+            .unwrap()
+            .seal_data(payload)
+            .context("data channel is not open")?;
+        self.send_message(message).await
+    }
+
+    /// Waits for the next payload from the peer. Anything else on the socket, including replays
+    /// and forgeries, is dropped; a new handshake from the peer is answered along the way.
+    pub async fn recv(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0_u8; 2048];
+        loop {
+            let (n, from) = self
+                .socket
+                .recv_from(&mut buf)
+                .await
+                .context("data channel recv_from failed")?;
+            if let Some(payload) = self.handle(&buf[..n], from).await? {
+                return Ok(payload);
+            }
+        }
+    }
+
+    /// Processes one datagram; returns the payload if it was data for us.
+    async fn handle(&self, datagram: &[u8], from: SocketAddr) -> Result<Option<Vec<u8>>> {
+        if from != self.peer {
+            return Ok(None);
+        }
+        let (reply, payload) = {
+            let mut session = self.session.lock().unwrap();
+            let Some(Ok(packet)) = session.key.map(|key| Packet::decode(datagram, key.as_bytes())) else {
+                return Ok(None);
+            };
+            match packet.message {
+                Message::HandshakeInit { payload } if !self.initiator => {
+                    (session.accept_initiation(&self.local, &self.prologue, &payload), None)
+                }
+                Message::HandshakeResponse { payload } => {
+                    session.finish_initiation(&payload);
+                    (None, None)
+                }
+                Message::Data { counter, payload } => (None, session.open_data(counter, &payload)),
+                Message::Hello { counter, payload } if session.open_hello(counter, &payload) => {
+                    (session.seal_hello(true), None)
+                }
+                _ => (None, None),
+            }
+        };
+        if let Some(reply) = reply {
+            self.send_message(reply).await?;
+        }
+        Ok(payload)
+    }
+
+    async fn send_message(&self, message: Message) -> Result<()> {
+        let sealed = self
+            .session
+            .lock()
+            .unwrap()
+            .seal(message)
+            .context("failed to encode data channel packet")?;
+        self.socket
+            .send_to(&sealed, self.peer)
+            .await
+            .context("data channel send_to failed")?;
+        Ok(())
+    }
+}
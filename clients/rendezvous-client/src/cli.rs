@@ -0,0 +1,266 @@
+//! Command line: subcommands, their flags, and where values come from.
+//!
+//! A value is taken from the first of: the flag, its environment variable (the names the Docker
+//! setups use), the config file, the default. Without a subcommand the client runs `punch`, so
+//! `rendezvous-client` with only environment variables behaves as it always did.
+//!
+//! The config file (`--config`, or `CONFIG`) is TOML with one key per long flag name, e.g.
+//! `client-id = "a"` or `peers = ["b", "c"]`. Every subcommand takes the keys it knows and
+//! ignores the rest, so one file can serve all of them.
+
+use anyhow::{Context, Result, bail};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use rendezvous_client::overlay::OverlayAddress;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Rendezvous, NAT traversal and VPN client for the vpn coordinator")]
+pub struct Cli {
+    /// TOML file with defaults for any flag, keyed by long flag name.
+    #[arg(long, global = true, env = "CONFIG")]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Join a room and punch an encrypted path to every peer in it (the default).
+    Punch(Box<PunchArgs>),
+    /// Classify our NAT's mapping and filtering behaviour (RFC 5780), optionally with the binding lifetime.
+    ProbeNat(ProbeNatArgs),
+    /// Join a room briefly and print who is in it.
+    ListPeers(ListPeersArgs),
+    /// Send one STUN Binding request and print our reflexive address.
+    Stun(StunArgs),
+    /// Measure data channel throughput over loopback.
+    Bench(BenchArgs),
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct CoordinatorArgs {
+    #[arg(long, env = "COORDINATOR_HOST", default_value = "coordinator")]
+    pub coordinator_host: String,
+    #[arg(long, env = "COORDINATOR_HTTP_PORT", default_value_t = 4000)]
+    pub coordinator_http_port: u16,
+    #[arg(long, env = "COORDINATOR_UDP_PORT", default_value_t = 3478)]
+    pub coordinator_udp_port: u16,
+    #[arg(long, env = "ROOM", default_value = "demo")]
+    pub room: String,
+    /// Seconds to wait for the coordinator, and for peers before giving up.
+    #[arg(long, env = "TIMEOUT_SECS", default_value_t = 15)]
+    pub timeout_secs: u64,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct PunchArgs {
+    #[command(flatten)]
+    pub coordinator: CoordinatorArgs,
+    #[arg(long, env = "CLIENT_ID")]
+    pub client_id: String,
+    /// Peers that must be reached before the run counts as done; without any, every peer present.
+    #[arg(long, env = "PEERS", value_delimiter = ',')]
+    pub peers: Vec<String>,
+    /// Older single-peer form of `--peers`.
+    #[arg(long, env = "PEER_ID", hide = true)]
+    pub peer_id: Option<String>,
+    /// Fixed peer keepalive; without one it follows the NAT binding lifetime once that is known.
+    #[arg(long, env = "KEEPALIVE_SECS")]
+    pub keepalive_secs: Option<u64>,
+    /// Largest idle gap the binding lifetime probe tries; 0 disables the probe.
+    #[arg(long, env = "LIFETIME_MAX_SECS", default_value_t = 120)]
+    pub lifetime_max_secs: u64,
+    /// Keep sessions up this long after the mesh is done, instead of exiting right away. TUN
+    /// mode runs until stopped regardless.
+    #[arg(long, env = "STAY_SECS", default_value_t = 0)]
+    pub stay_secs: u64,
+    /// STUN server (RFC 5780 capable for NAT classification) to learn our reflexive address from.
+    #[arg(long, env = "STUN_SERVER")]
+    pub stun_server: Option<String>,
+    /// TURN server to relay through when direct punching fails.
+    #[arg(long, env = "TURN_SERVER", requires_all = ["turn_username", "turn_password"])]
+    pub turn_server: Option<String>,
+    #[arg(long, env = "TURN_USERNAME")]
+    pub turn_username: Option<String>,
+    #[arg(long, env = "TURN_PASSWORD", hide_env_values = true)]
+    pub turn_password: Option<String>,
+    /// Overlay address to request for TUN mode; its prefix is the overlay network.
+    #[arg(long, env = "OVERLAY_ADDRESS")]
+    pub overlay_address: Option<OverlayAddress>,
+    /// Overlay network to claim a free address in for TUN mode.
+    #[arg(long, env = "OVERLAY_CIDR")]
+    pub overlay_cidr: Option<OverlayAddress>,
+    #[arg(long, env = "TUN_DEVICE", default_value = "vpn0")]
+    pub tun_device: String,
+    /// `json` adds a run report as the last line of output, on success and failure alike.
+    #[arg(long, env = "REPORT", value_enum, default_value_t = ReportFormat::Text)]
+    pub report: ReportFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// Log lines only.
+    Text,
+    Json,
+}
+
+impl PunchArgs {
+    /// `--peers`, or the older `PEER_ID`.
+    pub fn wanted(&self) -> Vec<String> {
+        let peers = if self.peers.is_empty() {
+            self.peer_id.iter().flat_map(|peers| peers.split(',')).map(String::from).collect()
+        } else {
+            self.peers.clone()
+        };
+        peers
+            .iter()
+            .map(|peer| peer.trim())
+            .filter(|peer| !peer.is_empty())
+            .map(String::from)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct ProbeNatArgs {
+    #[arg(long, env = "STUN_SERVER")]
+    pub stun_server: String,
+    /// Also measure the binding lifetime, trying idle gaps up to this long.
+    #[arg(long)]
+    pub lifetime_max_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct ListPeersArgs {
+    #[command(flatten)]
+    pub coordinator: CoordinatorArgs,
+    /// Id to join under; peers see it in presence while listing.
+    #[arg(long, env = "CLIENT_ID", default_value = "list-peers")]
+    pub client_id: String,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct StunArgs {
+    #[arg(long, env = "STUN_SERVER")]
+    pub stun_server: String,
+    /// Transmissions before giving up.
+    #[arg(long, default_value_t = 7)]
+    pub attempts: u32,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct BenchArgs {
+    #[arg(long, default_value_t = 10_000)]
+    pub packets: u32,
+    /// Payload bytes per packet.
+    #[arg(long, default_value_t = 1200)]
+    pub size: usize,
+}
+
+/// Parses the command line, with values from the config file filled in. Usage errors, `--help`
+/// and `--version` exit the process as clap does.
+pub fn parse() -> Result<Command> {
+    parse_from(std::env::args_os()).map_err(|e| match e.downcast::<clap::Error>() {
+        Ok(e) => e.exit(),
+        Err(e) => e,
+    })
+}
+
+pub fn parse_from(args: impl IntoIterator<Item = OsString>) -> Result<Command> {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+    // A first, lenient pass finds the subcommand and config file; required values may still be
+    // missing at this point, as the config file can supply them.
+    let lenient = Cli::command().ignore_errors(true).try_get_matches_from(&args)?;
+    if lenient.subcommand().is_none() {
+        args.insert(1.min(args.len()), "punch".into());
+    }
+    let lenient = Cli::command().ignore_errors(true).try_get_matches_from(&args)?;
+    if let Some(path) = lenient.get_one::<PathBuf>("config") {
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read config {}", path.display()))?;
+        let config: toml::Table = text
+            .parse()
+            .with_context(|| format!("failed to parse config {}", path.display()))?;
+        let (_, sub) = lenient.subcommand().context("no subcommand")?;
+        args.extend(config_args(sub, &config)?);
+    }
+    let cli = Cli::try_parse_from(&args)?;
+    cli.command.context("no subcommand")
+}
+
+/// Flags for the config values `sub` takes that came neither from the command line nor from
+/// the environment.
+fn config_args(sub: &ArgMatches, config: &toml::Table) -> Result<Vec<OsString>> {
+    let mut out = Vec::new();
+    for (key, value) in config {
+        let id = key.replace('-', "_");
+        if id == "config" {
+            continue;
+        }
+        let Ok(raw) = sub.try_get_raw(&id) else {
+            continue;
+        };
+        if raw.is_some() && sub.value_source(&id) != Some(ValueSource::DefaultValue) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(items) => items.iter().map(scalar).collect::<Result<Vec<_>>>()?,
+            value => vec![scalar(value)?],
+        };
+        for value in values {
+            out.push(format!("--{key}={value}", key = id.replace('_', "-")).into());
+        }
+    }
+    Ok(out)
+}
+
+fn scalar(value: &toml::Value) -> Result<String> {
+    Ok(match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => f.to_string(),
+        toml::Value::Boolean(b) => b.to_string(),
+        other => bail!("unsupported config value {other}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, ReportFormat, parse_from};
+    use std::ffi::OsString;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Command> {
+        parse_from(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn punch_is_the_default_subcommand() {
+        let Command::Punch(args) = parse(&["rendezvous-client", "--client-id", "a", "--peers", "b,c"]).unwrap() else {
+            panic!("expected punch");
+        };
+        assert_eq!(args.client_id, "a");
+        assert_eq!(args.wanted(), ["b", "c"]);
+        assert_eq!(args.coordinator.room, "demo");
+        assert_eq!(args.report, ReportFormat::Text);
+        assert!(matches!(parse(&["rendezvous-client", "stun", "--stun-server", "s:3478"]).unwrap(), Command::Stun(_)));
+        assert!(parse(&["rendezvous-client", "stun"]).is_err(), "stun needs a server");
+    }
+
+    #[test]
+    fn config_file_fills_what_flags_leave_open() {
+        let path = std::env::temp_dir().join(format!("rendezvous-client-{}.toml", std::process::id()));
+        std::fs::write(&path, "client-id = \"a\"\nroom = \"lab\"\npeers = [\"b\", \"c\"]\nstay_secs = 5\npackets = 1\nreport = \"json\"\n").unwrap();
+        let config = path.to_str().unwrap();
+
+        let Command::Punch(args) = parse(&["rendezvous-client", "--config", config, "--room", "override"]).unwrap() else {
+            panic!("expected punch");
+        };
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(args.client_id, "a");
+        assert_eq!(args.coordinator.room, "override", "flags beat the config file");
+        assert_eq!(args.wanted(), ["b", "c"]);
+        assert_eq!(args.stay_secs, 5);
+        assert_eq!(args.report, ReportFormat::Json);
+    }
+}
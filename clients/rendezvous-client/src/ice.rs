@@ -0,0 +1,406 @@
+//! ICE-style (RFC 8445) candidates, pairing and paced connectivity checks.
+//!
+//! Not a full ICE agent: there is a single component, checks are peer-wire `Probe`/`ProbeAck`
+//! packets rather than STUN, and the roles are fixed by client id.
+//! What it keeps from ICE is the part that matters for choosing a path: every local candidate
+//! is paired with every remote one, pairs are checked in priority order at a fixed pace, and
+//! the controlling side nominates the best pair that worked. Host pairs outrank everything, so
+//! two peers behind the same NAT end up on their LAN addresses instead of hairpinning.
+//!
+//! Local candidates carry a caller-chosen `base` handle (which socket or relay to send from),
+//! so the checklist never touches sockets itself.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CandidateKind {
+    Host,
+    /// Peer-reflexive: learned from where a check actually came from.
+    Prflx,
+    /// Server-reflexive: our NAT's public mapping, as seen by the coordinator or a STUN server.
+    Srflx,
+    Relay,
+}
+
+impl CandidateKind {
+    /// RFC 8445 section 5.1.2.2 recommended type preferences.
+    fn type_preference(self) -> u32 {
+        match self {
+            Self::Host => 126,
+            Self::Prflx => 110,
+            Self::Srflx => 100,
+            Self::Relay => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub address: SocketAddr,
+    pub priority: u32,
+}
+
+impl Candidate {
+    pub fn new(kind: CandidateKind, address: SocketAddr) -> Self {
+        // Single component (id 1) and a single interface, so local preference is always max.
+        let priority = (kind.type_preference() << 24) | (u32::from(u16::MAX) << 8) | (256 - 1);
+        Self {
+            kind,
+            address,
+            priority,
+        }
+    }
+}
+
+/// RFC 8445 section 6.1.2.3: `2^32*MIN(G,D) + 2*MAX(G,D) + (G>D?1:0)`.
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (u64::from(controlling), u64::from(controlled));
+    (g.min(d) << 32) + 2 * g.max(d) + u64::from(g > d)
+}
+
+/// The address a socket bound to `local` uses towards `towards`. Resolves an unspecified bind
+/// address (`0.0.0.0`) to the interface the route picks; nothing is sent.
+pub fn host_address(local: SocketAddr, towards: SocketAddr) -> io::Result<SocketAddr> {
+    if !local.ip().is_unspecified() {
+        return Ok(local);
+    }
+    let any = match towards.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let probe = UdpSocket::bind(SocketAddr::new(any, 0))?;
+    probe.connect(towards)?;
+    Ok(SocketAddr::new(probe.local_addr()?.ip(), local.port()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PairState {
+    Waiting,
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CandidatePair<B> {
+    pub local: Candidate,
+    pub base: B,
+    pub remote: Candidate,
+    pub priority: u64,
+    pub state: PairState,
+    checks: u32,
+}
+
+pub struct Checklist<B> {
+    controlling: bool,
+    locals: Vec<(Candidate, B)>,
+    remotes: Vec<Candidate>,
+    /// Sorted by descending priority.
+    pairs: Vec<CandidatePair<B>>,
+    pace: Duration,
+    max_checks: u32,
+    nomination_delay: Duration,
+    budget: f64,
+    last_paced: Instant,
+    cursor: usize,
+    first_success: Option<Instant>,
+    selected: Option<usize>,
+}
+
+impl<B: Copy + Eq> Checklist<B> {
+    /// One check every 50 ms (RFC 8445's Ta), a pair fails after 10 unanswered checks, and the
+    /// controlling side waits up to 500 ms after the first success for better pairs.
+    pub fn new(controlling: bool) -> Self {
+        Self {
+            controlling,
+            locals: Vec::new(),
+            remotes: Vec::new(),
+            pairs: Vec::new(),
+            pace: Duration::from_millis(50),
+            max_checks: 10,
+            nomination_delay: Duration::from_millis(500),
+            budget: 1.0,
+            last_paced: Instant::now(),
+            cursor: 0,
+            first_success: None,
+            selected: None,
+        }
+    }
+
+    pub fn local_candidates(&self) -> impl Iterator<Item = &Candidate> {
+        self.locals.iter().map(|(candidate, _)| candidate)
+    }
+
+    pub fn remote_candidates(&self) -> impl Iterator<Item = &Candidate> {
+        self.remotes.iter()
+    }
+
+    pub fn pairs(&self) -> &[CandidatePair<B>] {
+        &self.pairs
+    }
+
+    /// Adds a local candidate; returns `false` if it was already known.
+    pub fn add_local(&mut self, candidate: Candidate, base: B) -> bool {
+        if self.locals.iter().any(|(c, _)| c.address == candidate.address) {
+            return false;
+        }
+        self.locals.push((candidate, base));
+        for remote in self.remotes.clone() {
+            if same_family(&candidate, &remote) {
+                self.insert_pair(candidate, base, remote, PairState::Waiting);
+            }
+        }
+        true
+    }
+
+    /// Adds a remote candidate; returns `false` if it was already known.
+    pub fn add_remote(&mut self, candidate: Candidate) -> bool {
+        if self.remotes.iter().any(|c| c.address == candidate.address) {
+            return false;
+        }
+        self.remotes.push(candidate);
+        for (local, base) in self.locals.clone() {
+            if same_family(&local, &candidate) {
+                self.insert_pair(local, base, candidate, PairState::Waiting);
+            }
+        }
+        true
+    }
+
+    /// Checks that are due at `now`, paced at one per 50 ms, as `(base, remote address)`.
+    /// Pairs are visited round-robin in priority order until they succeed or fail.
+    pub fn due_checks(&mut self, now: Instant) -> Vec<(B, SocketAddr)> {
+        let elapsed = now.saturating_duration_since(self.last_paced);
+        self.last_paced = now;
+        let burst = self.pairs.len().max(1) as f64;
+        self.budget = (self.budget + elapsed.as_secs_f64() / self.pace.as_secs_f64()).min(burst);
+
+        let mut due = Vec::new();
+        while self.budget >= 1.0 {
+            let Some(index) = self.next_checkable() else {
+                break;
+            };
+            self.budget -= 1.0;
+            let pair = &mut self.pairs[index];
+            pair.checks += 1;
+            if pair.checks > self.max_checks {
+                pair.state = PairState::Failed;
+                continue;
+            }
+            pair.state = PairState::InProgress;
+            due.push((pair.base, pair.remote.address));
+        }
+        due
+    }
+
+    /// A check arrived on `base` from `from`. Unknown sources become peer-reflexive remote
+    /// candidates so the answer path gets checked too (a "triggered" check).
+    pub fn on_request(&mut self, base: B, from: SocketAddr) {
+        if self.find(base, from).is_none() {
+            self.learn_prflx(base, from, PairState::Waiting);
+        }
+    }
+
+    /// A check we sent from `base` was answered by `from`.
+    pub fn on_response(&mut self, base: B, from: SocketAddr, now: Instant) {
+        match self.find(base, from) {
+            Some(index) => self.pairs[index].state = PairState::Succeeded,
+            None => {
+                self.learn_prflx(base, from, PairState::Succeeded);
+            }
+        }
+        self.first_success.get_or_insert(now);
+    }
+
+    /// Controlling side: picks the pair to use once the best succeeded pair can no longer be
+    /// beaten (every higher-priority pair failed) or the nomination delay ran out.
+    pub fn nominate(&mut self, now: Instant) -> Option<CandidatePair<B>> {
+        if !self.controlling || self.selected.is_some() {
+            return None;
+        }
+        let best = self.pairs.iter().position(|p| p.state == PairState::Succeeded)?;
+        let unbeatable = self.pairs[..best].iter().all(|p| p.state == PairState::Failed);
+        let waited = self
+            .first_success
+            .is_some_and(|at| now.saturating_duration_since(at) >= self.nomination_delay);
+        if !(unbeatable || waited) {
+            return None;
+        }
+        self.selected = Some(best);
+        Some(self.pairs[best])
+    }
+
+    /// Controlled side: the peer nominated the pair it reached us on.
+    pub fn select(&mut self, base: B, from: SocketAddr) -> CandidatePair<B> {
+        let index = match self.find(base, from) {
+            Some(index) => index,
+            None => self.learn_prflx(base, from, PairState::Succeeded),
+        };
+        self.pairs[index].state = PairState::Succeeded;
+        self.selected = Some(index);
+        self.pairs[index]
+    }
+
+    pub fn selected(&self) -> Option<&CandidatePair<B>> {
+        self.selected.map(|index| &self.pairs[index])
+    }
+
+    /// True once there were pairs to check and all of them failed.
+    pub fn is_failed(&self) -> bool {
+        !self.pairs.is_empty() && self.pairs.iter().all(|p| p.state == PairState::Failed)
+    }
+
+    fn next_checkable(&mut self) -> Option<usize> {
+        let len = self.pairs.len();
+        (0..len)
+            .map(|step| (self.cursor + step) % len)
+            .find(|&i| matches!(self.pairs[i].state, PairState::Waiting | PairState::InProgress))
+            .inspect(|&i| self.cursor = i + 1)
+    }
+
+    fn find(&self, base: B, remote: SocketAddr) -> Option<usize> {
+        self.pairs
+            .iter()
+            .position(|p| p.base == base && p.remote.address == remote)
+    }
+
+    fn learn_prflx(&mut self, base: B, from: SocketAddr, state: PairState) -> usize {
+        let remote = Candidate::new(CandidateKind::Prflx, from);
+        if !self.remotes.iter().any(|c| c.address == from) {
+            self.remotes.push(remote);
+        }
+        // A check can arrive on a base we never advertised (e.g. a punching socket); its own
+        // address is unknown, so the pair is recorded with a peer-reflexive placeholder.
+        let local = self
+            .locals
+            .iter()
+            .find(|(c, b)| *b == base && same_family(c, &remote))
+            .map_or(Candidate::new(CandidateKind::Prflx, from), |(c, _)| *c);
+        self.insert_pair(local, base, remote, state)
+    }
+
+    fn insert_pair(&mut self, local: Candidate, base: B, remote: Candidate, state: PairState) -> usize {
+        let priority = if self.controlling {
+            pair_priority(local.priority, remote.priority)
+        } else {
+            pair_priority(remote.priority, local.priority)
+        };
+        if let Some(existing) = self.find(base, remote.address) {
+            return existing;
+        }
+        let pair = CandidatePair {
+            local,
+            base,
+            remote,
+            priority,
+            state,
+            checks: 0,
+        };
+        let index = self.pairs.partition_point(|p| p.priority >= priority);
+        self.pairs.insert(index, pair);
+        if let Some(selected) = self.selected.as_mut()
+            && *selected >= index
+        {
+            *selected += 1;
+        }
+        if self.cursor > index {
+            self.cursor += 1;
+        }
+        index
+    }
+}
+
+fn same_family(a: &Candidate, b: &Candidate) -> bool {
+    a.address.is_ipv4() == b.address.is_ipv4()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Candidate, CandidateKind, Checklist, PairState, pair_priority};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn priorities_follow_rfc8445() {
+        let host = Candidate::new(CandidateKind::Host, addr("192.168.1.2:5000"));
+        let srflx = Candidate::new(CandidateKind::Srflx, addr("198.51.100.1:40000"));
+        let relay = Candidate::new(CandidateKind::Relay, addr("203.0.113.9:50000"));
+        assert_eq!(host.priority, 2_130_706_431);
+        assert!(host.priority > srflx.priority && srflx.priority > relay.priority);
+        assert_eq!(pair_priority(2, 1), (1 << 32) + 4 + 1);
+        assert_eq!(pair_priority(1, 2), (1 << 32) + 4);
+    }
+
+    #[test]
+    fn same_nat_peers_nominate_the_lan_pair() {
+        let mut checklist = Checklist::new(true);
+        checklist.add_local(Candidate::new(CandidateKind::Host, addr("192.168.1.2:5000")), 0_u8);
+        checklist.add_local(Candidate::new(CandidateKind::Srflx, addr("198.51.100.1:40000")), 0_u8);
+        checklist.add_remote(Candidate::new(CandidateKind::Srflx, addr("198.51.100.1:40001")));
+        checklist.add_remote(Candidate::new(CandidateKind::Host, addr("192.168.1.3:5000")));
+
+        // Both locals share a base, so each remote is one pair; the host pair is checked first.
+        let start = Instant::now();
+        let due = checklist.due_checks(start);
+        assert_eq!(due, [(0, addr("192.168.1.3:5000"))]);
+        let due = checklist.due_checks(start + Duration::from_millis(50));
+        assert_eq!(due, [(0, addr("198.51.100.1:40001"))]);
+
+        // The hairpinned srflx pair answers first, but the host pair can still win.
+        checklist.on_response(0, addr("198.51.100.1:40001"), start);
+        assert_eq!(checklist.nominate(start), None);
+        checklist.on_response(0, addr("192.168.1.3:5000"), start);
+        let nominated = checklist.nominate(start).expect("host pair is unbeatable");
+        assert_eq!(nominated.remote.kind, CandidateKind::Host);
+        assert_eq!(checklist.selected().map(|p| p.remote.address), Some(addr("192.168.1.3:5000")));
+    }
+
+    #[test]
+    fn unanswered_pairs_fail_and_the_delay_settles_for_what_worked() {
+        let mut checklist = Checklist::new(true);
+        checklist.add_local(Candidate::new(CandidateKind::Host, addr("10.0.0.2:5000")), 0_u8);
+        checklist.add_remote(Candidate::new(CandidateKind::Host, addr("10.9.0.2:5000")));
+        checklist.add_remote(Candidate::new(CandidateKind::Relay, addr("203.0.113.9:50000")));
+
+        let start = Instant::now();
+        checklist.on_response(0, addr("203.0.113.9:50000"), start);
+        assert_eq!(checklist.nominate(start), None, "the host pair is still pending");
+        let nominated = checklist
+            .nominate(start + Duration::from_millis(500))
+            .expect("nomination delay elapsed");
+        assert_eq!(nominated.remote.kind, CandidateKind::Relay);
+
+        let mut lonely = Checklist::new(false);
+        lonely.add_local(Candidate::new(CandidateKind::Host, addr("10.0.0.2:5000")), 0_u8);
+        lonely.add_remote(Candidate::new(CandidateKind::Host, addr("10.9.0.2:5000")));
+        let mut now = start;
+        for _ in 0..20 {
+            now += Duration::from_millis(50);
+            lonely.due_checks(now);
+        }
+        assert!(lonely.is_failed());
+        assert_eq!(lonely.pairs()[0].state, PairState::Failed);
+        assert_eq!(lonely.nominate(now), None, "only the controlling side nominates");
+    }
+
+    #[test]
+    fn checks_from_unknown_sources_become_peer_reflexive_pairs() {
+        let mut checklist = Checklist::new(false);
+        checklist.add_local(Candidate::new(CandidateKind::Host, addr("10.0.0.2:5000")), 0_u8);
+        checklist.on_request(0, addr("198.51.100.7:41000"));
+        assert_eq!(checklist.pairs()[0].remote.kind, CandidateKind::Prflx);
+
+        let selected = checklist.select(3, addr("198.51.100.7:41001"));
+        assert_eq!(selected.base, 3);
+        assert_eq!(checklist.selected().map(|p| p.remote.address), Some(addr("198.51.100.7:41001")));
+    }
+}
@@ -0,0 +1,17 @@
+pub mod channel;
+pub mod ice;
+pub mod lifetime;
+pub mod nat;
+pub mod noise;
+pub mod overlay;
+pub mod phoenix;
+pub mod presence;
+pub mod probe;
+pub mod punch;
+pub mod rendezvous;
+pub mod report;
+pub mod session;
+pub mod stats;
+pub mod stun;
+pub mod tun;
+pub mod turn;
@@ -0,0 +1,162 @@
+//! NAT binding lifetime discovery (RFC 5780 section 4.6).
+//!
+//! A probe socket opens a binding with a Binding request and then stays silent. After an idle
+//! gap, a second socket asks the server (RESPONSE-PORT) to answer to the probe socket's mapped
+//! port instead of its own: if the answer gets through, the binding survived the gap. Gaps double
+//! until one fails or the limit is reached, so a run takes about twice the largest gap tried.
+//!
+//! Both sockets must map to the same public IP, which holds for endpoint-independent mapping and
+//! for most NATs with a single public address, and the server must support RESPONSE-PORT.
+
+use crate::stun::{Attribute, BindingOptions, Message, binding_request};
+use anyhow::{Context, Result};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, sleep, timeout_at};
+
+/// Share of the longest surviving gap used as the keepalive interval.
+const SAFETY_MARGIN: f64 = 0.75;
+const MIN_KEEPALIVE: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct LifetimeOptions {
+    pub first_gap: Duration,
+    /// Gaps are not grown past this; a binding that survives it is taken to live at least as long.
+    pub max_gap: Duration,
+    /// Transmissions of each Binding request; a check whose answers are all lost reads as expiry.
+    pub binding: BindingOptions,
+}
+
+impl Default for LifetimeOptions {
+    fn default() -> Self {
+        Self {
+            first_gap: Duration::from_secs(5),
+            max_gap: Duration::from_secs(120),
+            binding: BindingOptions {
+                attempts: 3,
+                ..BindingOptions::default()
+            },
+        }
+    }
+}
+
+/// What the probe learned: the binding lives at least `survived`, and less than `expired`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BindingLifetime {
+    /// Longest idle gap the binding survived; zero if even the first gap was too long.
+    pub survived: Duration,
+    /// Shortest idle gap it did not survive; `None` if it outlived the largest gap tried.
+    pub expired: Option<Duration>,
+}
+
+impl BindingLifetime {
+    /// A keepalive interval that refreshes the binding well within its lifetime.
+    pub fn keepalive_interval(&self) -> Duration {
+        let safe = match self.expired {
+            Some(expired) if self.survived.is_zero() => expired / 2,
+            _ => self.survived,
+        };
+        safe.mul_f64(SAFETY_MARGIN).max(MIN_KEEPALIVE)
+    }
+}
+
+impl fmt::Display for BindingLifetime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ">= {:?}", self.survived)?;
+        if let Some(expired) = self.expired {
+            write!(f, " (< {expired:?})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Measures how long a binding on `local_ip` survives without outbound traffic. Uses two fresh
+/// sockets, so it can run alongside the client's own traffic.
+pub async fn discover(
+    local_ip: IpAddr,
+    server: SocketAddr,
+    options: &LifetimeOptions,
+) -> Result<BindingLifetime> {
+    let bind = || async {
+        UdpSocket::bind(SocketAddr::new(local_ip, 0))
+            .await
+            .context("failed to bind binding lifetime probe socket")
+    };
+    let (probe, checker) = (bind().await?, bind().await?);
+
+    let mut survived = Duration::ZERO;
+    let mut gap = options.first_gap;
+    loop {
+        // (Re)opens the binding; the idle gap starts now.
+        let mapped = binding_request(&probe, server, &options.binding).await?;
+        sleep(gap).await;
+        if !answered_at(&probe, &checker, server, mapped.port(), &options.binding).await? {
+            return Ok(BindingLifetime {
+                survived,
+                expired: Some(gap),
+            });
+        }
+        survived = gap;
+        if gap >= options.max_gap {
+            return Ok(BindingLifetime {
+                survived,
+                expired: None,
+            });
+        }
+        gap = (gap * 2).min(options.max_gap);
+    }
+}
+
+/// Sends a Binding request from `checker` asking for the answer at `port`, and reports whether
+/// it reached `probe`. `probe` itself sends nothing, so the check does not refresh its binding.
+async fn answered_at(
+    probe: &UdpSocket,
+    checker: &UdpSocket,
+    server: SocketAddr,
+    port: u16,
+    options: &BindingOptions,
+) -> Result<bool> {
+    let request = Message::binding_request().with(Attribute::ResponsePort(port));
+    let packet = request.encode(None, options.fingerprint);
+    let mut rto = options.rto;
+    let mut buf = vec![0_u8; 1500];
+    for _ in 0..options.attempts.max(1) {
+        checker
+            .send_to(&packet, server)
+            .await
+            .context("failed to send stun request")?;
+        let deadline = Instant::now() + rto;
+        while let Ok(received) = timeout_at(deadline, probe.recv_from(&mut buf)).await {
+            let (n, _) = received.context("stun recv_from failed")?;
+            if Message::decode(&buf[..n]).is_ok_and(|response| response.transaction_id == request.transaction_id) {
+                return Ok(true);
+            }
+        }
+        rto *= 2;
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BindingLifetime;
+    use std::time::Duration;
+
+    #[test]
+    fn keepalive_stays_inside_the_lifetime() {
+        let lifetime = BindingLifetime {
+            survived: Duration::from_secs(40),
+            expired: Some(Duration::from_secs(80)),
+        };
+        assert_eq!(lifetime.keepalive_interval(), Duration::from_secs(30));
+        assert_eq!(lifetime.to_string(), ">= 40s (< 80s)");
+
+        let short = BindingLifetime {
+            survived: Duration::ZERO,
+            expired: Some(Duration::from_secs(4)),
+        };
+        assert_eq!(short.keepalive_interval(), Duration::from_millis(1500));
+    }
+}
@@ -0,0 +1,149 @@
+//! NAT behaviour discovery (RFC 5780) against a STUN server that has an alternate address.
+//!
+//! Mapping behaviour tells whether the public endpoint we learn from the coordinator is the
+//! one peers will see; filtering behaviour tells whether unsolicited peer packets get in.
+//! Peers publish the result in their presence metas to pick a punching strategy.
+
+use crate::stun::{Attribute, BindingOptions, Message, transact};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// How a NAT keys its mappings (or its inbound filter), from most to least permissive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Behavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+impl fmt::Display for Behavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::EndpointIndependent => "endpoint-independent",
+            Self::AddressDependent => "address-dependent",
+            Self::AddressAndPortDependent => "address-and-port-dependent",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NatBehavior {
+    pub mapping: Behavior,
+    pub filtering: Behavior,
+    /// Reflexive address of the probed socket, as seen by the primary server address.
+    pub mapped: SocketAddr,
+}
+
+impl NatBehavior {
+    /// Shape used in presence metas and join params: `{"mapping": ..., "filtering": ..., "mapped": ...}`.
+    pub fn to_meta(&self) -> Value {
+        serde_json::to_value(self).expect("nat behavior serializes")
+    }
+
+    pub fn from_meta(meta: &Value) -> Option<Self> {
+        serde_json::from_value(meta.get("nat")?.clone()).ok()
+    }
+}
+
+impl fmt::Display for NatBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mapping {}, filtering {} (mapped {})", self.mapping, self.filtering, self.mapped)
+    }
+}
+
+/// Classifies mapping and filtering behaviour.
+///
+/// Mapping tests run on `socket`, so `mapped` is the address peers should use for it.
+/// Filtering tests run on a fresh socket: the mapping tests already contacted the alternate
+/// address, which would let its answers through any filter. Unanswered filtering probes wait
+/// out the full retransmission schedule in `options`, so keep `attempts` small.
+pub async fn classify(socket: &UdpSocket, server: SocketAddr, options: &BindingOptions) -> Result<NatBehavior> {
+    let local = socket.local_addr()?;
+    let primary = probe(socket, server, None, options)
+        .await?
+        .with_context(|| format!("no stun binding response from {server}"))?;
+    let mapped = primary.mapped_address().context("stun response carries no mapped address")?;
+    let other = primary
+        .other_address()
+        .with_context(|| format!("stun server {server} has no alternate address (RFC 5780)"))?;
+
+    let mapping = if mapped == local {
+        Behavior::EndpointIndependent
+    } else {
+        // Test II: alternate IP, primary port. Test III: alternate IP and port.
+        let alt_ip = mapped_via(socket, SocketAddr::new(other.ip(), server.port()), options).await?;
+        if alt_ip == mapped {
+            Behavior::EndpointIndependent
+        } else if mapped_via(socket, other, options).await? == alt_ip {
+            Behavior::AddressDependent
+        } else {
+            Behavior::AddressAndPortDependent
+        }
+    };
+
+    let fresh = UdpSocket::bind(SocketAddr::new(local.ip(), 0))
+        .await
+        .context("failed to bind filtering probe socket")?;
+    probe(&fresh, server, None, options)
+        .await?
+        .with_context(|| format!("no stun binding response from {server}"))?;
+    let filtering = if probe(&fresh, server, Some((true, true)), options).await?.is_some() {
+        Behavior::EndpointIndependent
+    } else if probe(&fresh, server, Some((false, true)), options).await?.is_some() {
+        Behavior::AddressDependent
+    } else {
+        Behavior::AddressAndPortDependent
+    };
+
+    Ok(NatBehavior {
+        mapping,
+        filtering,
+        mapped,
+    })
+}
+
+async fn mapped_via(socket: &UdpSocket, server: SocketAddr, options: &BindingOptions) -> Result<SocketAddr> {
+    probe(socket, server, None, options)
+        .await?
+        .with_context(|| format!("no stun binding response from {server}"))?
+        .mapped_address()
+        .context("stun response carries no mapped address")
+}
+
+/// One Binding request, optionally with CHANGE-REQUEST `(change_ip, change_port)`.
+async fn probe(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change: Option<(bool, bool)>,
+    options: &BindingOptions,
+) -> Result<Option<Message>> {
+    let mut request = Message::binding_request();
+    if let Some((change_ip, change_port)) = change {
+        request.attributes.push(Attribute::ChangeRequest { change_ip, change_port });
+    }
+    transact(socket, server, request, options).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Behavior, NatBehavior};
+    use serde_json::json;
+
+    #[test]
+    fn round_trips_through_presence_meta() {
+        let nat = NatBehavior {
+            mapping: Behavior::AddressAndPortDependent,
+            filtering: Behavior::EndpointIndependent,
+            mapped: "198.51.100.1:40000".parse().unwrap(),
+        };
+        let meta = json!({ "udp": "198.51.100.1:40000", "nat": nat.to_meta() });
+        assert_eq!(meta["nat"]["mapping"], "address-and-port-dependent");
+        assert_eq!(NatBehavior::from_meta(&meta), Some(nat));
+        assert_eq!(NatBehavior::from_meta(&json!({ "udp": "x" })), None);
+    }
+}
@@ -0,0 +1,558 @@
+//! Noise IK handshake and transport keys for the peer data channel.
+//!
+//! `Noise_IK_25519_ChaChaPoly_SHA256`: the initiator already knows the responder's static key
+//! from presence and sends its own, encrypted, in the first message, so one round trip gives
+//! both sides transport keys and authenticates both static keys. The prologue names both client
+//! ids, which binds the keys to them: a handshake where either side has another id fails.
+//!
+//! Static keys come from the `static_key` presence meta, and the coordinator authenticates no
+//! one, so the binding of a key to a client id is trust on first use: a session keeps the first
+//! key presence showed for its peer. A client id whose metas carry conflicting keys gets none
+//! until they agree again, so a second client claiming a taken id cannot slip its key in beside
+//! the first one; but whoever publishes a key for an id first is believed.
+//!
+//! The first message carries the initiator's wall clock in microseconds; a responder only
+//! accepts initiations newer than the last one from that peer, so a recorded initiation cannot
+//! be replayed to reset the session. Transport packets carry an explicit 64-bit counter used as
+//! the ChaCha20-Poly1305 nonce, and receivers drop counters already seen or too far behind the
+//! newest (a sliding window, as datagrams may be lost or reordered).
+
+use crate::probe::{from_hex, to_hex};
+use anyhow::{Result, anyhow, bail, ensure};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_IK_25519_ChaChaPoly_SHA256";
+const DH_LEN: usize = 32;
+const AEAD_TAG_LEN: usize = 16;
+const TIMESTAMP_LEN: usize = 8;
+/// `e`, encrypted `s` and the encrypted timestamp payload.
+pub const INIT_LEN: usize = DH_LEN + DH_LEN + AEAD_TAG_LEN + TIMESTAMP_LEN + AEAD_TAG_LEN;
+/// `e` and the encrypted, empty payload.
+pub const RESPONSE_LEN: usize = DH_LEN + AEAD_TAG_LEN;
+/// Counters remembered behind the newest one received.
+pub const REPLAY_WINDOW: u64 = 128;
+
+/// A client's long-term X25519 key; the public half is published as hex in the `static_key`
+/// presence meta.
+#[derive(Clone)]
+pub struct StaticKeypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Self {
+        Self::from_secret(rand::random())
+    }
+
+    pub fn from_secret(bytes: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        self.public
+    }
+
+    pub fn public_hex(&self) -> String {
+        to_hex(self.public.as_bytes())
+    }
+
+    /// X25519 with the peer's public key, for keys outside the handshake (see `probe`). `None`
+    /// if `remote` is a low-order point, where anyone knows the result.
+    pub fn shared_secret(&self, remote: &PublicKey) -> Option<[u8; 32]> {
+        let shared = self.secret.diffie_hellman(remote);
+        shared.was_contributory().then(|| shared.to_bytes())
+    }
+}
+
+pub fn public_from_hex(s: &str) -> Option<PublicKey> {
+    let bytes: [u8; 32] = from_hex(s)?.try_into().ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+/// Prologue both sides mix in: the initiator's and responder's client ids, length-prefixed.
+pub fn prologue(initiator_id: &str, responder_id: &str) -> Vec<u8> {
+    let mut out = b"vpn-data".to_vec();
+    for id in [initiator_id, responder_id] {
+        out.extend_from_slice(&(id.len() as u32).to_be_bytes());
+        out.extend_from_slice(id.as_bytes());
+    }
+    out
+}
+
+/// Noise's SymmetricState: chaining key, handshake hash and the current handshake key.
+#[derive(Clone)]
+struct SymmetricState {
+    ck: [u8; 32],
+    h: [u8; 32],
+    k: Option<[u8; 32]>,
+    n: u64,
+}
+
+impl SymmetricState {
+    fn new(prologue: &[u8]) -> Self {
+        let mut state = Self {
+            ck: *PROTOCOL_NAME,
+            h: *PROTOCOL_NAME,
+            k: None,
+            n: 0,
+        };
+        state.mix_hash(prologue);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = Sha256::new().chain_update(self.h).chain_update(data).finalize().into();
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        let [ck, k] = hkdf(&self.ck, input);
+        self.ck = ck;
+        self.k = Some(k);
+        self.n = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match &self.k {
+            Some(k) => {
+                let out = encrypt(k, self.n, &self.h, plaintext);
+                self.n += 1;
+                out
+            }
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let plaintext = match &self.k {
+            Some(k) => {
+                let out = decrypt(k, self.n, &self.h, ciphertext)?;
+                self.n += 1;
+                out
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Transport keys: the initiator's sending key first.
+    fn split(&self) -> [[u8; 32]; 2] {
+        hkdf(&self.ck, &[])
+    }
+}
+
+/// Noise's HKDF with two outputs, on HMAC-SHA256.
+fn hkdf(ck: &[u8; 32], input: &[u8]) -> [[u8; 32]; 2] {
+    let hmac = |key: &[u8], parts: &[&[u8]]| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("hmac accepts any key length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    };
+    let temp = hmac(ck, &[input]);
+    let first = hmac(&temp, &[&[1]]);
+    let second = hmac(&temp, &[&first, &[2]]);
+    [first, second]
+}
+
+/// ChaChaPoly nonce: 32 zero bits, then the counter little-endian.
+fn nonce(n: u64) -> Nonce {
+    let mut nonce = [0_u8; 12];
+    nonce[4..].copy_from_slice(&n.to_le_bytes());
+    nonce.into()
+}
+
+fn encrypt(k: &[u8; 32], n: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(k))
+        .encrypt(&nonce(n), Payload { msg: plaintext, aad: ad })
+        .expect("chacha20poly1305 encrypts any message that fits in memory")
+}
+
+fn decrypt(k: &[u8; 32], n: u64, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    ChaCha20Poly1305::new(Key::from_slice(k))
+        .decrypt(&nonce(n), Payload { msg: ciphertext, aad: ad })
+        .map_err(|_| anyhow!("noise message failed to decrypt"))
+}
+
+fn dh(secret: &StaticSecret, public: &PublicKey) -> [u8; 32] {
+    secret.diffie_hellman(public).to_bytes()
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+/// The initiating side of a handshake, waiting for the response.
+pub struct Initiator {
+    state: SymmetricState,
+    e: StaticSecret,
+    s: StaticSecret,
+}
+
+impl Initiator {
+    /// Starts a handshake with the responder whose static key is `remote`; returns the first
+    /// message. A retry should start a new handshake: responders refuse a repeated timestamp.
+    pub fn start(local: &StaticKeypair, remote: &PublicKey, prologue: &[u8]) -> (Self, Vec<u8>) {
+        let e = StaticSecret::from(rand::random::<[u8; 32]>());
+        Self::start_with(local, remote, prologue, e, &now_micros().to_be_bytes())
+    }
+
+    /// [`Initiator::start`] with a given ephemeral key and payload.
+    fn start_with(
+        local: &StaticKeypair,
+        remote: &PublicKey,
+        prologue: &[u8],
+        e: StaticSecret,
+        payload: &[u8],
+    ) -> (Self, Vec<u8>) {
+        let mut state = SymmetricState::new(prologue);
+        state.mix_hash(remote.as_bytes());
+
+        let e_public = PublicKey::from(&e);
+        let mut message = e_public.as_bytes().to_vec();
+        state.mix_hash(e_public.as_bytes());
+        state.mix_key(&dh(&e, remote));
+        message.extend(state.encrypt_and_hash(local.public.as_bytes()));
+        state.mix_key(&dh(&local.secret, remote));
+        message.extend(state.encrypt_and_hash(payload));
+
+        let initiator = Self {
+            state,
+            e,
+            s: local.secret.clone(),
+        };
+        (initiator, message)
+    }
+
+    /// Reads the responder's message and returns the transport. A response that fails leaves
+    /// the initiator waiting for the right one.
+    pub fn finish(&self, response: &[u8]) -> Result<Transport> {
+        ensure!(
+            response.len() == RESPONSE_LEN,
+            "noise response is {} bytes, expected {RESPONSE_LEN}",
+            response.len()
+        );
+        self.read_response(response).map(|(transport, _)| transport)
+    }
+
+    /// Reads a response of at least `DH_LEN` bytes; returns the transport and the payload.
+    fn read_response(&self, response: &[u8]) -> Result<(Transport, Vec<u8>)> {
+        let mut state = self.state.clone();
+        let re = PublicKey::from(<[u8; 32]>::try_from(&response[..DH_LEN]).expect("length checked"));
+        state.mix_hash(re.as_bytes());
+        state.mix_key(&dh(&self.e, &re));
+        state.mix_key(&dh(&self.s, &re));
+        let payload = state.decrypt_and_hash(&response[DH_LEN..])?;
+        let [send, recv] = state.split();
+        Ok((Transport::new(send, recv), payload))
+    }
+}
+
+/// A handshake the responder accepted.
+pub struct Accepted {
+    pub transport: Transport,
+    /// Message to send back to the initiator.
+    pub response: Vec<u8>,
+    /// The initiator's clock when it started; pass it as `newer_than` next time.
+    pub timestamp: u64,
+}
+
+/// Answers a first message from the peer whose static key is `remote`. Initiations by any other
+/// key, or not newer than `newer_than`, are refused.
+pub fn respond(
+    local: &StaticKeypair,
+    remote: &PublicKey,
+    prologue: &[u8],
+    init: &[u8],
+    newer_than: u64,
+) -> Result<Accepted> {
+    ensure!(
+        init.len() == INIT_LEN,
+        "noise initiation is {} bytes, expected {INIT_LEN}",
+        init.len()
+    );
+    let (mut initiation, payload) = Initiation::read(local, prologue, init, |rs| rs == remote)?;
+    let timestamp = u64::from_be_bytes(payload.try_into().expect("ciphertext length checked"));
+    ensure!(timestamp > newer_than, "replayed or stale noise initiation");
+
+    let e = StaticSecret::from(rand::random::<[u8; 32]>());
+    let (transport, response) = initiation.respond(e, &[]);
+    Ok(Accepted {
+        transport,
+        response,
+        timestamp,
+    })
+}
+
+/// A first message the responder decrypted, before it answers.
+struct Initiation {
+    state: SymmetricState,
+    re: PublicKey,
+    rs: PublicKey,
+}
+
+impl Initiation {
+    /// Reads a first message of at least `DH_LEN + DH_LEN + AEAD_TAG_LEN` bytes and returns its
+    /// payload; refused unless `expected` accepts the initiator's static key.
+    fn read(
+        local: &StaticKeypair,
+        prologue: &[u8],
+        init: &[u8],
+        expected: impl FnOnce(&PublicKey) -> bool,
+    ) -> Result<(Self, Vec<u8>)> {
+        let mut state = SymmetricState::new(prologue);
+        state.mix_hash(local.public.as_bytes());
+
+        let re = PublicKey::from(<[u8; 32]>::try_from(&init[..DH_LEN]).expect("length checked"));
+        state.mix_hash(re.as_bytes());
+        state.mix_key(&dh(&local.secret, &re));
+        let (encrypted_static, encrypted_payload) = init[DH_LEN..].split_at(DH_LEN + AEAD_TAG_LEN);
+        let rs: [u8; 32] = state
+            .decrypt_and_hash(encrypted_static)?
+            .try_into()
+            .expect("ciphertext length checked");
+        let rs = PublicKey::from(rs);
+        if !expected(&rs) {
+            bail!("noise initiation from an unexpected static key");
+        }
+        state.mix_key(&dh(&local.secret, &rs));
+        let payload = state.decrypt_and_hash(encrypted_payload)?;
+        Ok((Self { state, re, rs }, payload))
+    }
+
+    /// The response under ephemeral key `e`, and the responder's transport.
+    fn respond(&mut self, e: StaticSecret, payload: &[u8]) -> (Transport, Vec<u8>) {
+        let state = &mut self.state;
+        let e_public = PublicKey::from(&e);
+        let mut response = e_public.as_bytes().to_vec();
+        state.mix_hash(e_public.as_bytes());
+        state.mix_key(&dh(&e, &self.re));
+        state.mix_key(&dh(&e, &self.rs));
+        response.extend(state.encrypt_and_hash(payload));
+        let [recv, send] = state.split();
+        (Transport::new(send, recv), response)
+    }
+}
+
+/// Transport keys and counters of an established channel.
+pub struct Transport {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    next: u64,
+    replay: ReplayWindow,
+}
+
+impl Transport {
+    fn new(send: [u8; 32], recv: [u8; 32]) -> Self {
+        Self {
+            send: ChaCha20Poly1305::new(Key::from_slice(&send)),
+            recv: ChaCha20Poly1305::new(Key::from_slice(&recv)),
+            next: 0,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    /// Encrypts `plaintext` under the next counter; returns the counter and ciphertext.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<(u64, Vec<u8>)> {
+        // Noise reserves the last nonce.
+        ensure!(self.next < u64::MAX, "transport counter exhausted, handshake again");
+        let counter = self.next;
+        self.next += 1;
+        let ciphertext = self
+            .send
+            .encrypt(&nonce(counter), plaintext)
+            .expect("chacha20poly1305 encrypts any message that fits in memory");
+        Ok((counter, ciphertext))
+    }
+
+    /// Decrypts a packet, refusing replayed counters and ones too old to tell.
+    pub fn open(&mut self, counter: u64, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        ensure!(self.replay.fresh(counter), "replayed or stale counter {counter}");
+        let plaintext = self
+            .recv
+            .decrypt(&nonce(counter), ciphertext)
+            .map_err(|_| anyhow!("data packet failed to decrypt"))?;
+        // Only authentic packets move the window.
+        self.replay.accept(counter);
+        Ok(plaintext)
+    }
+}
+
+/// Counters seen in the last [`REPLAY_WINDOW`] before the newest.
+#[derive(Default)]
+struct ReplayWindow {
+    newest: Option<u64>,
+    /// Bit `i` is set if counter `newest - i` was received.
+    seen: u128,
+}
+
+impl ReplayWindow {
+    fn fresh(&self, counter: u64) -> bool {
+        match self.newest {
+            None => true,
+            Some(newest) if counter > newest => true,
+            Some(newest) => {
+                let back = newest - counter;
+                back < REPLAY_WINDOW && self.seen & (1 << back) == 0
+            }
+        }
+    }
+
+    fn accept(&mut self, counter: u64) {
+        match self.newest {
+            Some(newest) if counter <= newest => self.seen |= 1 << (newest - counter),
+            Some(newest) => {
+                self.seen = self.seen.checked_shl((counter - newest) as u32).unwrap_or(0) | 1;
+                self.newest = Some(counter);
+            }
+            None => {
+                self.seen = 1;
+                self.newest = Some(counter);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Initiation, Initiator, REPLAY_WINDOW, StaticKeypair, Transport, prologue, public_from_hex, respond};
+    use crate::probe::from_hex;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    fn handshake(ids: (&str, &str)) -> anyhow::Result<(Transport, Transport)> {
+        let (a, b) = (StaticKeypair::generate(), StaticKeypair::generate());
+        let (initiator, init) = Initiator::start(&a, &b.public(), &prologue("a", "b"));
+        let accepted = respond(&b, &a.public(), &prologue(ids.0, ids.1), &init, 0)?;
+        Ok((initiator.finish(&accepted.response)?, accepted.transport))
+    }
+
+    #[test]
+    fn handshake_yields_matching_transports() {
+        let (mut a, mut b) = handshake(("a", "b")).unwrap();
+        let (counter, ciphertext) = a.seal(b"hello b").unwrap();
+        assert_eq!(b.open(counter, &ciphertext).unwrap(), b"hello b");
+        let (counter, ciphertext) = b.seal(b"hello a").unwrap();
+        assert_eq!(a.open(counter, &ciphertext).unwrap(), b"hello a");
+
+        let keys = StaticKeypair::generate();
+        assert_eq!(public_from_hex(&keys.public_hex()), Some(keys.public()));
+    }
+
+    /// `Noise_IK_25519_ChaChaPoly_SHA256` from the cacophony test vectors (as shipped with snow):
+    /// both handshake messages, then two transport messages each way.
+    #[test]
+    fn handshake_and_transport_match_the_cacophony_vector() {
+        let hex = |s: &str| from_hex(s).unwrap();
+        let key = |s: &str| <[u8; 32]>::try_from(hex(s)).unwrap();
+        let prologue = hex("4a6f686e2047616c74");
+        let secret = |s: &str| StaticKeypair::from_secret(key(s));
+        let init_static = secret("e61ef9919cde45dd5f82166404bd08e38bceb5dfdfded0a34c8df7ed542214d1");
+        let resp_static = secret("4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893");
+        assert_eq!(
+            resp_static.public(),
+            PublicKey::from(key("31e0303fd6418d2f8c0e78b91f22e8caed0fbe48656dcf4767e4834f701b8f62"))
+        );
+        let ephemeral = |s: &str| StaticSecret::from(key(s));
+        let init_ephemeral = ephemeral("893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a");
+        let resp_ephemeral = ephemeral("bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b");
+
+        let (initiator, init) = Initiator::start_with(
+            &init_static,
+            &resp_static.public(),
+            &prologue,
+            init_ephemeral,
+            &hex("4c756477696720766f6e204d69736573"),
+        );
+        assert_eq!(
+            init,
+            hex(concat!(
+                "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c7944718da798efbcd91528520204f904b9bd",
+                "6c7413dccdc214d951e15253e39987f18146e8cd0873654207148333479d4d16c289f0294b29960a72f48e0b7bba2e89",
+                "083169825e59642148d492020664ccf7"
+            ))
+        );
+        let (mut initiation, payload) =
+            Initiation::read(&resp_static, &prologue, &init, |rs| *rs == init_static.public()).unwrap();
+        assert_eq!(payload, hex("4c756477696720766f6e204d69736573"));
+
+        let (mut responder, response) = initiation.respond(resp_ephemeral, &hex("4d757272617920526f746862617264"));
+        assert_eq!(
+            response,
+            hex(concat!(
+                "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f1448088435361e70b2ed446e6c9ec387d1d6b3b84",
+                "0f194e373979d241b203c4acafccf5"
+            ))
+        );
+        let (mut initiator, payload) = initiator.read_response(&response).unwrap();
+        assert_eq!(payload, hex("4d757272617920526f746862617264"));
+
+        let transport = [
+            ("462e20412e20486179656b", "050e9f3c8fac16b68dbce8f8c4bfbf6617c897f9ada4aa29aa19c8"),
+            ("4361726c204d656e676572", "344233a6cabb7141d80f3da2fedc311d9646bbb0f505afe403a667"),
+            (
+                "4a65616e2d426170746973746520536179",
+                "62cdeeb172ad7ade7aa7d9e069da5790f12331bfa00177787a1d0810c67dc3b2b4",
+            ),
+            (
+                "457567656e2042f6686d20766f6e2042617765726b",
+                "029bead1b40992327044d409d9a1f3ad8f36c3c452775d557e18bbeb2e8dfcead32d514024",
+            ),
+        ];
+        for (i, (payload, ciphertext)) in transport.into_iter().enumerate() {
+            let (sender, receiver) = match i % 2 {
+                0 => (&mut initiator, &mut responder),
+                _ => (&mut responder, &mut initiator),
+            };
+            let (counter, sealed) = sender.seal(&hex(payload)).unwrap();
+            assert_eq!((counter, &sealed), ((i / 2) as u64, &hex(ciphertext)));
+            assert_eq!(receiver.open(counter, &sealed).unwrap(), hex(payload));
+        }
+    }
+
+    #[test]
+    fn handshake_is_bound_to_keys_and_client_ids() {
+        assert!(handshake(("a", "c")).is_err(), "a responder with another id refuses");
+
+        let (a, b, mallory) = (StaticKeypair::generate(), StaticKeypair::generate(), StaticKeypair::generate());
+        let (_, init) = Initiator::start(&mallory, &b.public(), &prologue("a", "b"));
+        assert!(respond(&b, &a.public(), &prologue("a", "b"), &init, 0).is_err(), "only a's key is accepted");
+
+        let (_, init) = Initiator::start(&a, &b.public(), &prologue("a", "b"));
+        let accepted = respond(&b, &a.public(), &prologue("a", "b"), &init, 0).unwrap();
+        let replayed = respond(&b, &a.public(), &prologue("a", "b"), &init, accepted.timestamp);
+        assert!(replayed.is_err(), "a recorded initiation cannot be replayed");
+    }
+
+    #[test]
+    fn transport_refuses_replays_and_forgeries_but_tolerates_reordering() {
+        let (mut a, mut b) = handshake(("a", "b")).unwrap();
+        let packets: Vec<_> = (0..4).map(|i| a.seal(&[i]).unwrap()).collect();
+        let open = |b: &mut Transport, i: usize| b.open(packets[i].0, &packets[i].1);
+        assert!(open(&mut b, 2).is_ok());
+        assert!(open(&mut b, 0).is_ok(), "late packets inside the window are accepted");
+        assert!(open(&mut b, 2).is_err(), "replays are refused");
+        let mut forged = packets[1].1.clone();
+        forged[0] ^= 1;
+        assert!(b.open(packets[1].0, &forged).is_err());
+        assert!(open(&mut b, 1).is_ok(), "a forgery does not burn the counter");
+
+        for _ in 0..REPLAY_WINDOW {
+            let (counter, ciphertext) = a.seal(b"x").unwrap();
+            b.open(counter, &ciphertext).unwrap();
+        }
+        assert!(open(&mut b, 3).is_err(), "too old to tell");
+    }
+}
@@ -0,0 +1,315 @@
+//! Overlay addressing for TUN mode: which peer owns which overlay IP, and where a packet goes.
+//!
+//! Every client claims an address in the room's overlay network (IPv4 or IPv6) and publishes it
+//! as `overlay_ip` in its presence meta. Without a requested address, a client tries the network's
+//! host addresses in an order derived from its client id, skipping those already claimed. Two
+//! clients may still claim the same address concurrently; the smaller client id keeps it, and the
+//! other claims the next free one, so every client reaches the same outcome from presence alone.
+//!
+//! A packet read from the TUN device is routed by its destination address; a packet a peer sends
+//! us is only delivered if its source is that peer's overlay address, so a peer cannot speak for
+//! another.
+
+use anyhow::{Context, Result, bail, ensure};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// An interface address with its prefix length, written `10.99.0.1/24`. With the host bits
+/// cleared it names the overlay network itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OverlayAddress {
+    pub ip: IpAddr,
+    pub prefix: u8,
+}
+
+impl OverlayAddress {
+    /// `ip` in this address's network, with the same prefix.
+    pub fn with_ip(self, ip: IpAddr) -> Self {
+        Self { ip, ..self }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        ip.is_ipv4() == self.ip.is_ipv4() && self.with_ip(ip).network_bits() == self.network_bits()
+    }
+
+    /// Host addresses of the network in the order `client_id` tries them: from a starting point
+    /// hashed from the id, upwards and wrapping. IPv4 skips the network and broadcast addresses
+    /// (unless the prefix leaves no others), IPv6 the subnet-router anycast address.
+    pub fn candidates(&self, client_id: &str) -> impl Iterator<Item = IpAddr> + use<> {
+        let host_bits = u32::from(self.max_prefix() - self.prefix);
+        // A whole IPv6 /0 has one address more than fits; nobody will miss it.
+        let size = 1_u128.checked_shl(host_bits).unwrap_or(u128::MAX);
+        // Usable hosts, as offsets from the network address.
+        let (first, count) = match self.ip {
+            IpAddr::V4(_) if size > 2 => (1, size - 2),
+            IpAddr::V6(_) if size > 1 => (1, size - 1),
+            _ => (0, size),
+        };
+        let hash = Sha256::digest(client_id.as_bytes());
+        let start = u128::from_be_bytes(hash[..16].try_into().expect("sha256 is 32 bytes")) % count;
+        let (network, v4) = (self.network_bits(), self.ip.is_ipv4());
+        (0..count).map(move |i| {
+            let offset = if i < count - start { start + i } else { i - (count - start) };
+            let host = network | (first + offset);
+            if v4 {
+                IpAddr::V4(Ipv4Addr::from(host as u32))
+            } else {
+                IpAddr::V6(Ipv6Addr::from(host))
+            }
+        })
+    }
+
+    fn max_prefix(&self) -> u8 {
+        if self.ip.is_ipv4() { 32 } else { 128 }
+    }
+
+    fn network_bits(&self) -> u128 {
+        let host_bits = u32::from(self.max_prefix() - self.prefix);
+        let bits = match self.ip {
+            IpAddr::V4(ip) => u128::from(u32::from(ip)),
+            IpAddr::V6(ip) => u128::from(ip),
+        };
+        bits & !(1_u128.checked_shl(host_bits).unwrap_or(0).wrapping_sub(1))
+    }
+}
+
+impl FromStr for OverlayAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (ip, prefix) = s.split_once('/').context("overlay address needs a prefix length")?;
+        let ip: IpAddr = ip.parse().with_context(|| format!("invalid overlay ip {ip}"))?;
+        let prefix: u8 = prefix.parse().with_context(|| format!("invalid prefix length {prefix}"))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        ensure!(prefix <= max, "prefix length {prefix} is longer than {max}");
+        Ok(Self { ip, prefix })
+    }
+}
+
+impl fmt::Display for OverlayAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+/// Source and destination of an IPv4 or IPv6 packet; fails on anything shorter than its header
+/// or whose length field disagrees with the datagram.
+pub fn addresses(packet: &[u8]) -> Result<(IpAddr, IpAddr)> {
+    let Some(first) = packet.first() else {
+        bail!("empty packet");
+    };
+    match first >> 4 {
+        4 => {
+            ensure!(packet.len() >= IPV4_HEADER_LEN, "truncated ipv4 header");
+            let total = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+            ensure!(total == packet.len(), "ipv4 total length {total}, packet is {}", packet.len());
+            let source: [u8; 4] = packet[12..16].try_into().expect("length checked");
+            let destination: [u8; 4] = packet[16..20].try_into().expect("length checked");
+            Ok((source.into(), destination.into()))
+        }
+        6 => {
+            ensure!(packet.len() >= IPV6_HEADER_LEN, "truncated ipv6 header");
+            let payload = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+            ensure!(
+                IPV6_HEADER_LEN + payload == packet.len(),
+                "ipv6 payload length {payload}, packet is {}",
+                packet.len()
+            );
+            let source: [u8; 16] = packet[8..24].try_into().expect("length checked");
+            let destination: [u8; 16] = packet[24..40].try_into().expect("length checked");
+            Ok((source.into(), destination.into()))
+        }
+        version => bail!("not an ip packet (version {version})"),
+    }
+}
+
+/// Who keeps each claimed address: the smallest client id among its claimants.
+fn owners<'a>(claims: impl IntoIterator<Item = (&'a str, IpAddr)>) -> BTreeMap<IpAddr, &'a str> {
+    let mut owners = BTreeMap::new();
+    for (peer, ip) in claims {
+        let owner = owners.entry(ip).or_insert(peer);
+        if peer < *owner {
+            *owner = peer;
+        }
+    }
+    owners
+}
+
+/// Our own claim in the overlay network.
+#[derive(Clone, Debug)]
+pub struct OverlayClaim {
+    network: OverlayAddress,
+    client_id: String,
+    ip: IpAddr,
+}
+
+impl OverlayClaim {
+    /// Claims `requested`, which must lie in `network`, or else the first of our candidates.
+    pub fn new(network: OverlayAddress, client_id: &str, requested: Option<IpAddr>) -> Result<Self> {
+        let ip = match requested {
+            Some(ip) => {
+                ensure!(network.contains(ip), "overlay address {ip} is outside {network}");
+                ip
+            }
+            None => network
+                .candidates(client_id)
+                .next()
+                .with_context(|| format!("overlay network {network} has no host addresses"))?,
+        };
+        Ok(Self {
+            network,
+            client_id: client_id.to_string(),
+            ip,
+        })
+    }
+
+    /// The claimed address, with the network's prefix.
+    pub fn address(&self) -> OverlayAddress {
+        self.network.with_ip(self.ip)
+    }
+
+    /// Checks our claim against the peers'. If a smaller client id claims our address too, moves
+    /// to the first candidate nobody claims and returns the new address.
+    pub fn resolve<'a>(&mut self, peers: impl IntoIterator<Item = (&'a str, IpAddr)>) -> Result<Option<OverlayAddress>> {
+        let claims: Vec<(&str, IpAddr)> = peers.into_iter().filter(|(peer, _)| *peer != self.client_id).collect();
+        if !claims.iter().any(|&(peer, ip)| ip == self.ip && peer < self.client_id.as_str()) {
+            return Ok(None);
+        }
+        self.ip = self
+            .network
+            .candidates(&self.client_id)
+            .find(|candidate| !claims.iter().any(|(_, ip)| ip == candidate))
+            .with_context(|| format!("overlay network {} is full", self.network))?;
+        Ok(Some(self.address()))
+    }
+}
+
+/// The peer-id to overlay-IP table routing uses, both ways. Built from the peers' claims; a
+/// contested address routes to its owner only, the other claimants get no route until they move.
+#[derive(Default)]
+pub struct OverlayRoutes {
+    by_ip: HashMap<IpAddr, String>,
+    by_peer: BTreeMap<String, IpAddr>,
+}
+
+impl OverlayRoutes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_claims<'a>(claims: impl IntoIterator<Item = (&'a str, IpAddr)>) -> Self {
+        let mut routes = Self::new();
+        for (ip, peer) in owners(claims) {
+            routes.by_ip.insert(ip, peer.to_string());
+            routes.by_peer.insert(peer.to_string(), ip);
+        }
+        routes
+    }
+
+    pub fn peer(&self, ip: IpAddr) -> Option<&str> {
+        self.by_ip.get(&ip).map(String::as_str)
+    }
+
+    pub fn address(&self, peer: &str) -> Option<IpAddr> {
+        self.by_peer.get(peer).copied()
+    }
+
+    /// Peers and their addresses, ordered by peer id.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, IpAddr)> {
+        self.by_peer.iter().map(|(peer, ip)| (peer.as_str(), *ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OverlayAddress, OverlayClaim, OverlayRoutes, addresses};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn ipv4(source: [u8; 4], destination: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0];
+        packet.extend(source);
+        packet.extend(destination);
+        packet.extend([0; 4]);
+        packet
+    }
+
+    #[test]
+    fn packets_are_routed_by_destination() {
+        let packet = ipv4([10, 99, 0, 1], [10, 99, 0, 2]);
+        let (source, destination) = addresses(&packet).unwrap();
+        assert_eq!(source, IpAddr::V4(Ipv4Addr::new(10, 99, 0, 1)));
+        assert_eq!(destination, IpAddr::V4(Ipv4Addr::new(10, 99, 0, 2)));
+        assert!(addresses(&packet[..23]).is_err(), "length field must match");
+        assert!(addresses(b"hello from b").is_err());
+
+        let mut v6 = vec![0x60, 0, 0, 0, 0, 2, 17, 64];
+        v6.extend(Ipv6Addr::LOCALHOST.octets());
+        v6.extend(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        v6.extend([0, 0]);
+        let (_, destination) = addresses(&v6).unwrap();
+        assert_eq!(destination, "fd00::2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn contested_addresses_route_to_the_smaller_client_id() {
+        let (first, second): (IpAddr, IpAddr) = ("10.99.0.2".parse().unwrap(), "10.99.0.3".parse().unwrap());
+        let routes = OverlayRoutes::from_claims([("c", first), ("b", second), ("a", first)]);
+        assert_eq!(routes.peer(first), Some("a"));
+        assert_eq!(routes.address("c"), None, "c has to move first");
+        assert_eq!(routes.iter().collect::<Vec<_>>(), [("a", first), ("b", second)]);
+
+        let address: OverlayAddress = "10.99.0.1/24".parse().unwrap();
+        assert_eq!(address.to_string(), "10.99.0.1/24");
+        assert!("10.99.0.1/33".parse::<OverlayAddress>().is_err());
+        assert!("10.99.0.1".parse::<OverlayAddress>().is_err());
+    }
+
+    #[test]
+    fn candidates_are_host_addresses_in_a_per_client_order() {
+        let network: OverlayAddress = "10.99.0.0/30".parse().unwrap();
+        let mut hosts: Vec<IpAddr> = network.candidates("a").collect();
+        assert_eq!(hosts.len(), 2, "network and broadcast addresses are skipped");
+        hosts.sort();
+        assert_eq!(hosts, ["10.99.0.1".parse::<IpAddr>().unwrap(), "10.99.0.2".parse().unwrap()]);
+
+        let network: OverlayAddress = "10.99.0.0/16".parse().unwrap();
+        assert_ne!(network.candidates("a").next(), network.candidates("b").next());
+        assert_eq!(network.candidates("a").next(), network.candidates("a").next());
+
+        let network: OverlayAddress = "fd99::/64".parse().unwrap();
+        let ip = network.candidates("a").next().unwrap();
+        assert!(network.contains(ip) && ip != "fd99::".parse::<IpAddr>().unwrap());
+        assert!(!network.contains("10.99.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn conflicting_claims_settle_on_distinct_addresses() {
+        let network: OverlayAddress = "10.99.0.0/24".parse().unwrap();
+        let wanted = Some("10.99.0.7".parse().unwrap());
+        let mut a = OverlayClaim::new(network, "a", wanted).unwrap();
+        let mut b = OverlayClaim::new(network, "b", wanted).unwrap();
+        assert!(OverlayClaim::new(network, "c", Some("10.98.0.1".parse().unwrap())).is_err());
+
+        // Both see the same presence: a keeps the address, b moves, and then nothing changes.
+        let claims = [("a", a.address().ip), ("b", b.address().ip)];
+        assert_eq!(a.resolve(claims).unwrap(), None);
+        let moved = b.resolve(claims).unwrap().expect("b loses to a");
+        assert_eq!(moved.prefix, 24);
+        assert_ne!(moved.ip, a.address().ip);
+        let claims = [("a", a.address().ip), ("b", b.address().ip)];
+        assert_eq!(a.resolve(claims).unwrap(), None);
+        assert_eq!(b.resolve(claims).unwrap(), None);
+
+        // Nowhere left to go.
+        let tiny: OverlayAddress = "10.99.0.0/30".parse().unwrap();
+        let mut b = OverlayClaim::new(tiny, "b", Some("10.99.0.1".parse().unwrap())).unwrap();
+        let claims = [("a", "10.99.0.1".parse().unwrap()), ("c", "10.99.0.2".parse().unwrap())];
+        assert!(b.resolve(claims).is_err());
+    }
+}
@@ -0,0 +1,918 @@
+//! Minimal Phoenix Channels client (V2 JSON serializer).
+//!
+//! A [`Socket`] owns the websocket on a background task. It assigns message refs, answers
+//! `push` calls with the matching `phx_reply`, sends heartbeats and routes incoming events to
+//! the [`Channel`] that joined the topic. When the connection drops (or a heartbeat goes
+//! unanswered) the socket reconnects with backoff and rejoins every joined topic; a rejoin the
+//! server rejects is retried with the same backoff.
+
+use anyhow::{Context, Result, anyhow, bail};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, MissedTickBehavior, interval_at, sleep, sleep_until, timeout};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(25);
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Exponential reconnect delay: `initial`, doubling per failed attempt, capped at `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(200),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    pub heartbeat: Duration,
+    /// `None` disables reconnects: the first disconnect ends every channel's event stream.
+    pub reconnect: Option<Backoff>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            heartbeat: DEFAULT_HEARTBEAT_INTERVAL,
+            reconnect: Some(Backoff::default()),
+        }
+    }
+}
+
+/// One Phoenix V2 frame: `[join_ref, ref, topic, event, payload]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub join_ref: Option<String>,
+    pub msg_ref: Option<String>,
+    pub topic: String,
+    pub event: String,
+    pub payload: Value,
+}
+
+impl Frame {
+    pub fn encode(&self) -> String {
+        json!([self.join_ref, self.msg_ref, self.topic, self.event, self.payload]).to_string()
+    }
+
+    pub fn decode(txt: &str) -> Option<Self> {
+        let v: Value = serde_json::from_str(txt).ok()?;
+        let arr = v.as_array()?;
+        if arr.len() < 5 {
+            return None;
+        }
+        Some(Self {
+            join_ref: arr[0].as_str().map(str::to_string),
+            msg_ref: arr[1].as_str().map(str::to_string),
+            topic: arr[2].as_str()?.to_string(),
+            event: arr[3].as_str()?.to_string(),
+            payload: arr[4].clone(),
+        })
+    }
+}
+
+/// Server reply to a push (`phx_reply` payload).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply {
+    pub status: String,
+    pub response: Value,
+}
+
+impl Reply {
+    fn from_payload(payload: &Value) -> Self {
+        Self {
+            status: payload
+                .get("status")
+                .and_then(|s| s.as_str())
+                .unwrap_or("error")
+                .to_string(),
+            response: payload.get("response").cloned().unwrap_or(Value::Null),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+/// A server-initiated message on a joined channel (broadcasts, pushes, `phx_error`, `phx_close`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub event: String,
+    pub payload: Value,
+}
+
+/// What a [`Channel`] observes: server messages plus connection lifecycle changes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelEvent {
+    Message(Event),
+    /// The websocket dropped. Pending pushes fail; the channel is rejoined after reconnecting.
+    Disconnected,
+    /// The channel was joined again on a fresh connection. Server-side state tied to the old
+    /// connection (presence, registrations) has to be treated as stale.
+    Rejoined(Reply),
+    /// The server rejected a rejoin. The channel stays offline (pushes fail) until a retry,
+    /// scheduled with the reconnect backoff, ends in [`ChannelEvent::Rejoined`].
+    RejoinFailed(Reply),
+}
+
+enum Command {
+    Join {
+        topic: String,
+        params: Value,
+        events: mpsc::UnboundedSender<ChannelEvent>,
+        reply: oneshot::Sender<Reply>,
+    },
+    Push {
+        topic: String,
+        event: String,
+        payload: Value,
+        reply: oneshot::Sender<Reply>,
+    },
+    Leave {
+        topic: String,
+        reply: oneshot::Sender<Reply>,
+    },
+    /// A caller stopped waiting for its reply.
+    Abandoned,
+}
+
+/// Handle to a websocket connection; dropping every handle closes the socket.
+#[derive(Clone)]
+pub struct Socket {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Socket {
+    /// Connects to a Phoenix websocket endpoint such as `ws://host:4000/socket/websocket?vsn=2.0.0`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        Self::connect_with(url, SocketOptions::default()).await
+    }
+
+    pub async fn connect_with(url: &str, options: SocketOptions) -> Result<Self> {
+        let (ws, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .context("failed to connect websocket")?;
+        let (commands, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_socket(url.to_string(), ws, rx, options));
+        Ok(Self { commands })
+    }
+
+    /// Joins `topic` and waits for the server's reply.
+    ///
+    /// Fails if the join is rejected or no reply arrives within `wait`; a failed join is dropped
+    /// (and left, in case the server accepts it late) rather than rejoined. Once joined, the topic
+    /// is rejoined with the same `params` after every reconnect.
+    pub async fn join(&self, topic: &str, params: Value, wait: Duration) -> Result<(Channel, Reply)> {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(Command::Join {
+            topic: topic.to_string(),
+            params,
+            events: events_tx,
+            reply: reply_tx,
+        })?;
+        let reply = await_reply(self, reply_rx, wait, topic, "phx_join").await?;
+        if !reply.is_ok() {
+            bail!("join {topic} rejected: {}", reply.response);
+        }
+        let channel = Channel {
+            topic: topic.to_string(),
+            socket: self.clone(),
+            events,
+        };
+        Ok((channel, reply))
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| anyhow!("phoenix socket is closed"))
+    }
+}
+
+/// A joined topic. Events for the topic arrive through [`Channel::recv`].
+pub struct Channel {
+    topic: String,
+    socket: Socket,
+    events: mpsc::UnboundedReceiver<ChannelEvent>,
+}
+
+impl Channel {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Pushes an event and waits for its `phx_reply`.
+    pub async fn push(&self, event: &str, payload: Value, wait: Duration) -> Result<Reply> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.socket.send(Command::Push {
+            topic: self.topic.clone(),
+            event: event.to_string(),
+            payload,
+            reply: reply_tx,
+        })?;
+        await_reply(&self.socket, reply_rx, wait, &self.topic, event).await
+    }
+
+    /// Next event on this channel; `None` once the socket has shut down for good.
+    pub async fn recv(&mut self) -> Option<ChannelEvent> {
+        self.events.recv().await
+    }
+
+    pub async fn leave(self, wait: Duration) -> Result<Reply> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.socket.send(Command::Leave {
+            topic: self.topic.clone(),
+            reply: reply_tx,
+        })?;
+        await_reply(&self.socket, reply_rx, wait, &self.topic, "phx_leave").await
+    }
+}
+
+async fn await_reply(
+    socket: &Socket,
+    rx: oneshot::Receiver<Reply>,
+    wait: Duration,
+    topic: &str,
+    event: &str,
+) -> Result<Reply> {
+    match timeout(wait, rx).await {
+        Ok(Ok(reply)) => Ok(reply),
+        Ok(Err(_)) => bail!("phoenix socket closed before {event} on {topic} was answered"),
+        Err(_) => {
+            // The receiver is gone with the timeout, which is how the socket tells what to drop.
+            let _ = socket.send(Command::Abandoned);
+            bail!("timed out waiting for reply to {event} on {topic}")
+        }
+    }
+}
+
+struct JoinedTopic {
+    /// `None` while the socket is offline and the topic waits to be (re)joined.
+    join_ref: Option<String>,
+    params: Value,
+    events: mpsc::UnboundedSender<ChannelEvent>,
+    /// Caller of a join issued while offline; answered by the rejoin reply.
+    pending_join: Option<oneshot::Sender<Reply>>,
+    /// Rejected rejoins in a row, and when to try again after the last one.
+    rejoin_attempts: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Who is waiting for a given `phx_reply`.
+enum Pending {
+    Caller(oneshot::Sender<Reply>),
+    /// A first join of the topic; the topic is dropped again if the join is rejected.
+    Join(String, oneshot::Sender<Reply>),
+    Rejoin(String),
+    Heartbeat,
+}
+
+/// Bookkeeping for one socket: ref counter, replies in flight and joined topics.
+#[derive(Default)]
+struct SocketState {
+    next_ref: u64,
+    pending: HashMap<String, Pending>,
+    topics: HashMap<String, JoinedTopic>,
+    /// Delay between retries of a rejected rejoin.
+    rejoin_backoff: Backoff,
+}
+
+impl SocketState {
+    fn make_ref(&mut self) -> String {
+        self.next_ref += 1;
+        self.next_ref.to_string()
+    }
+
+    /// Turns a command into the frame to send, registering whatever reply/route it needs.
+    fn frame_for(&mut self, command: Command) -> Option<Frame> {
+        match command {
+            Command::Join {
+                topic,
+                params,
+                events,
+                reply,
+            } => {
+                let join_ref = self.make_ref();
+                self.pending.insert(join_ref.clone(), Pending::Join(topic.clone(), reply));
+                self.topics.insert(
+                    topic.clone(),
+                    JoinedTopic {
+                        join_ref: Some(join_ref.clone()),
+                        params: params.clone(),
+                        events,
+                        pending_join: None,
+                        rejoin_attempts: 0,
+                        retry_at: None,
+                    },
+                );
+                Some(join_frame(topic, join_ref, params))
+            }
+            Command::Push {
+                topic,
+                event,
+                payload,
+                reply,
+            } => {
+                let join_ref = self.topics.get(&topic)?.join_ref.clone()?;
+                let msg_ref = self.make_ref();
+                self.pending.insert(msg_ref.clone(), Pending::Caller(reply));
+                Some(Frame {
+                    join_ref: Some(join_ref),
+                    msg_ref: Some(msg_ref),
+                    topic,
+                    event,
+                    payload,
+                })
+            }
+            Command::Leave { topic, reply } => {
+                let join_ref = self.topics.remove(&topic)?.join_ref?;
+                let msg_ref = self.make_ref();
+                self.pending.insert(msg_ref.clone(), Pending::Caller(reply));
+                Some(leave_frame(topic, join_ref, msg_ref))
+            }
+            // See `abandoned`, which may leave several topics.
+            Command::Abandoned => None,
+        }
+    }
+
+    /// Drops what callers stopped waiting for: replies to pushes and leaves, and joins, whose
+    /// topics go so that no reconnect rejoins them. Returns `phx_leave` frames for the joins
+    /// already sent, in case the server accepts them late.
+    fn abandoned(&mut self) -> Vec<Frame> {
+        let gone: Vec<String> = self
+            .topics
+            .iter()
+            .filter(|(_, joined)| {
+                joined.pending_join.as_ref().is_some_and(|tx| tx.is_closed())
+                    || joined.join_ref.as_ref().is_some_and(|join_ref| {
+                        matches!(self.pending.get(join_ref), Some(Pending::Join(_, tx)) if tx.is_closed())
+                    })
+            })
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        let mut leaves = Vec::new();
+        for topic in gone {
+            let Some(join_ref) = self.topics.remove(&topic).and_then(|joined| joined.join_ref) else {
+                continue;
+            };
+            self.pending.remove(&join_ref);
+            let msg_ref = self.make_ref();
+            leaves.push(leave_frame(topic, join_ref, msg_ref));
+        }
+        self.pending
+            .retain(|_, pending| !matches!(pending, Pending::Caller(tx) | Pending::Join(_, tx) if tx.is_closed()));
+        leaves
+    }
+
+    /// Handles a command while disconnected: joins wait for the reconnect, leaves drop the topic
+    /// and everything else fails.
+    fn queue_offline(&mut self, command: Command) {
+        match command {
+            Command::Join {
+                topic,
+                params,
+                events,
+                reply,
+            } => {
+                self.topics.insert(
+                    topic,
+                    JoinedTopic {
+                        join_ref: None,
+                        params,
+                        events,
+                        pending_join: Some(reply),
+                        rejoin_attempts: 0,
+                        retry_at: None,
+                    },
+                );
+            }
+            Command::Leave { topic, .. } => {
+                self.topics.remove(&topic);
+            }
+            // Nothing was sent for the topics it drops.
+            Command::Abandoned => {
+                self.abandoned();
+            }
+            // Dropping a push reply sender fails the caller with "socket closed".
+            Command::Push { .. } => {}
+        }
+    }
+
+    /// Returns `None` (and tears the connection down) if the previous heartbeat went unanswered.
+    fn heartbeat(&mut self) -> Option<Frame> {
+        if self.pending.values().any(|p| matches!(p, Pending::Heartbeat)) {
+            return None;
+        }
+        let msg_ref = self.make_ref();
+        self.pending.insert(msg_ref.clone(), Pending::Heartbeat);
+        Some(Frame {
+            join_ref: None,
+            msg_ref: Some(msg_ref),
+            topic: "phoenix".to_string(),
+            event: "heartbeat".to_string(),
+            payload: json!({}),
+        })
+    }
+
+    fn route(&mut self, frame: Frame) {
+        if frame.event == "phx_reply" {
+            let Some(pending) = frame.msg_ref.as_ref().and_then(|r| self.pending.remove(r)) else {
+                return;
+            };
+            let reply = Reply::from_payload(&frame.payload);
+            match pending {
+                Pending::Caller(tx) => {
+                    let _ = tx.send(reply);
+                }
+                Pending::Join(topic, tx) => {
+                    // A rejected join must not be rejoined after the next reconnect.
+                    let current = self.topics.get(&topic).and_then(|joined| joined.join_ref.as_ref());
+                    if !reply.is_ok() && current == frame.msg_ref.as_ref() {
+                        self.topics.remove(&topic);
+                    }
+                    let _ = tx.send(reply);
+                }
+                Pending::Rejoin(topic) => self.rejoined(&topic, reply),
+                Pending::Heartbeat => {}
+            }
+            return;
+        }
+
+        let Some(joined) = self.topics.get(&frame.topic) else {
+            return;
+        };
+        // Broadcasts carry no join_ref; anything else must belong to the current join.
+        if frame
+            .join_ref
+            .as_ref()
+            .is_some_and(|r| Some(r) != joined.join_ref.as_ref())
+        {
+            return;
+        }
+        let _ = joined.events.send(ChannelEvent::Message(Event {
+            event: frame.event,
+            payload: frame.payload,
+        }));
+    }
+
+    /// Settles a rejoin. A rejected one leaves the topic offline and schedules a retry; for a
+    /// join issued while offline the caller gets the rejection instead and the topic is dropped.
+    fn rejoined(&mut self, topic: &str, reply: Reply) {
+        let Some(joined) = self.topics.get_mut(topic) else {
+            return;
+        };
+        if let Some(tx) = joined.pending_join.take() {
+            if !reply.is_ok() {
+                self.topics.remove(topic);
+            }
+            let _ = tx.send(reply);
+            return;
+        }
+        if reply.is_ok() {
+            joined.rejoin_attempts = 0;
+            let _ = joined.events.send(ChannelEvent::Rejoined(reply));
+            return;
+        }
+        joined.join_ref = None;
+        joined.retry_at = Some(Instant::now() + self.rejoin_backoff.delay(joined.rejoin_attempts));
+        joined.rejoin_attempts = joined.rejoin_attempts.saturating_add(1);
+        let _ = joined.events.send(ChannelEvent::RejoinFailed(reply));
+    }
+
+    /// When the earliest scheduled rejoin retry is due.
+    fn next_retry(&self) -> Option<Instant> {
+        self.topics.values().filter_map(|joined| joined.retry_at).min()
+    }
+
+    /// The `phx_join` frame for one topic whose retry is due at `now`, if any.
+    fn due_rejoin(&mut self, now: Instant) -> Option<Frame> {
+        let topic = self
+            .topics
+            .iter()
+            .find(|(_, joined)| joined.retry_at.is_some_and(|at| at <= now))
+            .map(|(topic, _)| topic.clone())?;
+        Some(self.rejoin_frame(topic))
+    }
+
+    /// Fails in-flight pushes and tells every channel the connection is gone.
+    fn disconnected(&mut self) {
+        self.pending.clear();
+        for joined in self.topics.values_mut() {
+            if joined.join_ref.take().is_some() {
+                let _ = joined.events.send(ChannelEvent::Disconnected);
+            }
+        }
+    }
+
+    /// `phx_join` frames for every topic, each under a fresh join ref.
+    fn rejoin_frames(&mut self) -> Vec<Frame> {
+        let topics: Vec<String> = self.topics.keys().cloned().collect();
+        topics.into_iter().map(|topic| self.rejoin_frame(topic)).collect()
+    }
+
+    fn rejoin_frame(&mut self, topic: String) -> Frame {
+        let join_ref = self.make_ref();
+        self.pending.insert(join_ref.clone(), Pending::Rejoin(topic.clone()));
+        let joined = self.topics.get_mut(&topic).expect("rejoined topic is joined");
+        joined.join_ref = Some(join_ref.clone());
+        joined.retry_at = None;
+        join_frame(topic, join_ref, joined.params.clone())
+    }
+}
+
+fn join_frame(topic: String, join_ref: String, params: Value) -> Frame {
+    Frame {
+        join_ref: Some(join_ref.clone()),
+        msg_ref: Some(join_ref),
+        topic,
+        event: "phx_join".to_string(),
+        payload: params,
+    }
+}
+
+fn leave_frame(topic: String, join_ref: String, msg_ref: String) -> Frame {
+    Frame {
+        join_ref: Some(join_ref),
+        msg_ref: Some(msg_ref),
+        topic,
+        event: "phx_leave".to_string(),
+        payload: json!({}),
+    }
+}
+
+async fn run_socket(
+    url: String,
+    mut ws: WsStream,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    options: SocketOptions,
+) {
+    let mut state = SocketState {
+        rejoin_backoff: options.reconnect.unwrap_or_default(),
+        ..SocketState::default()
+    };
+    loop {
+        let open = drive_connection(&mut ws, &mut commands, &mut state, options.heartbeat).await;
+        let _ = ws.close(None).await;
+        if !open {
+            return;
+        }
+
+        state.disconnected();
+        let Some(backoff) = options.reconnect else {
+            // Dropping `state` ends every channel's event stream.
+            return;
+        };
+        let Some(reconnected) = reconnect(&url, backoff, &mut commands, &mut state).await else {
+            return;
+        };
+        ws = reconnected;
+
+        for frame in state.rejoin_frames() {
+            // A failed send surfaces as a dropped connection in the next drive_connection.
+            let _ = ws.send(Message::Text(frame.encode())).await;
+        }
+    }
+}
+
+/// Pumps one connection. Returns `false` when every handle was dropped, `true` when the
+/// connection was lost and should be re-established.
+async fn drive_connection(
+    ws: &mut WsStream,
+    commands: &mut mpsc::UnboundedReceiver<Command>,
+    state: &mut SocketState,
+    heartbeat: Duration,
+) -> bool {
+    let mut heartbeat_tick = interval_at(Instant::now() + heartbeat, heartbeat);
+    heartbeat_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let frames: Vec<Frame> = tokio::select! {
+            command = commands.recv() => {
+                match command {
+                    None => return false,
+                    Some(Command::Abandoned) => state.abandoned(),
+                    Some(command) => state.frame_for(command).into_iter().collect(),
+                }
+            }
+            _ = heartbeat_tick.tick() => {
+                let Some(frame) = state.heartbeat() else { return true };
+                vec![frame]
+            }
+            _ = sleep_until(state.next_retry().unwrap_or_else(Instant::now)), if state.next_retry().is_some() => {
+                state.due_rejoin(Instant::now()).into_iter().collect()
+            }
+            msg = ws.next() => {
+                match msg {
+                    Some(Ok(Message::Text(txt))) => {
+                        if let Some(frame) = Frame::decode(&txt) {
+                            state.route(frame);
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return true,
+                    Some(Ok(_)) => {}
+                }
+                Vec::new()
+            }
+        };
+
+        for frame in frames {
+            if ws.send(Message::Text(frame.encode())).await.is_err() {
+                return true;
+            }
+        }
+    }
+}
+
+async fn reconnect(
+    url: &str,
+    backoff: Backoff,
+    commands: &mut mpsc::UnboundedReceiver<Command>,
+    state: &mut SocketState,
+) -> Option<WsStream> {
+    let mut attempt = 0_u32;
+    loop {
+        let delay = sleep(backoff.delay(attempt));
+        tokio::pin!(delay);
+        loop {
+            tokio::select! {
+                _ = &mut delay => break,
+                command = commands.recv() => state.queue_offline(command?),
+            }
+        }
+        if let Ok((ws, _)) = tokio_tungstenite::connect_async(url).await {
+            return Some(ws);
+        }
+        attempt = attempt.saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, ChannelEvent, Frame, SocketState};
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot};
+
+    #[test]
+    fn decodes_and_encodes_v2_frames() {
+        let txt = r#"["3","7","rendezvous:demo","phx_reply",{"status":"ok","response":{}}]"#;
+        let frame = Frame::decode(txt).expect("frame should decode");
+        assert_eq!(frame.join_ref.as_deref(), Some("3"));
+        assert_eq!(frame.msg_ref.as_deref(), Some("7"));
+        assert_eq!(frame.topic, "rendezvous:demo");
+        assert_eq!(frame.event, "phx_reply");
+        assert_eq!(Frame::decode(&frame.encode()), Some(frame));
+    }
+
+    #[test]
+    fn broadcast_frames_have_null_refs() {
+        let frame = Frame::decode(r#"[null,null,"rendezvous:demo","udp_seen",{}]"#).expect("frame should decode");
+        assert_eq!(frame.join_ref, None);
+        assert_eq!(frame.msg_ref, None);
+        assert!(Frame::decode(r#"["1","1","t"]"#).is_none());
+    }
+
+    #[test]
+    fn routes_replies_by_ref_and_events_by_join_ref() {
+        let mut state = SocketState::default();
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let (reply_tx, mut reply_rx) = oneshot::channel();
+        let join = state
+            .frame_for(super::Command::Join {
+                topic: "rendezvous:demo".to_string(),
+                params: json!({}),
+                events: events_tx,
+                reply: reply_tx,
+            })
+            .expect("join should produce a frame");
+        assert_eq!(join.join_ref, join.msg_ref);
+
+        let join_ref = join.join_ref.clone();
+        state.route(Frame {
+            event: "phx_reply".to_string(),
+            payload: json!({"status": "ok", "response": {}}),
+            ..join
+        });
+        assert!(reply_rx.try_recv().expect("reply should be delivered").is_ok());
+
+        state.route(Frame {
+            join_ref: Some("stale".to_string()),
+            msg_ref: None,
+            topic: "rendezvous:demo".to_string(),
+            event: "presence_state".to_string(),
+            payload: json!({}),
+        });
+        assert!(events.try_recv().is_err(), "events for an old join must be dropped");
+
+        state.route(Frame {
+            join_ref,
+            msg_ref: None,
+            topic: "rendezvous:demo".to_string(),
+            event: "presence_state".to_string(),
+            payload: json!({}),
+        });
+        let Ok(ChannelEvent::Message(event)) = events.try_recv() else {
+            panic!("event should be routed");
+        };
+        assert_eq!(event.event, "presence_state");
+    }
+
+    #[test]
+    fn unanswered_heartbeat_marks_the_connection_dead() {
+        let mut state = SocketState::default();
+        let first = state.heartbeat().expect("first heartbeat is sent");
+        assert!(state.heartbeat().is_none(), "second tick without a reply means the link is dead");
+
+        state.route(Frame {
+            event: "phx_reply".to_string(),
+            payload: json!({"status": "ok", "response": {}}),
+            ..first
+        });
+        assert!(state.heartbeat().is_some());
+    }
+
+    #[test]
+    fn rejoins_topics_with_fresh_refs_after_disconnect() {
+        let mut state = SocketState::default();
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let (reply_tx, _reply_rx) = oneshot::channel();
+        let join = state
+            .frame_for(super::Command::Join {
+                topic: "rendezvous:demo".to_string(),
+                params: json!({"client_id": "a"}),
+                events: events_tx,
+                reply: reply_tx,
+            })
+            .expect("join should produce a frame");
+
+        state.disconnected();
+        assert_eq!(events.try_recv(), Ok(ChannelEvent::Disconnected));
+
+        let rejoin = state.rejoin_frames().pop().expect("topic should be rejoined");
+        assert_eq!(rejoin.event, "phx_join");
+        assert_eq!(rejoin.payload, json!({"client_id": "a"}));
+        assert_ne!(rejoin.join_ref, join.join_ref);
+
+        state.route(Frame {
+            event: "phx_reply".to_string(),
+            payload: json!({"status": "ok", "response": {}}),
+            ..rejoin
+        });
+        assert!(matches!(events.try_recv(), Ok(ChannelEvent::Rejoined(reply)) if reply.is_ok()));
+    }
+
+    #[test]
+    fn rejected_first_join_is_not_rejoined_after_a_reconnect() {
+        let mut state = SocketState::default();
+        let (events_tx, _events) = mpsc::unbounded_channel();
+        let (reply_tx, mut reply_rx) = oneshot::channel();
+        let join = state
+            .frame_for(super::Command::Join {
+                topic: "rendezvous:demo".to_string(),
+                params: json!({"client_id": "a"}),
+                events: events_tx,
+                reply: reply_tx,
+            })
+            .expect("join should produce a frame");
+        state.route(Frame {
+            event: "phx_reply".to_string(),
+            payload: json!({"status": "error", "response": {"reason": "unauthorized"}}),
+            ..join
+        });
+        assert!(!reply_rx.try_recv().expect("the caller gets the rejection").is_ok());
+
+        state.disconnected();
+        let rejoins = state.rejoin_frames();
+        assert!(
+            rejoins.iter().all(|frame| frame.topic != "rendezvous:demo"),
+            "no phx_join for a topic whose join failed"
+        );
+        assert!(state.topics.is_empty());
+    }
+
+    #[test]
+    fn rejected_rejoin_goes_offline_and_is_retried() {
+        let mut state = SocketState::default();
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let (reply_tx, _reply_rx) = oneshot::channel();
+        state.frame_for(super::Command::Join {
+            topic: "rendezvous:demo".to_string(),
+            params: json!({"client_id": "a"}),
+            events: events_tx,
+            reply: reply_tx,
+        });
+        state.disconnected();
+        assert_eq!(events.try_recv(), Ok(ChannelEvent::Disconnected));
+
+        let rejoin = state.rejoin_frames().pop().expect("topic should be rejoined");
+        state.route(Frame {
+            event: "phx_reply".to_string(),
+            payload: json!({"status": "error", "response": {"reason": "unmatched topic"}}),
+            ..rejoin.clone()
+        });
+        assert!(matches!(events.try_recv(), Ok(ChannelEvent::RejoinFailed(reply)) if !reply.is_ok()));
+        assert_eq!(state.topics["rendezvous:demo"].join_ref, None, "a rejected join must not stay current");
+        let (push_tx, _push_rx) = oneshot::channel();
+        let push = state.frame_for(super::Command::Push {
+            topic: "rendezvous:demo".to_string(),
+            event: "candidates".to_string(),
+            payload: json!({}),
+            reply: push_tx,
+        });
+        assert!(push.is_none(), "pushes fail until the retry succeeds");
+
+        let retry_at = state.next_retry().expect("a retry should be scheduled");
+        assert!(state.due_rejoin(retry_at - Duration::from_millis(1)).is_none());
+        let retry = state.due_rejoin(retry_at).expect("retry is due");
+        assert_eq!(retry.payload, json!({"client_id": "a"}));
+        assert_ne!(retry.join_ref, rejoin.join_ref);
+        assert_eq!(state.next_retry(), None);
+
+        state.route(Frame {
+            event: "phx_reply".to_string(),
+            payload: json!({"status": "ok", "response": {}}),
+            ..retry
+        });
+        assert!(matches!(events.try_recv(), Ok(ChannelEvent::Rejoined(reply)) if reply.is_ok()));
+        assert_eq!(state.topics["rendezvous:demo"].rejoin_attempts, 0);
+    }
+
+    #[test]
+    fn abandoned_joins_and_pushes_are_forgotten() {
+        let mut state = SocketState::default();
+        let join = |state: &mut SocketState, topic: &str| {
+            let (events_tx, _) = mpsc::unbounded_channel();
+            let (reply_tx, reply_rx) = oneshot::channel();
+            let frame = state.frame_for(super::Command::Join {
+                topic: topic.to_string(),
+                params: json!({}),
+                events: events_tx,
+                reply: reply_tx,
+            });
+            (frame.expect("join should produce a frame"), reply_rx)
+        };
+        let (joined, _joined_rx) = join(&mut state, "rendezvous:joined");
+        state.route(Frame {
+            event: "phx_reply".to_string(),
+            payload: json!({"status": "ok", "response": {}}),
+            ..joined
+        });
+        let (timed_out, timed_out_rx) = join(&mut state, "rendezvous:timed-out");
+        let (push_tx, push_rx) = oneshot::channel();
+        let push = state.frame_for(super::Command::Push {
+            topic: "rendezvous:joined".to_string(),
+            event: "candidates".to_string(),
+            payload: json!({}),
+            reply: push_tx,
+        });
+        assert!(push.is_some());
+
+        // Both callers give up waiting.
+        drop((timed_out_rx, push_rx));
+        let leaves = state.abandoned();
+        assert_eq!(leaves.len(), 1, "the timed-out join is left");
+        assert_eq!((leaves[0].event.as_str(), &leaves[0].topic), ("phx_leave", &timed_out.topic));
+        assert_eq!(leaves[0].join_ref, timed_out.join_ref);
+        assert!(state.pending.is_empty(), "no reply is waited for any more");
+        assert_eq!(state.topics.keys().collect::<Vec<_>>(), ["rendezvous:joined"]);
+
+        state.disconnected();
+        let rejoins = state.rejoin_frames();
+        assert_eq!(rejoins.iter().map(|frame| frame.topic.as_str()).collect::<Vec<_>>(), ["rendezvous:joined"]);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(350),
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(200));
+        assert_eq!(backoff.delay(2), Duration::from_millis(350));
+        assert_eq!(backoff.delay(40), Duration::from_millis(350));
+    }
+}
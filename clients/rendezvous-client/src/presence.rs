@@ -0,0 +1,289 @@
+//! Client-side Phoenix Presence sync.
+//!
+//! Mirrors `Presence.syncState` / `Presence.syncDiff` from the Phoenix JS client: the
+//! server sends one `presence_state` after each join and `presence_diff` pushes afterwards.
+//! A key may have several metas (one per tracked process, e.g. an old and a new socket
+//! during a reconnect); metas are told apart by their `phx_ref`.
+
+use crate::noise;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use x25519_dalek::PublicKey;
+
+/// A key's presence changed. `metas` is the key's full meta list after the change, or the
+/// last known list for [`PresenceChange::Left`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PresenceChange {
+    Joined { key: String, metas: Vec<Value> },
+    Updated { key: String, metas: Vec<Value> },
+    Left { key: String, metas: Vec<Value> },
+}
+
+#[derive(Debug, Default)]
+pub struct Presence {
+    state: BTreeMap<String, Vec<Value>>,
+    synced: bool,
+    /// Diffs that arrived before the `presence_state` they apply to.
+    pending_diffs: Vec<Value>,
+}
+
+impl Presence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the whole state with a `presence_state` payload.
+    ///
+    /// `on_change` is called for every key that joined, changed metas or is no longer present.
+    /// Diffs buffered since the last [`Presence::desync`] are applied afterwards.
+    pub fn sync_state(&mut self, payload: &Value, mut on_change: impl FnMut(PresenceChange)) {
+        let fresh = parse_entries(payload);
+        let old = std::mem::take(&mut self.state);
+        for (key, metas) in &old {
+            if !fresh.contains_key(key) {
+                on_change(PresenceChange::Left {
+                    key: key.clone(),
+                    metas: metas.clone(),
+                });
+            }
+        }
+        for (key, metas) in &fresh {
+            if let Some(change) = classify(key, old.get(key), Some(metas)) {
+                on_change(change);
+            }
+        }
+        self.state = fresh;
+        self.synced = true;
+        for diff in std::mem::take(&mut self.pending_diffs) {
+            self.apply_diff(&diff, &mut on_change);
+        }
+    }
+
+    /// Applies a `presence_diff` payload (`{"joins": {...}, "leaves": {...}}`).
+    ///
+    /// Before the first `presence_state` (or after [`Presence::desync`]) the diff is buffered.
+    pub fn sync_diff(&mut self, payload: &Value, mut on_change: impl FnMut(PresenceChange)) {
+        if self.synced {
+            self.apply_diff(payload, &mut on_change);
+        } else {
+            self.pending_diffs.push(payload.clone());
+        }
+    }
+
+    /// Marks the state stale, e.g. when the channel lost its connection. The current entries
+    /// are kept so the next `presence_state` reports only what actually changed meanwhile.
+    pub fn desync(&mut self) {
+        self.synced = false;
+        self.pending_diffs.clear();
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[Value]> {
+        self.state.get(key).map(Vec::as_slice)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.state.keys().map(String::as_str)
+    }
+
+    fn apply_diff(&mut self, payload: &Value, on_change: &mut impl FnMut(PresenceChange)) {
+        let joins = parse_entries(&payload["joins"]);
+        let leaves = parse_entries(&payload["leaves"]);
+        let mut before = BTreeMap::new();
+        for key in joins.keys().chain(leaves.keys()) {
+            before
+                .entry(key.clone())
+                .or_insert_with(|| self.state.get(key).cloned());
+        }
+
+        for (key, joined) in joins {
+            let metas = self.state.entry(key).or_default();
+            for meta in joined {
+                match metas.iter_mut().find(|m| phx_ref(m) == phx_ref(&meta)) {
+                    Some(existing) => *existing = meta,
+                    None => metas.push(meta),
+                }
+            }
+        }
+        for (key, left) in leaves {
+            let Some(metas) = self.state.get_mut(&key) else {
+                continue;
+            };
+            metas.retain(|m| !left.iter().any(|l| phx_ref(l) == phx_ref(m)));
+            if metas.is_empty() {
+                self.state.remove(&key);
+            }
+        }
+
+        for (key, old) in before {
+            if let Some(change) = classify(&key, old.as_ref(), self.state.get(&key)) {
+                on_change(change);
+            }
+        }
+    }
+}
+
+/// The `udp` endpoint of the most recently joined meta that has one.
+pub fn latest_udp(metas: &[Value]) -> Option<&str> {
+    metas.iter().rev().find_map(|meta| meta.get("udp")?.as_str())
+}
+
+/// The `overlay_ip` of the most recently joined meta that has a valid one.
+pub fn latest_overlay_ip(metas: &[Value]) -> Option<IpAddr> {
+    metas.iter().rev().find_map(|meta| meta.get("overlay_ip")?.as_str()?.parse().ok())
+}
+
+/// The `static_key` the metas that have one agree on. Conflicting keys give none: the
+/// coordinator authenticates no one, so a second client under the same key could be anyone.
+pub fn agreed_static_key(metas: &[Value]) -> Option<PublicKey> {
+    let mut keys = metas
+        .iter()
+        .filter_map(|meta| noise::public_from_hex(meta.get("static_key")?.as_str()?));
+    let first = keys.next()?;
+    keys.all(|key| key == first).then_some(first)
+}
+
+fn classify(key: &str, old: Option<&Vec<Value>>, new: Option<&Vec<Value>>) -> Option<PresenceChange> {
+    let key = key.to_string();
+    match (old, new) {
+        (None, Some(metas)) => Some(PresenceChange::Joined {
+            key,
+            metas: metas.clone(),
+        }),
+        (Some(metas), None) => Some(PresenceChange::Left {
+            key,
+            metas: metas.clone(),
+        }),
+        (Some(old), Some(metas)) if old != metas => Some(PresenceChange::Updated {
+            key,
+            metas: metas.clone(),
+        }),
+        _ => None,
+    }
+}
+
+fn parse_entries(v: &Value) -> BTreeMap<String, Vec<Value>> {
+    let Some(map) = v.as_object() else {
+        return BTreeMap::new();
+    };
+    map.iter()
+        .filter_map(|(key, entry)| {
+            let metas = entry.get("metas")?.as_array()?.clone();
+            (!metas.is_empty()).then(|| (key.clone(), metas))
+        })
+        .collect()
+}
+
+fn phx_ref(meta: &Value) -> Option<&str> {
+    meta.get("phx_ref")?.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Presence, PresenceChange, agreed_static_key, latest_udp};
+    use serde_json::json;
+
+    fn changes(presence: &mut Presence, event: &str, payload: serde_json::Value) -> Vec<PresenceChange> {
+        let mut out = Vec::new();
+        match event {
+            "presence_state" => presence.sync_state(&payload, |c| out.push(c)),
+            _ => presence.sync_diff(&payload, |c| out.push(c)),
+        }
+        out
+    }
+
+    fn key(change: &PresenceChange) -> (&'static str, &str) {
+        match change {
+            PresenceChange::Joined { key, .. } => ("joined", key),
+            PresenceChange::Updated { key, .. } => ("updated", key),
+            PresenceChange::Left { key, .. } => ("left", key),
+        }
+    }
+
+    #[test]
+    fn diffs_join_update_and_leave_keys() {
+        let mut presence = Presence::new();
+        let got = changes(
+            &mut presence,
+            "presence_state",
+            json!({ "a": { "metas": [{ "phx_ref": "1", "udp": "10.0.0.2:1000" }] } }),
+        );
+        assert_eq!(got.iter().map(key).collect::<Vec<_>>(), [("joined", "a")]);
+
+        // A meta update arrives as a join of the new ref plus a leave of the old one.
+        let got = changes(
+            &mut presence,
+            "presence_diff",
+            json!({
+                "joins": {
+                    "a": { "metas": [{ "phx_ref": "2", "udp": "10.0.0.2:2000" }] },
+                    "b": { "metas": [{ "phx_ref": "3" }] }
+                },
+                "leaves": { "a": { "metas": [{ "phx_ref": "1" }] } }
+            }),
+        );
+        assert_eq!(got.iter().map(key).collect::<Vec<_>>(), [("updated", "a"), ("joined", "b")]);
+        assert_eq!(latest_udp(presence.get("a").unwrap()), Some("10.0.0.2:2000"));
+
+        let got = changes(
+            &mut presence,
+            "presence_diff",
+            json!({ "joins": {}, "leaves": { "b": { "metas": [{ "phx_ref": "3" }] } } }),
+        );
+        assert_eq!(got.iter().map(key).collect::<Vec<_>>(), [("left", "b")]);
+        assert_eq!(presence.keys().collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[test]
+    fn key_stays_while_any_meta_remains() {
+        let mut presence = Presence::new();
+        changes(
+            &mut presence,
+            "presence_state",
+            json!({ "a": { "metas": [{ "phx_ref": "1" }, { "phx_ref": "2", "udp": "10.0.0.2:2000" }] } }),
+        );
+        let got = changes(
+            &mut presence,
+            "presence_diff",
+            json!({ "leaves": { "a": { "metas": [{ "phx_ref": "2" }] } } }),
+        );
+        assert_eq!(got.iter().map(key).collect::<Vec<_>>(), [("updated", "a")]);
+        assert_eq!(latest_udp(presence.get("a").unwrap()), None);
+    }
+
+    #[test]
+    fn static_key_needs_every_meta_to_agree() {
+        let (first, second) = ("11".repeat(32), "22".repeat(32));
+        let metas = [json!({ "phx_ref": "1", "static_key": first }), json!({ "phx_ref": "2" })];
+        assert_eq!(agreed_static_key(&metas).map(|key| key.to_bytes()), Some([0x11; 32]));
+        let metas = [json!({ "phx_ref": "1", "static_key": first }), json!({ "phx_ref": "2", "static_key": first })];
+        assert!(agreed_static_key(&metas).is_some());
+        let metas = [json!({ "phx_ref": "1", "static_key": first }), json!({ "phx_ref": "2", "static_key": second })];
+        assert_eq!(agreed_static_key(&metas), None, "a second client under the same id");
+    }
+
+    #[test]
+    fn fresh_state_reports_departed_keys_and_buffers_early_diffs() {
+        let mut presence = Presence::new();
+        changes(
+            &mut presence,
+            "presence_state",
+            json!({ "a": { "metas": [{ "phx_ref": "1" }] }, "b": { "metas": [{ "phx_ref": "2" }] } }),
+        );
+
+        presence.desync();
+        let early = changes(
+            &mut presence,
+            "presence_diff",
+            json!({ "joins": { "c": { "metas": [{ "phx_ref": "4" }] } } }),
+        );
+        assert!(early.is_empty(), "diffs wait for the state they apply to");
+
+        let got = changes(
+            &mut presence,
+            "presence_state",
+            json!({ "a": { "metas": [{ "phx_ref": "1" }] } }),
+        );
+        assert_eq!(got.iter().map(key).collect::<Vec<_>>(), [("left", "b"), ("joined", "c")]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Keys and nonces that authenticate peer probes.
+//!
+//! Two peers derive a shared pair key from an X25519 exchange between their static keys (the
+//! ones the data channel's handshake uses, see `noise`): each side's private key with the other's
+//! public key from presence. The pair key names their session and keys every packet's tag (see
+//! `peer_wire`). Presence only carries public keys, so other members of the room, and anyone who
+//! reads the metas, cannot compute it. Requests (probes, keepalives) carry a fresh nonce, and acks
+//! must echo the nonce of a request we actually sent, so a recorded check cannot be replayed
+//! either.
+
+use crate::noise::StaticKeypair;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use x25519_dalek::PublicKey;
+
+/// Outstanding requests we still accept answers for, and request nonces remembered for replay
+/// detection.
+const OUTSTANDING: usize = 64;
+const SEEN: usize = 256;
+
+pub use peer_wire::Nonce;
+
+/// Key shared by two peers, the same whichever side derives it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PairKey([u8; 32]);
+
+impl PairKey {
+    /// Our key for the peer whose static key is `remote`. `None` if `remote` is a low-order point,
+    /// which would make the exchange's result public.
+    pub fn derive(local_id: &str, local: &StaticKeypair, remote_id: &str, remote: &PublicKey) -> Option<Self> {
+        let shared = local.shared_secret(remote)?;
+        let (first_id, second_id) = if local_id <= remote_id {
+            (local_id, remote_id)
+        } else {
+            (remote_id, local_id)
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(&shared).expect("hmac accepts any key length");
+        mac.update(b"vpn-probe ");
+        mac.update(first_id.as_bytes());
+        mac.update(b" ");
+        mac.update(second_id.as_bytes());
+        Some(Self(mac.finalize().into_bytes().into()))
+    }
+
+    /// Session id both sides put in their packets' headers.
+    pub fn session_id(&self) -> u64 {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("hmac accepts any key length");
+        mac.update(b"vpn-session");
+        let digest = mac.finalize().into_bytes();
+        u64::from_be_bytes(digest[..8].try_into().expect("sha256 output is longer than 8 bytes"))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+/// Per-peer nonce bookkeeping for the challenge/response.
+#[derive(Default)]
+pub struct Challenges {
+    outstanding: VecDeque<Nonce>,
+    seen: VecDeque<Nonce>,
+}
+
+impl Challenges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A fresh nonce for a request we are about to send.
+    pub fn challenge(&mut self) -> Nonce {
+        let nonce = rand::random();
+        if self.outstanding.len() == OUTSTANDING {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back(nonce);
+        nonce
+    }
+
+    /// Whether a response echoes one of our outstanding requests. Each request is answered once.
+    pub fn answer(&mut self, nonce: &Nonce) -> bool {
+        match self.outstanding.iter().position(|n| n == nonce) {
+            Some(index) => {
+                self.outstanding.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a request's nonce is new; replays of recently seen requests are refused.
+    pub fn fresh(&mut self, nonce: &Nonce) -> bool {
+        if self.seen.contains(nonce) {
+            return false;
+        }
+        if self.seen.len() == SEEN {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(*nonce);
+        true
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Challenges, PairKey};
+    use crate::noise::{StaticKeypair, public_from_hex};
+    use peer_wire::{Message, Packet};
+    use x25519_dalek::PublicKey;
+
+    #[test]
+    fn both_sides_derive_the_same_pair_key_and_verify_each_other() {
+        let (a, b) = (StaticKeypair::generate(), StaticKeypair::generate());
+        let key = PairKey::derive("a", &a, "b", &b.public()).unwrap();
+        assert_eq!(Some(key), PairKey::derive("b", &b, "a", &a.public()));
+        assert_ne!(Some(key), PairKey::derive("a", &a, "c", &b.public()), "the ids are bound in");
+
+        let probe = Packet {
+            session: key.session_id(),
+            sequence: 0,
+            message: Message::Probe {
+                flags: 0,
+                nonce: [7; 16],
+                timestamp: 0,
+            },
+        };
+        let datagram = probe.encode(key.as_bytes()).unwrap();
+        assert_eq!(Packet::decode(&datagram, key.as_bytes()), Ok(probe));
+
+        // A low-order key would make the pair key public, so there is none.
+        assert_eq!(PairKey::derive("a", &a, "b", &PublicKey::from([0; 32])), None);
+    }
+
+    #[test]
+    fn a_room_member_who_sees_every_meta_cannot_forge_a_pair_packet() {
+        let (a, b, mallory) = (StaticKeypair::generate(), StaticKeypair::generate(), StaticKeypair::generate());
+        let key = PairKey::derive("a", &a, "b", &b.public()).unwrap();
+        // All mallory learns from presence: both public keys and ids.
+        let (a_public, b_public) = (
+            public_from_hex(&a.public_hex()).unwrap(),
+            public_from_hex(&b.public_hex()).unwrap(),
+        );
+        let keepalive = Message::Keepalive {
+            nonce: [9; 16],
+            timestamp: 1,
+        };
+        let guesses = [
+            PairKey::derive("a", &mallory, "b", &b_public),
+            PairKey::derive("b", &mallory, "a", &a_public),
+            PairKey::derive("mallory", &mallory, "a", &a_public),
+            PairKey::derive("mallory", &mallory, "b", &b_public),
+        ];
+        for guess in guesses.into_iter().flatten() {
+            assert_ne!(guess.session_id(), key.session_id());
+            for session in [guess.session_id(), key.session_id()] {
+                let forged = Packet {
+                    session,
+                    sequence: 0,
+                    message: keepalive.clone(),
+                };
+                let datagram = forged.encode(guess.as_bytes()).unwrap();
+                assert!(Packet::decode(&datagram, key.as_bytes()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn responses_must_echo_an_outstanding_challenge_once() {
+        let mut challenges = Challenges::new();
+        let nonce = challenges.challenge();
+        assert!(!challenges.answer(&[0; 16]));
+        assert!(challenges.answer(&nonce));
+        assert!(!challenges.answer(&nonce), "a replayed response is refused");
+
+        assert!(challenges.fresh(&[1; 16]));
+        assert!(!challenges.fresh(&[1; 16]), "a replayed request is refused");
+    }
+}
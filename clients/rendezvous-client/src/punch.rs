@@ -0,0 +1,283 @@
+//! Hole-punching strategies for NATs that don't keep one public endpoint per socket.
+//!
+//! The rendezvous endpoint a peer advertises is the mapping its NAT made towards the
+//! coordinator. Behind an endpoint-independent mapping that is also what we will see, so
+//! sending to it is enough. Behind a dependent ("symmetric") mapping the peer's packets to us
+//! leave from a different port, so we either guess it (sequential allocators tend to hand out
+//! the next few ports) or, when both sides are symmetric, spray from many sockets to many
+//! ports until two guesses meet (the birthday paradox makes that likely after a few hundred
+//! probes per side).
+
+use crate::nat::{Behavior, NatBehavior};
+use anyhow::{Context, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::task::{self, Poll};
+use std::time::Instant;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Send to the advertised endpoint only.
+    Direct,
+    /// Also probe the `window` ports following the advertised one.
+    PortPrediction { window: u16 },
+    /// Open `sockets` extra local sockets and probe random ports on the peer's IP from them.
+    Birthday { sockets: usize },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PunchConfig {
+    /// Upper bound on probes sent per second, across all sockets.
+    pub probes_per_second: u32,
+    pub prediction_window: u16,
+    pub birthday_sockets: usize,
+}
+
+impl Default for PunchConfig {
+    fn default() -> Self {
+        Self {
+            probes_per_second: 100,
+            prediction_window: 32,
+            birthday_sockets: 64,
+        }
+    }
+}
+
+/// Picks a strategy from both sides' NAT classification; unknown behaviour is treated as
+/// endpoint-independent, which keeps the plain single-endpoint punch.
+pub fn choose_strategy(local: Option<&NatBehavior>, remote: Option<&NatBehavior>, config: &PunchConfig) -> Strategy {
+    let dependent = |nat: Option<&NatBehavior>| nat.is_some_and(|n| n.mapping != Behavior::EndpointIndependent);
+    let remote_dependent = dependent(remote);
+    if !remote_dependent {
+        return Strategy::Direct;
+    }
+    if dependent(local) {
+        return Strategy::Birthday {
+            sockets: config.birthday_sockets,
+        };
+    }
+    // The peer reaches our stable endpoint from an unknown port. Unless our filter only admits
+    // the exact ip:port we sent to, its packets get in and we learn the port from them.
+    match local.map(|n| n.filtering) {
+        Some(Behavior::AddressAndPortDependent) => Strategy::PortPrediction {
+            window: config.prediction_window,
+        },
+        _ => Strategy::Direct,
+    }
+}
+
+/// Sends probes for one peer according to a [`Strategy`], within the configured rate.
+pub struct Puncher {
+    strategy: Strategy,
+    peer: SocketAddr,
+    /// Extra sockets for [`Strategy::Birthday`]; index `i` is reported as `Some(i)`.
+    sockets: Vec<UdpSocket>,
+    probes_per_second: u32,
+    budget: f64,
+    last_refill: Instant,
+    sent: usize,
+    rng: StdRng,
+}
+
+impl Puncher {
+    /// `local_ip` is the address extra sockets bind to (normally the primary socket's).
+    pub async fn new(strategy: Strategy, peer: SocketAddr, local_ip: IpAddr, config: &PunchConfig) -> Result<Self> {
+        let mut sockets = Vec::new();
+        if let Strategy::Birthday { sockets: count } = strategy {
+            for _ in 0..count {
+                let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0))
+                    .await
+                    .context("failed to bind birthday punch socket")?;
+                sockets.push(socket);
+            }
+        }
+        Ok(Self {
+            strategy,
+            peer,
+            sockets,
+            probes_per_second: config.probes_per_second.max(1),
+            budget: 1.0,
+            last_refill: Instant::now(),
+            sent: 0,
+            rng: StdRng::from_entropy(),
+        })
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Sends as many probes as the rate allows since the last call. The advertised endpoint is
+    /// always probed first from the primary socket.
+    pub async fn send_probes(&mut self, primary: &UdpSocket, payload: &[u8]) -> io::Result<usize> {
+        let targets = self.next_targets(Instant::now());
+        for (socket, target) in &targets {
+            // A probe towards a closed port may bounce as ICMP; that is expected while guessing.
+            let _ = self.socket(primary, *socket).send_to(payload, target).await;
+        }
+        Ok(targets.len())
+    }
+
+    /// Receives on the primary socket or any extra socket, whichever has a datagram first.
+    /// The index says which socket to keep using for the peer (see [`Puncher::socket`]).
+    pub async fn recv_from_any(
+        &self,
+        primary: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<usize>)> {
+        poll_fn(|cx| {
+            let mut read = ReadBuf::new(&mut *buf);
+            if let Poll::Ready(result) = primary.poll_recv_from(cx, &mut read) {
+                let len = read.filled().len();
+                return Poll::Ready(result.map(|from| (len, from, None)));
+            }
+            self.poll_recv_extra(cx, buf)
+                .map_ok(|(len, from, index)| (len, from, Some(index)))
+        })
+        .await
+    }
+
+    /// Polls the extra sockets only, for callers that multiplex several punchers over one
+    /// primary socket.
+    pub fn poll_recv_extra(&self, cx: &mut task::Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr, usize)>> {
+        for (index, socket) in self.sockets.iter().enumerate() {
+            let mut read = ReadBuf::new(&mut *buf);
+            if let Poll::Ready(result) = socket.poll_recv_from(cx, &mut read) {
+                let len = read.filled().len();
+                return Poll::Ready(result.map(|from| (len, from, index)));
+            }
+        }
+        Poll::Pending
+    }
+
+    /// The socket behind an index from [`Puncher::recv_from_any`]; `None` is the primary socket.
+    pub fn socket<'a>(&'a self, primary: &'a UdpSocket, index: Option<usize>) -> &'a UdpSocket {
+        index.map_or(primary, |i| &self.sockets[i])
+    }
+
+    /// Gives up extra socket `index`, for a caller that keeps using the path punched from it.
+    pub fn into_socket(mut self, index: usize) -> UdpSocket {
+        self.sockets.swap_remove(index)
+    }
+
+    fn next_targets(&mut self, now: Instant) -> Vec<(Option<usize>, SocketAddr)> {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        let rate = f64::from(self.probes_per_second);
+        self.budget = (self.budget + elapsed.as_secs_f64() * rate).min(rate);
+
+        let mut targets = Vec::new();
+        while self.budget >= 1.0 {
+            self.budget -= 1.0;
+            let target = match self.strategy {
+                // One probe per round is plenty for a single endpoint.
+                Strategy::Direct if !targets.is_empty() => break,
+                Strategy::Direct => (None, self.peer),
+                Strategy::PortPrediction { window } => {
+                    let offset = (self.sent % (usize::from(window) + 1)) as u16;
+                    let Some(port) = self.peer.port().checked_add(offset) else {
+                        self.sent += 1;
+                        continue;
+                    };
+                    (None, SocketAddr::new(self.peer.ip(), port))
+                }
+                Strategy::Birthday { .. } if self.sent.is_multiple_of(self.sockets.len() + 1) => (None, self.peer),
+                Strategy::Birthday { .. } => {
+                    let socket = self.sent % (self.sockets.len() + 1) - 1;
+                    let port = self.rng.gen_range(1024..=u16::MAX);
+                    (Some(socket), SocketAddr::new(self.peer.ip(), port))
+                }
+            };
+            self.sent += 1;
+            targets.push(target);
+        }
+        targets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PunchConfig, Puncher, Strategy, choose_strategy};
+    use crate::nat::{Behavior, NatBehavior};
+    use std::collections::HashSet;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    fn nat(mapping: Behavior, filtering: Behavior) -> NatBehavior {
+        NatBehavior {
+            mapping,
+            filtering,
+            mapped: "198.51.100.1:40000".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn strategy_follows_both_nat_types() {
+        let config = PunchConfig::default();
+        let cone = nat(Behavior::EndpointIndependent, Behavior::AddressDependent);
+        let port_restricted = nat(Behavior::EndpointIndependent, Behavior::AddressAndPortDependent);
+        let symmetric = nat(Behavior::AddressAndPortDependent, Behavior::AddressAndPortDependent);
+
+        assert_eq!(choose_strategy(None, None, &config), Strategy::Direct);
+        assert_eq!(choose_strategy(Some(&symmetric), Some(&cone), &config), Strategy::Direct);
+        assert_eq!(choose_strategy(Some(&cone), Some(&symmetric), &config), Strategy::Direct);
+        assert_eq!(
+            choose_strategy(Some(&port_restricted), Some(&symmetric), &config),
+            Strategy::PortPrediction { window: 32 }
+        );
+        assert_eq!(
+            choose_strategy(Some(&symmetric), Some(&symmetric), &config),
+            Strategy::Birthday { sockets: 64 }
+        );
+    }
+
+    #[tokio::test]
+    async fn prediction_sweeps_the_window_within_the_rate() {
+        let config = PunchConfig {
+            probes_per_second: 4,
+            prediction_window: 3,
+            birthday_sockets: 0,
+        };
+        let peer: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        let strategy = Strategy::PortPrediction { window: 3 };
+        let mut puncher = Puncher::new(strategy, peer, IpAddr::V4(Ipv4Addr::LOCALHOST), &config)
+            .await
+            .unwrap();
+
+        let start = puncher.last_refill;
+        assert_eq!(puncher.next_targets(start).len(), 1, "starts with a single probe");
+        let targets = puncher.next_targets(start + Duration::from_secs(10));
+        assert_eq!(targets.len(), 4, "a long pause still only allows one second of burst");
+        let ports: Vec<u16> = targets.iter().map(|(_, t)| t.port()).collect();
+        assert_eq!(ports, [5001, 5002, 5003, 5000]);
+    }
+
+    #[tokio::test]
+    async fn birthday_spreads_probes_over_extra_sockets() {
+        let config = PunchConfig {
+            probes_per_second: 1000,
+            prediction_window: 0,
+            birthday_sockets: 8,
+        };
+        let peer: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+        let mut puncher = Puncher::new(
+            Strategy::Birthday { sockets: 8 },
+            peer,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let targets = puncher.next_targets(Instant::now() + Duration::from_secs(1));
+        let sockets: HashSet<_> = targets.iter().filter_map(|(s, _)| *s).collect();
+        assert_eq!(sockets.len(), 8);
+        assert!(targets.contains(&(None, peer)), "the advertised endpoint is still probed");
+        assert!(targets.iter().all(|(_, t)| t.ip() == peer.ip()));
+    }
+}
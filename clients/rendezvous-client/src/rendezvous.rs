@@ -0,0 +1,1119 @@
+//! Rendezvous as a library: join a room on the coordinator, punch a path to the peers in it and
+//! open the encrypted data channel over each.
+//!
+//! [`connect`] is for services that want one peer: it returns the punched socket, the peer's
+//! endpoint and what the session learned on the way. [`run`] drives a whole mesh and is what the
+//! client's `punch` command wraps. A run fails once `timeout` passes without every wanted peer
+//! reached (a joining peer or the relay fallback restarts the clock); dropping its future cancels
+//! it and leaves the room.
+
+use crate::channel::DataChannel;
+use crate::ice::{self, Candidate, CandidateKind};
+use crate::lifetime::{self, BindingLifetime, LifetimeOptions};
+use crate::nat::{self, NatBehavior};
+use crate::noise::{self, StaticKeypair};
+use crate::overlay::{self, OverlayAddress, OverlayClaim, OverlayRoutes};
+use crate::phoenix::{Channel, ChannelEvent, Socket};
+use crate::presence::{Presence, PresenceChange, agreed_static_key, latest_overlay_ip, latest_udp};
+use crate::probe::PairKey;
+use crate::punch::{PunchConfig, Puncher, choose_strategy};
+use crate::report::{self, RunReport};
+use crate::session::{Session, SessionState, SessionTable, Via};
+use crate::stats::ClientStats;
+use crate::stun::{self, BindingOptions};
+use crate::tun::{self, Tun};
+use crate::turn::{TurnClient, TurnConfig};
+use anyhow::{Context, Result, bail};
+use peer_wire::{FLAG_NOMINATE, Message, Packet};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio::time::{Instant as TokioInstant, Interval, interval, interval_at, sleep, sleep_until};
+use url::Url;
+
+/// Peer keepalive until the NAT binding lifetime is known, unless one is pinned.
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// How long a finished run stays in the room after the last hello it answered, so a peer whose
+/// ack got lost can say hello again before seeing us leave.
+const HANDOFF_LINGER: Duration = Duration::from_secs(1);
+
+/// A progress line on stdout, if the run logs.
+macro_rules! say {
+    ($log:expr, $($arg:tt)*) => {
+        if $log {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub coordinator_host: String,
+    pub coordinator_http_port: u16,
+    pub coordinator_udp_port: u16,
+    pub room: String,
+    pub client_id: String,
+    /// Peers that must be reached before the run is done; without any, every peer present.
+    pub peers: Vec<String>,
+    /// How long to wait for the coordinator, and for peers before giving up.
+    pub timeout: Duration,
+    /// Fixed peer keepalive; without one it follows the NAT binding lifetime once that is known.
+    pub keepalive: Option<Duration>,
+    /// Largest idle gap the binding lifetime probe tries; `None` disables the probe.
+    pub lifetime_max: Option<Duration>,
+    /// Keep sessions up this long after the mesh is done, instead of returning right away.
+    /// TUN mode ignores it: the device serves the overlay until the run is cancelled.
+    pub stay: Option<Duration>,
+    /// STUN server (RFC 5780 capable for NAT classification) to learn our reflexive address from.
+    pub stun_server: Option<String>,
+    /// TURN server to relay through when direct punching fails.
+    pub turn: Option<TurnServer>,
+    /// Overlay address to request for TUN mode; its prefix is the overlay network.
+    pub overlay_address: Option<OverlayAddress>,
+    /// Overlay network to claim a free address in for TUN mode.
+    pub overlay_cidr: Option<OverlayAddress>,
+    pub tun_device: String,
+    /// Print progress lines to stdout, as the client does.
+    pub log: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct TurnServer {
+    /// `host:port`.
+    pub server: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl Config {
+    /// `client_id` joining `room` on the coordinator at `coordinator_host`, on its default ports
+    /// (4000 for the websocket, 3478 for UDP registration), with the client's defaults otherwise
+    /// and no logging.
+    pub fn new(coordinator_host: &str, room: &str, client_id: &str) -> Self {
+        Self {
+            coordinator_host: coordinator_host.to_string(),
+            coordinator_http_port: 4000,
+            coordinator_udp_port: 3478,
+            room: room.to_string(),
+            client_id: client_id.to_string(),
+            peers: Vec::new(),
+            timeout: Duration::from_secs(15),
+            keepalive: None,
+            lifetime_max: Some(Duration::from_secs(120)),
+            stay: None,
+            stun_server: None,
+            turn: None,
+            overlay_address: None,
+            overlay_cidr: None,
+            tun_device: "vpn0".to_string(),
+            log: false,
+        }
+    }
+}
+
+/// A peer reached through the rendezvous, with the socket that reaches it.
+pub struct PeerConnection {
+    /// The socket the path was punched from. The NAT binding belongs to it, so keep sending
+    /// from it; the peer's keepalives and handshakes keep arriving here too.
+    pub socket: UdpSocket,
+    /// The peer's endpoint on the path.
+    pub peer: SocketAddr,
+    /// What the run learned: our public endpoint and NAT, and the peer's session (nominated
+    /// pair, first contact, punch attempts, RTT).
+    pub report: RunReport,
+    client_id: String,
+    peer_id: String,
+    session: Session,
+    static_key: StaticKeypair,
+}
+
+impl PeerConnection {
+    /// The encrypted channel the rendezvous already opened over the socket; no new handshake.
+    pub fn into_channel(self) -> DataChannel {
+        DataChannel::open(
+            self.socket,
+            self.peer,
+            self.session,
+            (&self.client_id, &self.peer_id),
+            self.static_key,
+        )
+    }
+}
+
+/// Joins `room` on the coordinator at `coordinator_host` as `client_id` and returns once
+/// `peer_id` was reached over a punched, encrypted path. See [`connect_with`] for other ports,
+/// timeouts and STUN/TURN servers.
+pub async fn connect(coordinator_host: &str, room: &str, client_id: &str, peer_id: &str) -> Result<PeerConnection> {
+    connect_with(Config::new(coordinator_host, room, client_id), peer_id).await
+}
+
+/// [`connect`] with a full [`Config`]; its `peers` are replaced by `peer_id`. Fails if the only
+/// path to the peer is the TURN relay, which no plain socket can stand in for.
+pub async fn connect_with(mut config: Config, peer_id: &str) -> Result<PeerConnection> {
+    config.peers = vec![peer_id.to_string()];
+    let client_id = config.client_id.clone();
+    let mut report = RunReport::new(&client_id, &config.room);
+    let Mesh {
+        udp,
+        mut sessions,
+        static_key,
+    } = punch_mesh(config, &mut report).await?;
+    report.finish(&Ok(()));
+
+    let mut session = sessions
+        .remove(peer_id)
+        .with_context(|| format!("{peer_id} left before the rendezvous finished"))?;
+    let peer = session.endpoint.context("reached peer has no endpoint")?;
+    let socket = match session.via {
+        Via::Socket(None) => udp,
+        Via::Socket(Some(index)) => session
+            .puncher
+            .take()
+            .context("punching socket without a puncher")?
+            .into_socket(index),
+        Via::Relay => bail!("{peer_id} is only reachable through the TURN relay"),
+    };
+    Ok(PeerConnection {
+        socket,
+        peer,
+        report,
+        client_id,
+        peer_id: peer_id.to_string(),
+        session,
+        static_key,
+    })
+}
+
+/// Runs the mesh `config` describes until every wanted peer was reached (and `stay` passed),
+/// filling in `report` on the way. In TUN mode it only returns on failure: the device routes
+/// the overlay until the future is dropped.
+pub async fn run(config: Config, report: &mut RunReport) -> Result<()> {
+    punch_mesh(config, report).await.map(|_| ())
+}
+
+/// Where a finished mesh left off.
+struct Mesh {
+    udp: UdpSocket,
+    sessions: SessionTable,
+    static_key: StaticKeypair,
+}
+
+/// Resolves a `host:port` to one address; `what` names it in errors.
+pub async fn lookup(host: &str, what: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(host)
+        .await
+        .with_context(|| format!("failed to resolve {what}"))?
+        .next()
+        .with_context(|| format!("{what} resolution returned no results"))
+}
+
+/// Mirrors a presence change into the peer map. Returns the key if the peer left.
+///
+/// A peer whose metas carry no `udp` yet keeps the endpoint we already learned from `udp_seen`.
+fn apply_presence_change(peers: &mut HashMap<String, Option<String>>, change: PresenceChange) -> Option<String> {
+    match change {
+        PresenceChange::Joined { key, metas } | PresenceChange::Updated { key, metas } => {
+            let known = peers.entry(key).or_default();
+            if let Some(udp) = latest_udp(&metas) {
+                *known = Some(udp.to_string());
+            }
+            None
+        }
+        PresenceChange::Left { key, .. } => {
+            peers.remove(&key);
+            Some(key)
+        }
+    }
+}
+
+async fn resolve_udp_target(host: &str, port: u16) -> Result<SocketAddr> {
+    let mut addrs = tokio::net::lookup_host((host, port))
+        .await
+        .context("failed to resolve coordinator UDP address")?;
+    addrs
+        .next()
+        .context("coordinator UDP address resolution returned no results")
+}
+
+/// Pushes every local candidate gathered so far; peers pair them with their own.
+async fn announce_candidates(channel: &Channel, locals: &[(Candidate, Via)], wait: Duration) {
+    let candidates: Vec<&Candidate> = locals.iter().map(|(candidate, _)| candidate).collect();
+    let _ = channel.push("candidates", json!({ "candidates": candidates }), wait).await;
+}
+
+/// The peer's session, opened with the current local candidates if it is new. The side with the
+/// larger client id controls the pair nomination.
+fn session_for<'a>(sessions: &'a mut SessionTable, client_id: &str, peer: &str, locals: &[(Candidate, Via)]) -> &'a mut Session {
+    sessions.ensure(peer, || Session::new(client_id > peer, locals))
+}
+
+/// Every peer's overlay address claim in presence; ours is left out.
+fn overlay_claims<'a>(presence: &'a Presence, client_id: &str) -> Vec<(&'a str, IpAddr)> {
+    presence
+        .keys()
+        .filter(|peer| *peer != client_id)
+        .filter_map(|peer| Some((peer, latest_overlay_ip(presence.get(peer)?)?)))
+        .collect()
+}
+
+/// Whether every wanted peer has been reached and acknowledged our hello, or, if none were named,
+/// every peer present. A peer counts as reached once data arrived over its encrypted channel; both
+/// sets outlive the sessions, so a peer that finished and left still counts.
+fn mesh_done(sessions: &SessionTable, wanted: &[String], reached: &HashSet<String>, acked: &HashSet<String>) -> bool {
+    let confirmed = |peer: &str| reached.contains(peer) && acked.contains(peer);
+    if wanted.is_empty() {
+        !reached.is_empty() && sessions.iter().all(|(peer, _)| confirmed(peer))
+    } else {
+        wanted.iter().all(|peer| confirmed(peer))
+    }
+}
+
+fn print_sessions(log: bool, client_id: &str, sessions: &SessionTable, stats: &ClientStats) {
+    if !log {
+        return;
+    }
+    println!("{client_id} stats {stats}");
+    for line in sessions.to_string().lines() {
+        println!("{client_id} session {line}");
+    }
+}
+
+/// A tick every `period`, the first one a full period from now.
+fn every(period: Duration) -> Interval {
+    interval_at(TokioInstant::now() + period, period)
+}
+
+/// The mesh loop behind [`run`] and [`connect_with`]; returns what the sessions run over.
+///
+/// A TURN allocation made on the way is released however the loop ends, instead of being left
+/// to run out its lifetime on the server.
+async fn punch_mesh(config: Config, report: &mut RunReport) -> Result<Mesh> {
+    let (log, client_id) = (config.log, config.client_id.clone());
+    let mut relay = None;
+    let mesh = mesh_loop(config, report, &mut relay).await;
+    if let Some(relay) = relay
+        && let Err(e) = relay.release().await
+    {
+        say!(log, "{client_id} turn release failed: {e:#}");
+    }
+    mesh
+}
+
+/// [`punch_mesh`] up to the relay cleanup; `relay` is our TURN allocation once direct checks
+/// failed, which becomes a relay candidate for everyone.
+async fn mesh_loop(config: Config, report: &mut RunReport, relay: &mut Option<TurnClient>) -> Result<Mesh> {
+    let Config {
+        coordinator_host,
+        coordinator_http_port,
+        coordinator_udp_port,
+        room,
+        client_id,
+        peers: wanted,
+        timeout,
+        keepalive: keepalive_pinned,
+        lifetime_max,
+        stay,
+        log,
+        ..
+    } = config.clone();
+    // TUN mode (Linux): the client claims an overlay address, the requested one or any free one
+    // in the overlay network, and routes IP packets between a TUN device and the peers' data
+    // channels.
+    let requested = config.overlay_address;
+    let mut overlay_claim = config
+        .overlay_cidr
+        .or(requested)
+        .map(|network| OverlayClaim::new(network, &client_id, requested.map(|address| address.ip)))
+        .transpose()?;
+
+    let udp_target = resolve_udp_target(coordinator_host.as_str(), coordinator_udp_port).await?;
+    // Optional relay fallback for when direct punching fails.
+    let mut turn_config = match &config.turn {
+        Some(turn) => Some(TurnConfig::new(
+            lookup(&turn.server, "TURN server").await?,
+            &turn.username,
+            &turn.password,
+        )),
+        None => None,
+    };
+    let ws_url = Url::parse(&format!(
+        "ws://{}:{}/socket/websocket?vsn=2.0.0",
+        coordinator_host, coordinator_http_port
+    ))?;
+    let ws_url_s = ws_url.to_string();
+    let topic = format!("rendezvous:{room}");
+
+    // One UDP socket for registration + direct peer traffic.
+    // Using a single socket increases the odds that NAT mapping stays stable.
+    let udp = tokio::net::UdpSocket::bind("0.0.0.0:0")
+        .await
+        .context("failed to bind UDP socket")?;
+
+    // Optional: learn our reflexive address from any standard STUN server (e.g. host:3478), and
+    // our NAT's mapping/filtering behaviour if the server supports RFC 5780.
+    let mut nat = None;
+    // Binding lifetime discovery takes minutes, so it runs in the background (RFC 5780 again).
+    // A join set, so the probe stops with the run.
+    let mut lifetime_probe: JoinSet<Result<BindingLifetime>> = JoinSet::new();
+    if let Some(stun_server) = &config.stun_server {
+        let server = lookup(stun_server, "STUN server").await?;
+        if let Some(max_gap) = lifetime_max {
+            let local_ip = udp.local_addr()?.ip();
+            let options = LifetimeOptions {
+                max_gap,
+                ..LifetimeOptions::default()
+            };
+            lifetime_probe.spawn(async move { lifetime::discover(local_ip, server, &options).await });
+        }
+        let probe = BindingOptions {
+            attempts: 3,
+            ..BindingOptions::default()
+        };
+        match nat::classify(&udp, server, &probe).await {
+            Ok(behavior) => {
+                say!(log, "{client_id} nat {behavior}");
+                report.public_endpoint = Some(behavior.mapped);
+                report.nat = Some(behavior);
+                nat = Some(behavior);
+            }
+            Err(e) => {
+                say!(log, "{client_id} nat classification unavailable: {e:#}");
+                let mapped = stun::binding_request(&udp, server, &BindingOptions::default()).await?;
+                say!(log, "{client_id} reflexive address {mapped} (stun {stun_server})");
+                report.public_endpoint = Some(mapped);
+            }
+        }
+    }
+
+    let device = match overlay_claim.as_ref().map(OverlayClaim::address) {
+        Some(address) => {
+            let name = config.tun_device.clone();
+            let device = Tun::create(&name)?;
+            tun::configure(&name, address)?;
+            say!(log, "{client_id} tun {name} up with {address}");
+            Some(device)
+        }
+        None => None,
+    };
+    // The device lives as long as the run, so in TUN mode a done mesh keeps going.
+    let serving = device.is_some();
+
+    let reg = json!({ "room": room, "client_id": client_id });
+    udp.send_to(reg.to_string().as_bytes(), udp_target)
+        .await
+        .context("failed to send UDP registration")?;
+
+    // Websocket connect with retry.
+    let start = Instant::now();
+    let socket = loop {
+        match Socket::connect(&ws_url_s).await {
+            Ok(v) => break v,
+            Err(_) if start.elapsed() < timeout => {
+                sleep(Duration::from_millis(200)).await;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    // Join channel. Heartbeats and ref tracking are handled by the socket.
+    // The coordinator copies `nat` into our presence meta so peers can pick a punching strategy,
+    // `static_key` to key our probes and the data channel with and `overlay_ip` so they know which
+    // tunneled packets are ours.
+    let static_key = StaticKeypair::generate();
+    let join_params = json!({
+        "client_id": client_id,
+        "nat": nat.map(|n| n.to_meta()),
+        "static_key": static_key.public_hex(),
+        "overlay_ip": overlay_claim.as_ref().map(|claim| claim.address().ip.to_string()),
+    });
+    let (mut channel, _) = socket
+        .join(&topic, join_params, timeout)
+        .await?;
+
+    // Re-register after join so the server can broadcast udp_seen to this socket.
+    udp.send_to(reg.to_string().as_bytes(), udp_target)
+        .await
+        .context("failed to send UDP registration (post-join)")?;
+
+    // ICE-style candidates shared by every session. Host candidates outrank the reflexive ones,
+    // so peers behind one NAT stay on the LAN.
+    let host = ice::host_address(udp.local_addr()?, udp_target).context("failed to determine host address")?;
+    let mut locals = vec![(Candidate::new(CandidateKind::Host, host), Via::Socket(None))];
+    if let Some(nat) = &nat {
+        locals.push((Candidate::new(CandidateKind::Srflx, nat.mapped), Via::Socket(None)));
+    }
+    announce_candidates(&channel, &locals, timeout).await;
+
+    let mut peers: HashMap<String, Option<String>> = HashMap::new();
+    let mut presence = Presence::new();
+    let mut deadline = Instant::now() + timeout;
+    let stay_deadline = stay.map(|stay| Instant::now() + stay);
+
+    // One session per peer in the room, punched and kept alive independently.
+    let punch_config = PunchConfig::default();
+    let mut sessions = SessionTable::new();
+    // Peers we received data from over the encrypted channel.
+    let mut reached = HashSet::new();
+    // Peers that acknowledged our hello, so they count us as reached too.
+    let mut acked = HashSet::new();
+    // Once the mesh is done without a stay: when to leave, pushed back by every hello we answer.
+    let mut linger_until: Option<Instant> = None;
+    // Which peer owns which overlay IP, for TUN mode.
+    let mut routes = OverlayRoutes::new();
+    // Refreshes and channel binds on the relay, run beside the loop so relayed data keeps flowing.
+    let mut turn_requests: JoinSet<Result<()>> = JoinSet::new();
+    let mut punch_tick = interval(Duration::from_millis(200));
+    let mut stats = ClientStats {
+        binding_lifetime: None,
+        keepalive: keepalive_pinned.unwrap_or(DEFAULT_KEEPALIVE).max(Duration::from_secs(1)),
+        coordinator_keepalive: Duration::from_secs(5),
+    };
+    let mut keepalive_tick = interval(stats.keepalive);
+    let mut coord_keepalive_tick = interval(stats.coordinator_keepalive);
+    let mut ws_open = true;
+    let mut buf = vec![0u8; 2048];
+    let mut relay_buf = vec![0u8; 2048];
+    let mut tun_buf = vec![0u8; usize::from(tun::MTU)];
+
+    loop {
+        let done = mesh_done(&sessions, &wanted, &reached, &acked);
+        if !done && Instant::now() >= deadline {
+            // Some peers are unreachable directly: fall back to the relay once, if one is configured.
+            let Some(config) = turn_config.take() else {
+                break;
+            };
+            let remotes: Vec<SocketAddr> = sessions
+                .iter()
+                .filter(|(_, session)| !session.is_established())
+                .flat_map(|(_, session)| session.checklist.remote_candidates().map(|c| c.address))
+                .collect();
+            if remotes.is_empty() {
+                break;
+            }
+            // Without a relay the run ends as if none were configured, with the direct sessions
+            // it has.
+            let client = match TurnClient::allocate(UdpSocket::bind("0.0.0.0:0").await?, config).await {
+                Ok(client) => client,
+                Err(e) => {
+                    say!(log, "{client_id} turn fallback failed: {e:#}");
+                    break;
+                }
+            };
+            // A channel per remote candidate, so the data goes out as ChannelData; binding one
+            // also installs the permission for the remote's IP.
+            for remote in &remotes {
+                if let Err(e) = client.channel_bind(*remote).await {
+                    say!(log, "{client_id} turn channel to {remote} failed: {e:#}");
+                }
+            }
+            let relayed = client.relayed_address();
+            say!(log, "{client_id} direct udp failed for some peers, relaying via {relayed}");
+            // Trickled as a relay candidate: the peers' checks to it also open their own NATs for
+            // the relay's answers.
+            let candidate = Candidate::new(CandidateKind::Relay, relayed);
+            locals.push((candidate, Via::Relay));
+            for (_, session) in sessions.iter_mut() {
+                session.checklist.add_local(candidate, Via::Relay);
+            }
+            if ws_open {
+                announce_candidates(&channel, &locals, timeout).await;
+            }
+            *relay = Some(client);
+            deadline = Instant::now() + timeout;
+            continue;
+        }
+
+        if !done || serving {
+            linger_until = None;
+        } else {
+            if stay_deadline.is_none() {
+                linger_until.get_or_insert_with(|| Instant::now() + HANDOFF_LINGER);
+            }
+            if stay_deadline.or(linger_until).is_some_and(|until| Instant::now() >= until) {
+                print_sessions(log, &client_id, &sessions, &stats);
+                report.record_sessions(&sessions, &reached, &peers);
+                return Ok(Mesh { udp, sessions, static_key });
+            }
+        }
+
+        // The coordinator reports our own reflexive endpoint too; without STUN it is our srflx.
+        if let Some(Some(mine)) = peers.get(&client_id)
+            && let Ok(mine) = mine.parse::<SocketAddr>()
+            && !locals.iter().any(|(c, _)| c.address == mine)
+        {
+            let candidate = Candidate::new(CandidateKind::Srflx, mine);
+            report.public_endpoint.get_or_insert(mine);
+            locals.push((candidate, Via::Socket(None)));
+            for (_, session) in sessions.iter_mut() {
+                session.checklist.add_local(candidate, Via::Socket(None));
+            }
+            if ws_open {
+                announce_candidates(&channel, &locals, timeout).await;
+            }
+        }
+
+        for peer in peers.keys().filter(|peer| **peer != client_id) {
+            session_for(&mut sessions, &client_id, peer, &locals);
+        }
+        // Trust on first use: a session keeps the first key its peer's metas agree on (see `noise`).
+        for (peer, session) in sessions.iter_mut().filter(|(_, s)| s.static_key.is_none()) {
+            session.static_key = presence.get(peer).and_then(agreed_static_key);
+            session.key = session
+                .static_key
+                .and_then(|remote| PairKey::derive(&client_id, &static_key, peer, &remote));
+        }
+        let advertised: Vec<(String, SocketAddr)> = peers
+            .iter()
+            .filter(|(peer, _)| sessions.get(peer).is_some_and(|s| s.puncher.is_none()))
+            .filter_map(|(peer, udp)| Some((peer.clone(), udp.as_deref()?.parse().ok()?)))
+            .collect();
+        for (peer, endpoint) in advertised {
+            let peer_nat = presence
+                .get(&peer)
+                .and_then(|metas| metas.iter().rev().find_map(NatBehavior::from_meta));
+            let strategy = choose_strategy(nat.as_ref(), peer_nat.as_ref(), &punch_config);
+            let puncher = Puncher::new(strategy, endpoint, udp.local_addr()?.ip(), &punch_config).await?;
+            let session = session_for(&mut sessions, &client_id, &peer, &locals);
+            session.puncher = Some(puncher);
+            session.checklist.add_remote(Candidate::new(CandidateKind::Srflx, endpoint));
+            if session.state == SessionState::Discovered {
+                session.state = SessionState::Checking;
+            }
+            say!(log, "{client_id} discovered peer {peer} at {endpoint} (punching: {strategy:?})");
+        }
+
+        // Wake up for the timeout, or the end of the stay or linger, even if nothing else happens.
+        let wake = if !done {
+            Some(deadline)
+        } else {
+            stay_deadline.or(linger_until).filter(|_| !serving)
+        };
+        tokio::select! {
+            _ = sleep_until(wake.map_or_else(TokioInstant::now, TokioInstant::from_std)), if wake.is_some() => {}
+            _ = coord_keepalive_tick.tick() => {
+                // Keep coordinator observation fresh (and keep the NAT mapping to the coordinator alive).
+                let _ = udp.send_to(reg.to_string().as_bytes(), udp_target).await;
+            }
+            Some(probed) = lifetime_probe.join_next(), if !lifetime_probe.is_empty() => {
+                match probed.context("binding lifetime probe panicked")? {
+                    Ok(lifetime) => {
+                        // Refresh well within the lifetime: the coordinator always, peers unless pinned.
+                        let refresh = lifetime.keepalive_interval();
+                        stats.binding_lifetime = Some(lifetime);
+                        stats.coordinator_keepalive = refresh;
+                        coord_keepalive_tick = every(refresh);
+                        if keepalive_pinned.is_none() {
+                            stats.keepalive = refresh;
+                            keepalive_tick = every(refresh);
+                        }
+                        say!(log, "{client_id} nat {stats}");
+                    }
+                    Err(e) => say!(log, "{client_id} binding lifetime unavailable: {e:#}"),
+                }
+            }
+            Some(requested) = turn_requests.join_next(), if !turn_requests.is_empty() => {
+                if let Err(e) = requested.context("turn request panicked")? {
+                    say!(log, "{client_id} {e:#}");
+                }
+            }
+            _ = punch_tick.tick(), if sessions.iter().any(|(peer, s)| {
+                s.state == SessionState::Checking
+                    || s.is_validating_path()
+                    || s.is_established() && (!s.is_channel_open() || !acked.contains(peer))
+            }) => {
+                let now = Instant::now();
+                // On established sessions the controlling side (re)tries the handshake, and both
+                // sides say hello over the channel until the peer acknowledged it.
+                for (peer, session) in sessions.iter_mut().filter(|(_, s)| s.is_established()) {
+                    let Some(endpoint) = session.endpoint else { continue };
+                    let message = if client_id.as_str() > peer {
+                        session.initiation_due(&static_key, &noise::prologue(&client_id, peer), now)
+                    } else {
+                        None
+                    };
+                    let message = message.or_else(|| {
+                        (!acked.contains(peer)).then(|| session.seal_hello(false)).flatten()
+                    });
+                    if let Some(message) = message.and_then(|message| session.seal(message)) {
+                        session.send(&udp, relay.as_ref(), session.via, &message, endpoint).await;
+                    }
+                }
+                for (peer, session) in sessions.iter_mut().filter(|(_, s)| s.is_validating_path()) {
+                    match session.path_challenge_due() {
+                        Some((via, endpoint, challenge)) => {
+                            if let Some(challenge) = session.seal(challenge) {
+                                session.send(&udp, relay.as_ref(), via, &challenge, endpoint).await;
+                            }
+                        }
+                        None => say!(log, "{client_id} path to {peer} not validated, staying on the old one"),
+                    }
+                }
+                let checking = sessions
+                    .iter_mut()
+                    .filter(|(_, s)| s.state == SessionState::Checking && s.key.is_some());
+                for (peer, session) in checking {
+                    if let Some(pair) = session.checklist.nominate(now) {
+                        say!(
+                            log,
+                            "{client_id} nominating {:?} {} -> {:?} {} for {peer}",
+                            pair.local.kind, pair.local.address, pair.remote.kind, pair.remote.address
+                        );
+                    }
+                    if let Some(pair) = session.checklist.selected().copied() {
+                        // Repeat the nomination until the peer acknowledges it.
+                        let nominate = session.probe(FLAG_NOMINATE, now);
+                        if let Some(nominate) = session.seal(nominate) {
+                            session.send(&udp, relay.as_ref(), pair.base, &nominate, pair.remote.address).await;
+                        }
+                        continue;
+                    }
+                    for (via, remote) in session.checklist.due_checks(now) {
+                        let probe = session.probe(0, now);
+                        if let Some(probe) = session.seal(probe) {
+                            session.send(&udp, relay.as_ref(), via, &probe, remote).await;
+                        }
+                    }
+                    // Port prediction and birthday probes reach endpoints no candidate describes;
+                    // whatever answers shows up as a peer-reflexive pair. One challenge per round.
+                    if session.puncher.is_some() {
+                        let probe = session.probe(0, now);
+                        if let Some(probe) = session.seal(probe)
+                            && let Some(puncher) = session.puncher.as_mut()
+                        {
+                            let _ = puncher.send_probes(&udp, &probe).await;
+                        }
+                    }
+                }
+            }
+            _ = keepalive_tick.tick(), if sessions.iter().any(|(_, s)| s.is_established()) => {
+                let now = Instant::now();
+                for (_, session) in sessions.iter_mut() {
+                    let Some(endpoint) = session.endpoint.filter(|_| session.is_established()) else {
+                        continue;
+                    };
+                    let (nonce, timestamp) = (session.challenges.challenge(), session.timestamp(now));
+                    if let Some(keepalive) = session.seal(Message::Keepalive { nonce, timestamp }) {
+                        session.send(&udp, relay.as_ref(), session.via, &keepalive, endpoint).await;
+                    }
+                }
+                // The next tick catches up on anything still in flight.
+                if let Some(relay) = relay.clone()
+                    && turn_requests.is_empty()
+                {
+                    turn_requests.spawn(async move { relay.keep_alive().await });
+                }
+                print_sessions(log, &client_id, &sessions, &stats);
+                // A TUN run only ends by being cancelled, so keep its report current.
+                report.record_sessions(&sessions, &reached, &peers);
+            }
+            read = async {
+                match &device {
+                    Some(device) => device.recv(&mut tun_buf).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let n = read.context("tun read failed")?;
+                let packet = &tun_buf[..n];
+                // Packets for overlay addresses nobody owns, or peers without a channel, are dropped.
+                let Ok((_, destination)) = overlay::addresses(packet) else {
+                    continue;
+                };
+                let Some(session) = routes.peer(destination).and_then(|peer| sessions.get_mut(peer)) else {
+                    continue;
+                };
+                if let Some(endpoint) = session.endpoint.filter(|_| session.is_established())
+                    && let Some(message) = session.seal_data(packet).and_then(|message| session.seal(message))
+                {
+                    session.send(&udp, relay.as_ref(), session.via, &message, endpoint).await;
+                }
+            }
+            recv = async {
+                tokio::select! {
+                    direct = sessions.recv_from_any(&udp, &mut buf) => direct.map_err(anyhow::Error::from),
+                    relayed = async {
+                        match &relay {
+                            Some(relay) => relay.recv_from(&mut relay_buf).await,
+                            None => std::future::pending().await,
+                        }
+                    } => {
+                        relayed.map(|(n, from)| (n, from, None, Via::Relay))
+                    }
+                }
+            } => {
+                let (n, from, owner, via) = recv.context("udp recv_from failed")?;
+                let datagram = if via == Via::Relay { &relay_buf[..n] } else { &buf[..n] };
+                let Ok(id) = peer_wire::session_of(datagram) else {
+                    continue;
+                };
+                let Some((sender, session)) = sessions.by_session_id(id) else {
+                    continue;
+                };
+                // A punching socket belongs to one session; anything else arriving on it is a stray.
+                if owner.is_some_and(|owner| owner != sender) {
+                    continue;
+                }
+                let sender = sender.to_string();
+                let Some(Ok(packet)) = session.key.map(|key| Packet::decode(datagram, key.as_bytes())) else {
+                    continue;
+                };
+                // Forged, replayed or unsolicited probes never touch the session: requests need a
+                // nonce we have not seen, acks must echo one of our own outstanding requests.
+                let fresh = match &packet.message {
+                    Message::Probe { nonce, .. } | Message::Keepalive { nonce, .. } => session.challenges.fresh(nonce),
+                    Message::ProbeAck { nonce, .. } => session.challenges.answer(nonce),
+                    // Beyond the tag, a challenge must come in on a path we know the peer on, so
+                    // responses never go elsewhere; a response must match our challenge.
+                    Message::PathChallenge { .. } => session.is_known_path(via, from),
+                    Message::PathResponse { .. } => true,
+                    // The handshake and the AEAD refuse replays of these themselves.
+                    Message::HandshakeInit { .. }
+                    | Message::HandshakeResponse { .. }
+                    | Message::Data { .. }
+                    | Message::Hello { .. }
+                    | Message::HelloAck { .. } => true,
+                    _ => false,
+                };
+                if !fresh {
+                    continue;
+                }
+                let controlling = client_id.as_str() > sender.as_str();
+                session.seen(Instant::now(), &packet);
+
+                // Keepalives only travel the established path, so one from elsewhere means the
+                // peer's NAT rebound or it roamed (late checks may not). Prove it receives there.
+                if matches!(packet.message, Message::Keepalive { .. })
+                    && let Some(challenge) = session.observe_path(via, from)
+                    && let Some(challenge) = session.seal(challenge)
+                {
+                    say!(log, "{client_id} peer {sender} seen at {from} via {via:?}, validating path");
+                    session.send(&udp, relay.as_ref(), via, &challenge, from).await;
+                }
+
+                // Checks are accepted from whatever endpoint and local socket actually work, even
+                // if they differ from the advertised candidates (symmetric NAT, predicted ports
+                // and birthday hits all become peer-reflexive pairs).
+                let ack = match packet.message {
+                    Message::Probe { flags, nonce, timestamp } => {
+                        session.checklist.on_request(via, from);
+                        Some(Message::ProbeAck { flags, nonce, timestamp })
+                    }
+                    Message::Keepalive { nonce, timestamp } => Some(Message::ProbeAck {
+                        flags: 0,
+                        nonce,
+                        timestamp,
+                    }),
+                    Message::PathChallenge { data } => Some(Message::PathResponse { data }),
+                    Message::HandshakeInit { ref payload } if !controlling => {
+                        let response = session.accept_initiation(&static_key, &noise::prologue(&sender, &client_id), payload);
+                        if response.is_some() {
+                            say!(log, "{client_id} secure channel up with {sender}");
+                        }
+                        response
+                    }
+                    _ => None,
+                };
+                if let Some(ack) = ack.and_then(|ack| session.seal(ack)) {
+                    session.send(&udp, relay.as_ref(), via, &ack, from).await;
+                }
+
+                if let Message::PathResponse { data } = packet.message
+                    && let Some(previous) = session.confirm_path(via, from, data)
+                {
+                    say!(log, "{client_id} path to {sender} migrated {previous} -> {from} via {via:?}");
+                }
+
+                if let Message::HandshakeResponse { ref payload } = packet.message
+                    && session.finish_initiation(payload)
+                {
+                    say!(log, "{client_id} secure channel up with {sender}");
+                }
+                if let Message::Data { counter, ref payload } = packet.message
+                    && let Some(data) = session.open_data(counter, payload)
+                {
+                    // In TUN mode, IP packets go to the device if their source is the sender's own
+                    // overlay address; anything else is dropped.
+                    if let Some(device) = &device
+                        && let Ok((source, _)) = overlay::addresses(&data)
+                        && routes.address(&sender) == Some(source)
+                        && let Err(e) = device.send(&data).await
+                    {
+                        say!(log, "{client_id} tun write failed: {e}");
+                    }
+                    reached.insert(sender.clone());
+                    continue;
+                }
+                if let Message::Hello { counter, ref payload } | Message::HelloAck { counter, ref payload } =
+                    packet.message
+                    && session.open_hello(counter, payload)
+                {
+                    if matches!(packet.message, Message::HelloAck { .. }) {
+                        acked.insert(sender.clone());
+                    } else {
+                        // Every hello is acknowledged, repeats too in case our ack was lost, and
+                        // keeps a finished run in the room a little longer.
+                        if let Some(ack) = session.seal_hello(true).and_then(|ack| session.seal(ack)) {
+                            session.send(&udp, relay.as_ref(), via, &ack, from).await;
+                        }
+                        if let Some(until) = linger_until.as_mut() {
+                            *until = Instant::now() + HANDOFF_LINGER;
+                        }
+                    }
+                    if reached.insert(sender.clone()) {
+                        say!(log, "{client_id} hello from {sender}");
+                    }
+                    continue;
+                }
+
+                let agreed = match packet.message {
+                    Message::ProbeAck { flags: 0, .. } => {
+                        session.checklist.on_response(via, from, Instant::now());
+                        None
+                    }
+                    Message::ProbeAck { flags: FLAG_NOMINATE, .. } => session
+                        .checklist
+                        .selected()
+                        .filter(|pair| pair.base == via && pair.remote.address == from)
+                        .copied(),
+                    Message::Probe { flags: FLAG_NOMINATE, .. } if !controlling => {
+                        Some(session.checklist.select(via, from))
+                    }
+                    _ => None,
+                };
+                if let Some(pair) = agreed
+                    && !session.is_established()
+                {
+                    session.state = SessionState::Established;
+                    session.endpoint = Some(from);
+                    session.via = via;
+                    let path = report::path_name(&pair);
+                    say!(
+                        log,
+                        "{client_id} {path} udp ok with {sender} (from {from}, {:?} -> {:?})",
+                        pair.local.kind, pair.remote.kind
+                    );
+                }
+            }
+            event = channel.recv(), if ws_open => {
+                let event = match event {
+                    Some(ChannelEvent::Message(event)) => event,
+                    Some(ChannelEvent::Disconnected) => {
+                        say!(log, "{client_id} lost coordinator connection, reconnecting");
+                        presence.desync();
+                        continue;
+                    }
+                    Some(ChannelEvent::RejoinFailed(reply)) => {
+                        // The socket retries; until then presence stays out of sync.
+                        say!(log, "{client_id} rejoin of {topic} rejected: {}, retrying", reply.response);
+                        continue;
+                    }
+                    Some(ChannelEvent::Rejoined(_)) => {
+                        // The new socket process has no udp registration for us yet, and peers may
+                        // have missed candidates broadcast while we were away.
+                        say!(log, "{client_id} rejoined {topic}");
+                        let _ = udp.send_to(reg.to_string().as_bytes(), udp_target).await;
+                        announce_candidates(&channel, &locals, timeout).await;
+                        // The rejoin repeats the join params, which may name an address we lost since.
+                        if let Some(claim) = &overlay_claim {
+                            let ip = claim.address().ip.to_string();
+                            let _ = channel.push("overlay_ip", json!({ "overlay_ip": ip }), timeout).await;
+                        }
+                        continue;
+                    }
+                    None => {
+                        // Punching can still finish with endpoints we already know.
+                        ws_open = false;
+                        continue;
+                    }
+                };
+                let payload = event.payload;
+
+                match event.event.as_str() {
+                    "presence_state" | "presence_diff" => {
+                        let mut left = Vec::new();
+                        let mut joined = false;
+                        let on_change = |change: PresenceChange| {
+                            joined |= matches!(&change, PresenceChange::Joined { key, .. } if *key != client_id);
+                            left.extend(apply_presence_change(&mut peers, change));
+                        };
+                        if event.event == "presence_state" {
+                            presence.sync_state(&payload, on_change);
+                        } else {
+                            presence.sync_diff(&payload, on_change);
+                        }
+                        for peer in left {
+                            // Stop punching/keepalives; a rejoining peer is rediscovered from scratch.
+                            if sessions.remove(&peer).is_some() {
+                                say!(log, "{client_id} peer {peer} left, tearing down session");
+                            }
+                        }
+                        // A claim may have appeared, moved or left: rebuild the routes, and give up our
+                        // own address if a smaller client id claims it too.
+                        let claims = overlay_claims(&presence, &client_id);
+                        let updated = OverlayRoutes::from_claims(claims.iter().copied());
+                        for (peer, ip) in updated.iter().filter(|&(peer, ip)| routes.address(peer) != Some(ip)) {
+                            say!(log, "{client_id} overlay {ip} is {peer}");
+                        }
+                        routes = updated;
+                        if let Some(claim) = overlay_claim.as_mut() {
+                            let old = claim.address();
+                            if let Some(new) = claim.resolve(claims.iter().copied())? {
+                                say!(log, "{client_id} overlay {} is contested, moving to {new}", old.ip);
+                                if let Some(device) = &device {
+                                    tun::readdress(device.name(), old, new)?;
+                                }
+                                let ip = new.ip.to_string();
+                                let _ = channel.push("overlay_ip", json!({ "overlay_ip": ip }), timeout).await;
+                            }
+                        }
+                        if joined {
+                            // Candidates are only broadcast, so a peer that joins later needs ours
+                            // again, and gets a full timeout to be reached.
+                            deadline = deadline.max(Instant::now() + timeout);
+                            announce_candidates(&channel, &locals, timeout).await;
+                        }
+                    }
+                    "candidates_seen" => {
+                        let candidates = serde_json::from_value::<Vec<Candidate>>(payload["candidates"].clone());
+                        if let Some(peer) = payload["client_id"].as_str()
+                            && peer != client_id
+                            && let Ok(candidates) = candidates
+                        {
+                            let session = session_for(&mut sessions, &client_id, peer, &locals);
+                            for candidate in candidates {
+                                if !session.checklist.add_remote(candidate) {
+                                    continue;
+                                }
+                                say!(log, "{client_id} peer {peer} candidate {:?} {}", candidate.kind, candidate.address);
+                                if session.state == SessionState::Discovered {
+                                    session.state = SessionState::Checking;
+                                }
+                                if let Some(relay) = relay.clone() {
+                                    let remote = candidate.address;
+                                    turn_requests.spawn(async move { relay.channel_bind(remote).await.map(drop) });
+                                }
+                            }
+                        }
+                    }
+                    "udp_seen" => {
+                        if let Some(obj) = payload.as_object() {
+                            let cid = obj.get("client_id").and_then(|v| v.as_str());
+                            let udp = obj.get("udp").and_then(|v| v.as_str());
+                            if let (Some(cid), Some(udp)) = (cid, udp) {
+                                peers.insert(cid.to_string(), Some(udp.to_string()));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    report.record_sessions(&sessions, &reached, &peers);
+    let pending: Vec<&str> = if wanted.is_empty() {
+        sessions
+            .iter()
+            .map(|(peer, _)| peer)
+            .filter(|peer| !reached.contains(*peer))
+            .collect()
+    } else {
+        wanted
+            .iter()
+            .map(String::as_str)
+            .filter(|peer| !reached.contains(*peer))
+            .collect()
+    };
+    if pending.is_empty() {
+        bail!("{client_id} timed out waiting for peers in room {room}");
+    }
+    bail!("{client_id} timed out waiting for udp with {}", pending.join(", "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_presence_change, mesh_done, overlay_claims};
+    use crate::presence::{Presence, PresenceChange};
+    use crate::session::{Session, SessionState, SessionTable};
+    use serde_json::json;
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn mesh_is_done_once_every_wanted_peer_was_reached() {
+        let mut sessions = SessionTable::new();
+        let mut reached = HashSet::new();
+        let mut acked = HashSet::new();
+        let wanted = vec!["b".to_string(), "c".to_string()];
+        assert!(!mesh_done(&sessions, &[], &reached, &acked), "an empty room is not a mesh");
+
+        sessions.ensure("b", || Session::new(true, &[])).state = SessionState::Established;
+        sessions.ensure("c", || Session::new(true, &[]));
+        reached.insert("b".to_string());
+        acked.insert("b".to_string());
+        assert!(!mesh_done(&sessions, &wanted, &reached, &acked));
+        assert!(!mesh_done(&sessions, &[], &reached, &acked));
+
+        // b finishing and leaving must not undo it.
+        sessions.remove("b");
+        sessions.get_mut("c").unwrap().state = SessionState::Established;
+        reached.insert("c".to_string());
+        assert!(!mesh_done(&sessions, &wanted, &reached, &acked), "c has not acknowledged our hello");
+        acked.insert("c".to_string());
+        assert!(mesh_done(&sessions, &wanted, &reached, &acked));
+        assert!(mesh_done(&sessions, &[], &reached, &acked));
+    }
+
+    #[test]
+    fn overlay_claims_come_from_the_latest_metas_of_other_clients() {
+        let mut presence = Presence::new();
+        let state = json!({
+            "a": { "metas": [{ "phx_ref": "1", "overlay_ip": "10.99.0.1" }] },
+            "b": { "metas": [
+                { "phx_ref": "2", "overlay_ip": "10.99.0.2" },
+                { "phx_ref": "3", "overlay_ip": "10.99.0.3" },
+            ] },
+            "c": { "metas": [{ "phx_ref": "4", "overlay_ip": "not an ip" }] },
+        });
+        presence.sync_state(&state, |_| {});
+        let claims = overlay_claims(&presence, "a");
+        assert_eq!(claims, [("b", "10.99.0.3".parse().unwrap())]);
+    }
+
+    #[test]
+    fn presence_changes_update_the_peer_map() {
+        let mut peers = HashMap::from([("a".to_string(), Some("10.0.0.2:1234".to_string()))]);
+
+        let left = apply_presence_change(
+            &mut peers,
+            PresenceChange::Updated {
+                key: "a".to_string(),
+                metas: vec![json!({ "phx_ref": "2" })],
+            },
+        );
+        assert_eq!(left, None);
+        assert_eq!(peers["a"].as_deref(), Some("10.0.0.2:1234"), "known endpoint survives metas without udp");
+
+        apply_presence_change(
+            &mut peers,
+            PresenceChange::Joined {
+                key: "b".to_string(),
+                metas: vec![json!({ "phx_ref": "3", "udp": "10.0.0.3:3" })],
+            },
+        );
+        assert_eq!(peers["b"].as_deref(), Some("10.0.0.3:3"));
+
+        let left = apply_presence_change(
+            &mut peers,
+            PresenceChange::Left {
+                key: "a".to_string(),
+                metas: Vec::new(),
+            },
+        );
+        assert_eq!(left.as_deref(), Some("a"));
+        assert!(!peers.contains_key("a"));
+    }
+}
@@ -0,0 +1,210 @@
+//! Machine-readable summary of a `punch` run.
+//!
+//! With `--report json` the client prints one [`RunReport`] as a single JSON line after
+//! everything else, whether the run succeeded or not, so harnesses can assert on endpoints,
+//! pairs and path quality instead of log lines. Times are milliseconds since the run started.
+
+use crate::ice::{Candidate, CandidateKind, CandidatePair};
+use crate::nat::NatBehavior;
+use crate::session::{Session, SessionTable, Via};
+use crate::stats::PathStats;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// `direct`, or `relayed` if either side of the pair is our TURN allocation or the peer's.
+pub fn path_name<B>(pair: &CandidatePair<B>) -> &'static str {
+    if pair.local.kind == CandidateKind::Relay || pair.remote.kind == CandidateKind::Relay {
+        "relayed"
+    } else {
+        "direct"
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RunReport {
+    pub client_id: String,
+    pub room: String,
+    pub ok: bool,
+    /// Why the run failed; `None` if it succeeded.
+    pub failure: Option<String>,
+    /// Our reflexive address, from STUN or as the coordinator saw our registration.
+    pub public_endpoint: Option<SocketAddr>,
+    pub nat: Option<NatBehavior>,
+    pub duration_ms: u64,
+    pub peers: Vec<PeerReport>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerReport {
+    pub peer: String,
+    /// `discovered`, `checking` or `established`.
+    pub state: String,
+    /// Whether data arrived over the peer's encrypted channel.
+    pub reached: bool,
+    pub encrypted: bool,
+    /// The peer's endpoint as the coordinator saw it.
+    pub advertised: Option<SocketAddr>,
+    /// The endpoint in use once established; differs from the pair's after a migration.
+    pub endpoint: Option<SocketAddr>,
+    pub path: Option<&'static str>,
+    /// The nominated candidate pair.
+    pub pair: Option<PairReport>,
+    pub first_contact_ms: Option<u64>,
+    pub punch_attempts: u32,
+    pub rtt: RttReport,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct PairReport {
+    pub local: Candidate,
+    pub remote: Candidate,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RttReport {
+    pub rtt_ms: Option<f64>,
+    pub rtt_var_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    /// Fraction of the peer's recent packets that were lost, 0.0 to 1.0.
+    pub loss: Option<f64>,
+    pub received: u64,
+}
+
+impl From<PathStats> for RttReport {
+    fn from(stats: PathStats) -> Self {
+        let ms = |d: Option<Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+        Self {
+            rtt_ms: ms(stats.rtt),
+            rtt_var_ms: ms(stats.rtt_var),
+            jitter_ms: ms(stats.jitter),
+            loss: stats.loss,
+            received: stats.received,
+        }
+    }
+}
+
+impl RunReport {
+    /// An empty report for a run starting now.
+    pub fn new(client_id: &str, room: &str) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            room: room.to_string(),
+            ok: false,
+            failure: None,
+            public_endpoint: None,
+            nat: None,
+            duration_ms: 0,
+            peers: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn since_start(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.started).as_millis() as u64
+    }
+
+    /// Replaces the peer list with the current sessions. `advertised` maps peers to the endpoint
+    /// the coordinator reported for them.
+    pub fn record_sessions(
+        &mut self,
+        sessions: &SessionTable,
+        reached: &HashSet<String>,
+        advertised: &HashMap<String, Option<String>>,
+    ) {
+        self.peers = sessions
+            .iter()
+            .map(|(peer, session)| self.peer(peer, session, reached.contains(peer), advertised))
+            .collect();
+    }
+
+    fn peer(
+        &self,
+        peer: &str,
+        session: &Session,
+        reached: bool,
+        advertised: &HashMap<String, Option<String>>,
+    ) -> PeerReport {
+        let pair = session.checklist.selected().filter(|_| session.is_established());
+        PeerReport {
+            peer: peer.to_string(),
+            state: session.state.to_string(),
+            reached,
+            encrypted: session.is_channel_open(),
+            advertised: advertised
+                .get(peer)
+                .and_then(|udp| udp.as_deref()?.parse().ok()),
+            endpoint: session.endpoint,
+            path: session.is_established().then(|| {
+                pair.map_or(
+                    if session.via == Via::Relay { "relayed" } else { "direct" },
+                    path_name,
+                )
+            }),
+            pair: pair.map(|pair| PairReport {
+                local: pair.local,
+                remote: pair.remote,
+            }),
+            first_contact_ms: session.first_seen.map(|at| self.since_start(at)),
+            punch_attempts: session.probes_sent(),
+            rtt: session.stats().into(),
+        }
+    }
+
+    /// Closes the report with the run's outcome.
+    pub fn finish(&mut self, outcome: &anyhow::Result<()>) {
+        self.ok = outcome.is_ok();
+        self.failure = outcome.as_ref().err().map(|e| format!("{e:#}"));
+        self.duration_ms = self.since_start(Instant::now());
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("run report serializes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunReport;
+    use crate::ice::{Candidate, CandidateKind};
+    use crate::session::{SessionState, SessionTable, Via};
+    use serde_json::Value;
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn reports_established_peers_and_the_failure() {
+        let host = Candidate::new(CandidateKind::Host, "10.0.1.2:4000".parse().unwrap());
+        let mut sessions = SessionTable::new();
+        sessions.ensure("b", || crate::session::Session::new(true, &[(host, Via::Socket(None))]));
+        sessions.ensure("c", || crate::session::Session::new(true, &[(host, Via::Socket(None))]));
+        let session = sessions.get_mut("b").unwrap();
+        session.checklist.add_remote(Candidate::new(CandidateKind::Srflx, "100.64.0.3:5000".parse().unwrap()));
+        let pair = session.checklist.select(Via::Socket(None), "100.64.0.3:5000".parse().unwrap());
+        session.state = SessionState::Established;
+        session.endpoint = Some(pair.remote.address);
+        let _ = session.probe(0, std::time::Instant::now());
+
+        let mut report = RunReport::new("a", "demo");
+        let reached = HashSet::from(["b".to_string()]);
+        let advertised = HashMap::from([("b".to_string(), Some("100.64.0.3:5000".to_string())), ("c".to_string(), None)]);
+        report.record_sessions(&sessions, &reached, &advertised);
+        report.finish(&Err(anyhow::anyhow!("a timed out waiting for udp with c")));
+
+        let json: Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["ok"], false);
+        assert_eq!(json["failure"], "a timed out waiting for udp with c");
+        let b = &json["peers"][0];
+        assert_eq!(b["peer"], "b");
+        assert_eq!(b["state"], "established");
+        assert_eq!(b["reached"], true);
+        assert_eq!(b["path"], "direct");
+        assert_eq!(b["advertised"], "100.64.0.3:5000");
+        assert_eq!(b["pair"]["remote"]["kind"], "srflx");
+        assert_eq!(b["punch_attempts"], 1);
+        let c = &json["peers"][1];
+        assert_eq!((c["state"].as_str(), &c["path"], &c["pair"]), (Some("discovered"), &Value::Null, &Value::Null));
+    }
+}
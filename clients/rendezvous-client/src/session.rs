@@ -0,0 +1,501 @@
+//! Per-peer sessions for a room.
+//!
+//! Every peer in the room gets its own session: candidate checklist, punching state and, once a
+//! pair is agreed on, the endpoint and local path to keep using. All sessions share the primary
+//! UDP socket (and the TURN allocation, if any); datagrams name their sender, so the table only
+//! has to demultiplex the extra punching sockets.
+//!
+//! Once established, a session follows the peer when its NAT rebinds or it roams: authenticated
+//! traffic from another path triggers a QUIC-style path challenge there, and the session only
+//! switches once the matching response comes back from that same path.
+//!
+//! Each session also measures its path (see [`crate::stats`]); a migration starts the estimates
+//! over.
+//!
+//! On top of an established session runs the encrypted data channel (see [`crate::noise`]): the
+//! controlling side initiates the handshake, retrying with a fresh one until it is answered.
+
+use crate::ice::{Candidate, Checklist};
+use crate::noise::{self, Initiator, StaticKeypair, Transport};
+use crate::probe::{Challenges, PairKey};
+use crate::punch::Puncher;
+use crate::stats::{PathMonitor, PathStats};
+use crate::turn::TurnClient;
+use peer_wire::{Message, PATH_DATA_LEN, Packet};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use x25519_dalek::PublicKey;
+
+/// How peer traffic reaches the peer: a local socket (`None` is the primary one, `Some(i)` the
+/// session's punching socket `i`) or our TURN allocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Via {
+    Socket(Option<usize>),
+    Relay,
+}
+
+/// Challenges sent on a candidate path before giving up on it.
+const PATH_CHALLENGE_ATTEMPTS: u32 = 5;
+/// How long an initiation waits for its response before a new handshake replaces it.
+const HANDSHAKE_RETRY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// In presence, but no endpoint or candidates yet.
+    Discovered,
+    Checking,
+    Established,
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Discovered => "discovered",
+            Self::Checking => "checking",
+            Self::Established => "established",
+        })
+    }
+}
+
+pub struct Session {
+    pub state: SessionState,
+    /// The peer endpoint in use once established.
+    pub endpoint: Option<SocketAddr>,
+    pub via: Via,
+    pub last_seen: Option<Instant>,
+    /// When the first authenticated packet from the peer arrived.
+    pub first_seen: Option<Instant>,
+    pub checklist: Checklist<Via>,
+    pub puncher: Option<Puncher>,
+    /// Pair key shared with the peer, once its presence meta published a static key. Until then
+    /// the session cannot send or accept probes.
+    pub key: Option<PairKey>,
+    pub challenges: Challenges,
+    /// The peer's static key, once its presence meta published one. Until then there is no data
+    /// channel.
+    pub static_key: Option<PublicKey>,
+    channel: Channel,
+    /// Newest handshake timestamp accepted from the peer.
+    last_initiation: u64,
+    /// Sequence number of the next packet we send.
+    sequence: u32,
+    /// A path other than the established one, being validated.
+    validating: Option<PathValidation>,
+    /// Origin of the timestamps we put in probes and keepalives.
+    epoch: Instant,
+    path: PathMonitor,
+    /// Connectivity checks sent so far, nominations and predicted-port rounds included.
+    probes: u32,
+}
+
+impl Session {
+    /// `controlling` decides who nominates; `locals` are the candidates gathered so far.
+    pub fn new<'a>(controlling: bool, locals: impl IntoIterator<Item = &'a (Candidate, Via)>) -> Self {
+        let mut checklist = Checklist::new(controlling);
+        for (candidate, base) in locals {
+            checklist.add_local(*candidate, *base);
+        }
+        Self {
+            state: SessionState::Discovered,
+            endpoint: None,
+            via: Via::Socket(None),
+            last_seen: None,
+            first_seen: None,
+            checklist,
+            puncher: None,
+            key: None,
+            challenges: Challenges::new(),
+            static_key: None,
+            channel: Channel::Closed,
+            last_initiation: 0,
+            sequence: 0,
+            validating: None,
+            epoch: Instant::now(),
+            path: PathMonitor::new(),
+            probes: 0,
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.state == SessionState::Established
+    }
+
+    /// Encodes `message` for the peer with the next sequence number; `None` until the key is known.
+    pub fn seal(&mut self, message: Message) -> Option<Vec<u8>> {
+        let key = self.key?;
+        let packet = Packet {
+            session: key.session_id(),
+            sequence: self.sequence,
+            message,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        packet.encode(key.as_bytes()).ok()
+    }
+
+    /// Authenticated, fresh traffic arrived on `(via, from)`. If that is not the established path,
+    /// starts validating it and returns the challenge to send there.
+    pub fn observe_path(&mut self, via: Via, from: SocketAddr) -> Option<Message> {
+        if !self.is_established() || (self.via == via && self.endpoint == Some(from)) {
+            return None;
+        }
+        if self.validating.as_ref().is_some_and(|v| v.via == via && v.endpoint == from) {
+            return None;
+        }
+        let data = rand::random();
+        self.validating = Some(PathValidation {
+            via,
+            endpoint: from,
+            data,
+            sent: 1,
+        });
+        Some(Message::PathChallenge { data })
+    }
+
+    pub fn is_validating_path(&self) -> bool {
+        self.validating.is_some()
+    }
+
+    /// Whether `(via, from)` is the established path or the one being validated. Path challenges
+    /// are only answered there, so a response never goes to an address the peer was not seen on.
+    pub fn is_known_path(&self, via: Via, from: SocketAddr) -> bool {
+        (self.is_established() && self.via == via && self.endpoint == Some(from))
+            || self.validating.as_ref().is_some_and(|v| v.via == via && v.endpoint == from)
+    }
+
+    /// The pending challenge again, for retransmission; the path is abandoned after a few tries.
+    pub fn path_challenge_due(&mut self) -> Option<(Via, SocketAddr, Message)> {
+        let validation = self.validating.as_mut()?;
+        if validation.sent >= PATH_CHALLENGE_ATTEMPTS {
+            self.validating = None;
+            return None;
+        }
+        validation.sent += 1;
+        let data = validation.data;
+        Some((validation.via, validation.endpoint, Message::PathChallenge { data }))
+    }
+
+    /// A path response arrived on `(via, from)`. If it answers our challenge for that path, the
+    /// session moves there; returns the endpoint it moved away from.
+    pub fn confirm_path(&mut self, via: Via, from: SocketAddr, data: [u8; PATH_DATA_LEN]) -> Option<SocketAddr> {
+        let validation = self.validating.as_ref()?;
+        if validation.via != via || validation.endpoint != from || validation.data != data {
+            return None;
+        }
+        self.validating = None;
+        let previous = self.endpoint.replace(from);
+        self.via = via;
+        self.path = PathMonitor::new();
+        previous
+    }
+
+    pub fn is_channel_open(&self) -> bool {
+        matches!(self.channel, Channel::Open(_))
+    }
+
+    /// The handshake initiation to send now, if the channel is not open and no initiation is
+    /// pending (or the pending one went unanswered for too long).
+    pub fn initiation_due(&mut self, local: &StaticKeypair, prologue: &[u8], now: Instant) -> Option<Message> {
+        let remote = self.static_key?;
+        match &self.channel {
+            Channel::Open(_) => return None,
+            Channel::Initiating { started, .. } if now.saturating_duration_since(*started) < HANDSHAKE_RETRY => {
+                return None;
+            }
+            _ => {}
+        }
+        let (initiator, payload) = Initiator::start(local, &remote, prologue);
+        self.channel = Channel::Initiating { initiator, started: now };
+        Some(Message::HandshakeInit { payload })
+    }
+
+    /// Answers a handshake initiation from the peer and opens the channel. Refused initiations
+    /// (wrong key or ids, replays) leave the channel as it was.
+    pub fn accept_initiation(&mut self, local: &StaticKeypair, prologue: &[u8], init: &[u8]) -> Option<Message> {
+        let remote = self.static_key?;
+        let accepted = noise::respond(local, &remote, prologue, init, self.last_initiation).ok()?;
+        self.last_initiation = accepted.timestamp;
+        self.channel = Channel::Open(accepted.transport);
+        Some(Message::HandshakeResponse {
+            payload: accepted.response,
+        })
+    }
+
+    /// Completes our pending handshake with the peer's response; whether the channel opened.
+    /// A response that does not match (a forgery, or one to an earlier attempt) is ignored.
+    pub fn finish_initiation(&mut self, response: &[u8]) -> bool {
+        let Channel::Initiating { initiator, .. } = &self.channel else {
+            return false;
+        };
+        match initiator.finish(response) {
+            Ok(transport) => {
+                self.channel = Channel::Open(transport);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Encrypts `payload` for the peer; `None` until the channel is open.
+    pub fn seal_data(&mut self, payload: &[u8]) -> Option<Message> {
+        let Channel::Open(transport) = &mut self.channel else {
+            return None;
+        };
+        let (counter, payload) = transport.seal(payload).ok()?;
+        Some(Message::Data { counter, payload })
+    }
+
+    /// Decrypts a data packet from the peer; `None` for forgeries, replays or a closed channel.
+    pub fn open_data(&mut self, counter: u64, payload: &[u8]) -> Option<Vec<u8>> {
+        let Channel::Open(transport) = &mut self.channel else {
+            return None;
+        };
+        transport.open(counter, payload).ok()
+    }
+
+    /// A hello for the peer, or with `ack` the answer to one; `None` until the channel is open.
+    /// Both seal an empty payload under the channel's keys, so they prove it works.
+    pub fn seal_hello(&mut self, ack: bool) -> Option<Message> {
+        let Channel::Open(transport) = &mut self.channel else {
+            return None;
+        };
+        let (counter, payload) = transport.seal(&[]).ok()?;
+        Some(if ack {
+            Message::HelloAck { counter, payload }
+        } else {
+            Message::Hello { counter, payload }
+        })
+    }
+
+    /// Whether a hello or hello-ack from the peer opens under the channel's keys.
+    pub fn open_hello(&mut self, counter: u64, payload: &[u8]) -> bool {
+        self.open_data(counter, payload).is_some_and(|payload| payload.is_empty())
+    }
+
+    /// Sends `msg` to `to` along `via`. Losses are left to the caller's retransmissions.
+    pub async fn send(&self, udp: &UdpSocket, relay: Option<&TurnClient>, via: Via, msg: &[u8], to: SocketAddr) {
+        match via {
+            Via::Socket(index) => {
+                let socket = self.puncher.as_ref().map_or(udp, |p| p.socket(udp, index));
+                let _ = socket.send_to(msg, to).await;
+            }
+            Via::Relay => {
+                if let Some(relay) = relay {
+                    let _ = relay.send_to(msg, to).await;
+                }
+            }
+        }
+    }
+
+    /// Our clock for the `timestamp` of probes and keepalives, in microseconds.
+    pub fn timestamp(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_micros() as u64
+    }
+
+    /// A connectivity check with a fresh nonce, counted towards [`Session::probes_sent`].
+    pub fn probe(&mut self, flags: u8, now: Instant) -> Message {
+        self.probes += 1;
+        let (nonce, timestamp) = (self.challenges.challenge(), self.timestamp(now));
+        Message::Probe { flags, nonce, timestamp }
+    }
+
+    pub fn probes_sent(&self) -> u32 {
+        self.probes
+    }
+
+    /// Any authenticated packet from the peer. Acks yield an RTT sample from the timestamp they
+    /// echo, requests a jitter sample; once established, sequence numbers feed the loss estimate.
+    pub fn seen(&mut self, now: Instant, packet: &Packet) {
+        self.last_seen = Some(now);
+        self.first_seen.get_or_insert(now);
+        match packet.message {
+            Message::ProbeAck { timestamp, .. } => {
+                if let Some(rtt) = self.timestamp(now).checked_sub(timestamp) {
+                    self.path.on_rtt(Duration::from_micros(rtt));
+                }
+            }
+            Message::Probe { timestamp, .. } | Message::Keepalive { timestamp, .. } => {
+                self.path.on_request(self.timestamp(now), timestamp);
+            }
+            _ => {}
+        }
+        if self.is_established() {
+            self.path.on_packet(packet.sequence);
+        }
+    }
+
+    pub fn stats(&self) -> PathStats {
+        self.path.stats()
+    }
+}
+
+enum Channel {
+    Closed,
+    Initiating { initiator: Initiator, started: Instant },
+    Open(Transport),
+}
+
+struct PathValidation {
+    via: Via,
+    endpoint: SocketAddr,
+    data: [u8; PATH_DATA_LEN],
+    sent: u32,
+}
+
+/// Sessions keyed by peer id.
+#[derive(Default)]
+pub struct SessionTable {
+    sessions: BTreeMap<String, Session>,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, peer: &str) -> Option<&Session> {
+        self.sessions.get(peer)
+    }
+
+    pub fn get_mut(&mut self, peer: &str) -> Option<&mut Session> {
+        self.sessions.get_mut(peer)
+    }
+
+    /// The peer's session, created with `new` if it has none yet.
+    pub fn ensure(&mut self, peer: &str, new: impl FnOnce() -> Session) -> &mut Session {
+        self.sessions.entry(peer.to_string()).or_insert_with(new)
+    }
+
+    /// The peer and session a packet's session id belongs to; only sessions with a key match.
+    pub fn by_session_id(&mut self, id: u64) -> Option<(&str, &mut Session)> {
+        self.sessions
+            .iter_mut()
+            .find(|(_, session)| session.key.is_some_and(|key| key.session_id() == id))
+            .map(|(peer, session)| (peer.as_str(), session))
+    }
+
+    pub fn remove(&mut self, peer: &str) -> Option<Session> {
+        self.sessions.remove(peer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Session)> {
+        self.sessions.iter().map(|(peer, session)| (peer.as_str(), session))
+    }
+
+    /// Path quality of every established session, and whether it runs direct or relayed.
+    pub fn stats(&self) -> impl Iterator<Item = (&str, Via, PathStats)> {
+        self.iter()
+            .filter(|(_, session)| session.is_established())
+            .map(|(peer, session)| (peer, session.via, session.stats()))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut Session)> {
+        self.sessions.iter_mut().map(|(peer, session)| (peer.as_str(), session))
+    }
+
+    /// Receives on the primary socket or any session's punching sockets. Datagrams from a
+    /// punching socket also report which session owns it.
+    pub async fn recv_from_any(
+        &self,
+        primary: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<String>, Via)> {
+        poll_fn(|cx| {
+            let mut read = ReadBuf::new(&mut *buf);
+            if let Poll::Ready(result) = primary.poll_recv_from(cx, &mut read) {
+                let len = read.filled().len();
+                return Poll::Ready(result.map(|from| (len, from, None, Via::Socket(None))));
+            }
+            for (peer, session) in &self.sessions {
+                if let Some(puncher) = &session.puncher
+                    && let Poll::Ready(result) = puncher.poll_recv_extra(cx, buf)
+                {
+                    return Poll::Ready(
+                        result.map(|(len, from, index)| (len, from, Some(peer.clone()), Via::Socket(Some(index)))),
+                    );
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// One line per peer: state, endpoint, path, path quality, whether the data channel is open and how
+/// long ago it was last heard from.
+impl fmt::Display for SessionTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (peer, session) in &self.sessions {
+            write!(f, "{peer} {}", session.state)?;
+            if let Some(endpoint) = session.endpoint {
+                write!(f, " {endpoint} via {:?}", session.via)?;
+            }
+            write!(f, "{}", session.stats())?;
+            if session.is_channel_open() {
+                f.write_str(" encrypted")?;
+            }
+            if let Some(seen) = session.last_seen {
+                write!(f, " seen {:?} ago", seen.elapsed())?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Session, SessionState, SessionTable, Via};
+    use crate::ice::{Candidate, CandidateKind};
+    use peer_wire::{Message, Packet};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn sessions_share_local_candidates_and_smooth_rtt() {
+        let locals = [(
+            Candidate::new(CandidateKind::Host, "10.0.0.2:5000".parse().unwrap()),
+            Via::Socket(None),
+        )];
+        let mut table = SessionTable::new();
+        table.ensure("b", || Session::new(false, &locals));
+        table.ensure("c", || Session::new(true, &locals));
+        table.ensure("b", || unreachable!("existing sessions are kept"));
+        assert_eq!(table.iter().map(|(peer, _)| peer).collect::<Vec<_>>(), ["b", "c"]);
+
+        let session = table.get_mut("c").unwrap();
+        assert_eq!(session.checklist.local_candidates().count(), 1);
+        let start = Instant::now();
+        let sent = [start, start + Duration::from_secs(1)].map(|at| session.timestamp(at));
+        let ack = |sequence, timestamp| Packet {
+            session: 0,
+            sequence,
+            message: Message::ProbeAck {
+                flags: 0,
+                nonce: [0; 16],
+                timestamp,
+            },
+        };
+        session.seen(start + Duration::from_millis(80), &ack(0, sent[0]));
+        session.seen(start + Duration::from_millis(1160), &ack(1, sent[1]));
+        assert_eq!(session.stats().rtt, Some(Duration::from_millis(90)));
+
+        session.state = SessionState::Established;
+        session.endpoint = Some("198.51.100.3:41000".parse().unwrap());
+        let table_text = table.to_string();
+        assert!(table_text.starts_with("b discovered\nc established 198.51.100.3:41000 via Socket(None) rtt 90ms"));
+        assert_eq!(table.stats().map(|(peer, _, _)| peer).collect::<Vec<_>>(), ["c"]);
+
+        assert!(table.remove("b").is_some());
+        assert!(table.get("b").is_none());
+    }
+}
@@ -0,0 +1,209 @@
+//! Path quality estimates for a session: smoothed RTT, jitter and loss.
+//!
+//! RTT samples come from the timestamps acks echo and are smoothed as in RFC 6298. Jitter is the
+//! RFC 3550 interarrival jitter of the peer's probes and keepalives, from the sender timestamps
+//! they carry. Loss is the share of the peer's last [`LOSS_WINDOW`] sequence numbers that never
+//! arrived, counted from the first packet the monitor saw.
+//!
+//! [`ClientStats`] holds the client-wide figures: the NAT binding lifetime, once discovered, and
+//! the keepalive intervals derived from it.
+
+use crate::lifetime::BindingLifetime;
+use std::fmt;
+use std::time::Duration;
+
+/// Sequence numbers the loss estimate looks back over.
+pub const LOSS_WINDOW: u32 = 64;
+
+/// A snapshot of a path's quality; `None` until there is a sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PathStats {
+    /// Smoothed round-trip time.
+    pub rtt: Option<Duration>,
+    /// Mean deviation of the RTT samples.
+    pub rtt_var: Option<Duration>,
+    pub jitter: Option<Duration>,
+    /// Fraction of the peer's recent packets that were lost, 0.0 to 1.0.
+    pub loss: Option<f64>,
+    /// Packets counted towards the loss estimate.
+    pub received: u64,
+}
+
+/// Written as ` rtt 90ms jitter 2ms loss 1.6%`, leaving out what has no sample yet.
+impl fmt::Display for PathStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(rtt) = self.rtt {
+            write!(f, " rtt {rtt:?}")?;
+        }
+        if let Some(jitter) = self.jitter {
+            write!(f, " jitter {jitter:?}")?;
+        }
+        if let Some(loss) = self.loss {
+            write!(f, " loss {:.1}%", loss * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientStats {
+    pub binding_lifetime: Option<BindingLifetime>,
+    /// Interval between keepalives on established peer paths.
+    pub keepalive: Duration,
+    /// Interval between UDP registrations with the coordinator.
+    pub coordinator_keepalive: Duration,
+}
+
+/// Written as `binding lifetime >= 40s (< 80s) keepalive 30s coordinator keepalive 30s`.
+impl fmt::Display for ClientStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.binding_lifetime {
+            Some(lifetime) => write!(f, "binding lifetime {lifetime}")?,
+            None => f.write_str("binding lifetime unknown")?,
+        }
+        write!(
+            f,
+            " keepalive {:?} coordinator keepalive {:?}",
+            self.keepalive, self.coordinator_keepalive
+        )
+    }
+}
+
+/// Feeds the estimates; the session converts wire timestamps for it.
+#[derive(Default)]
+pub struct PathMonitor {
+    srtt: Option<Duration>,
+    rtt_var: Duration,
+    /// Jitter in microseconds, and the previous packet's transit time (arrival minus sender
+    /// timestamp, on unrelated clocks, so only differences mean anything).
+    jitter: Option<f64>,
+    last_transit: Option<i64>,
+    highest: Option<u32>,
+    /// Bit `i` is set if sequence number `highest - i` arrived.
+    window: u64,
+    /// How many sequence numbers the window covers, up to `LOSS_WINDOW`.
+    span: u32,
+    received: u64,
+}
+
+impl PathMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An RTT sample from an acknowledged request.
+    pub fn on_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            Some(srtt) => {
+                self.rtt_var = (self.rtt_var * 3 + srtt.abs_diff(sample)) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+            None => {
+                self.srtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+        }
+    }
+
+    /// A request stamped `sent` by the peer arrived at `arrival`, both in microseconds.
+    pub fn on_request(&mut self, arrival: u64, sent: u64) {
+        let transit = arrival as i64 - sent as i64;
+        if let Some(last) = self.last_transit.replace(transit) {
+            let d = transit.abs_diff(last) as f64;
+            let jitter = self.jitter.unwrap_or(0.0);
+            self.jitter = Some(jitter + (d - jitter) / 16.0);
+        }
+    }
+
+    /// A packet with the peer's `sequence` number arrived. Duplicates count once.
+    pub fn on_packet(&mut self, sequence: u32) {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.window = 1;
+            self.span = 1;
+            self.received = 1;
+            return;
+        };
+        let ahead = sequence.wrapping_sub(highest) as i32;
+        if ahead > 0 {
+            let shift = ahead as u32;
+            self.window = self.window.checked_shl(shift).unwrap_or(0) | 1;
+            self.highest = Some(sequence);
+            self.span = self.span.saturating_add(shift).min(LOSS_WINDOW);
+        } else {
+            let back = ahead.unsigned_abs();
+            // Older than the window, or from before the first packet we counted.
+            if back >= self.span || self.window & (1 << back) != 0 {
+                return;
+            }
+            self.window |= 1 << back;
+        }
+        self.received += 1;
+    }
+
+    pub fn stats(&self) -> PathStats {
+        PathStats {
+            rtt: self.srtt,
+            rtt_var: self.srtt.map(|_| self.rtt_var),
+            jitter: self.jitter.map(|us| Duration::from_micros(us.round() as u64)),
+            loss: self
+                .highest
+                .map(|_| 1.0 - f64::from(self.window.count_ones()) / f64::from(self.span)),
+            received: self.received,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LOSS_WINDOW, PathMonitor};
+    use std::time::Duration;
+
+    #[test]
+    fn rtt_is_smoothed_like_tcp() {
+        let mut monitor = PathMonitor::new();
+        monitor.on_rtt(Duration::from_millis(80));
+        assert_eq!(monitor.stats().rtt_var, Some(Duration::from_millis(40)));
+        monitor.on_rtt(Duration::from_millis(160));
+        let stats = monitor.stats();
+        assert_eq!(stats.rtt, Some(Duration::from_millis(90)));
+        assert_eq!(stats.rtt_var, Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn jitter_follows_the_spread_of_transit_times() {
+        let mut monitor = PathMonitor::new();
+        // Clocks are unrelated: only the change in transit time counts.
+        monitor.on_request(5_000_000, 1_000);
+        assert_eq!(monitor.stats().jitter, None);
+        monitor.on_request(6_000_000, 1_001_000);
+        assert_eq!(monitor.stats().jitter, Some(Duration::ZERO));
+        monitor.on_request(7_016_000, 2_001_000);
+        assert_eq!(monitor.stats().jitter, Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn loss_counts_gaps_in_recent_sequence_numbers() {
+        let mut monitor = PathMonitor::new();
+        assert_eq!(monitor.stats().loss, None);
+        for sequence in [10, 11, 13, 13, 12, 14, 17] {
+            monitor.on_packet(sequence);
+        }
+        // 10..=17 is eight numbers, 15 and 16 missing; the duplicate 13 counts once.
+        let stats = monitor.stats();
+        assert_eq!(stats.loss, Some(0.25));
+        assert_eq!(stats.received, 6);
+        monitor.on_packet(9);
+        assert_eq!(monitor.stats().loss, Some(0.25), "packets from before the first are ignored");
+
+        // Old gaps age out of the window, and sequence numbers wrap.
+        let mut monitor = PathMonitor::new();
+        for sequence in u32::MAX - 2..=u32::MAX {
+            monitor.on_packet(sequence);
+        }
+        for sequence in 0..LOSS_WINDOW + 5 {
+            monitor.on_packet(sequence);
+        }
+        assert_eq!(monitor.stats().loss, Some(0.0));
+    }
+}
@@ -0,0 +1,614 @@
+//! STUN (RFC 8489, formerly RFC 5389) message codec and Binding client.
+//!
+//! Covers what endpoint discovery and the TURN client need: Binding requests/responses,
+//! (XOR-)MAPPED-ADDRESS, USERNAME, SOFTWARE, ERROR-CODE, the RFC 5780 and TURN attributes,
+//! and the MESSAGE-INTEGRITY / FINGERPRINT trailers. Any other attribute is kept as
+//! [`Attribute::Unknown`] so callers can still inspect it.
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_a442;
+pub const HEADER_LEN: usize = 20;
+
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS: u16 = 0x0101;
+pub const BINDING_ERROR: u16 = 0x0111;
+
+const CLASS_MASK: u16 = 0x0110;
+const CLASS_INDICATION: u16 = 0x0010;
+const CLASS_SUCCESS: u16 = 0x0100;
+const CLASS_ERROR: u16 = 0x0110;
+
+pub const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+pub const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+pub const ATTR_USERNAME: u16 = 0x0006;
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ATTR_ERROR_CODE: u16 = 0x0009;
+pub const ATTR_CHANNEL_NUMBER: u16 = 0x000c;
+pub const ATTR_LIFETIME: u16 = 0x000d;
+pub const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+pub const ATTR_DATA: u16 = 0x0013;
+pub const ATTR_REALM: u16 = 0x0014;
+pub const ATTR_NONCE: u16 = 0x0015;
+pub const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+pub const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const ATTR_RESPONSE_PORT: u16 = 0x0027;
+pub const ATTR_SOFTWARE: u16 = 0x8022;
+pub const ATTR_FINGERPRINT: u16 = 0x8028;
+pub const ATTR_RESPONSE_ORIGIN: u16 = 0x802b;
+pub const ATTR_OTHER_ADDRESS: u16 = 0x802c;
+
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+const INTEGRITY_LEN: usize = 20;
+
+pub type TransactionId = [u8; 12];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    MappedAddress(SocketAddr),
+    XorMappedAddress(SocketAddr),
+    Username(String),
+    Software(String),
+    ErrorCode { code: u16, reason: String },
+    /// Long-term credential realm and nonce (RFC 8489 section 9.2).
+    Realm(String),
+    Nonce(String),
+    /// TURN (RFC 8656) attributes.
+    ChannelNumber(u16),
+    Lifetime(u32),
+    XorPeerAddress(SocketAddr),
+    XorRelayedAddress(SocketAddr),
+    Data(Vec<u8>),
+    /// IANA protocol number; 17 is UDP.
+    RequestedTransport(u8),
+    /// RFC 5780: ask the server to answer from its alternate IP and/or port.
+    ChangeRequest { change_ip: bool, change_port: bool },
+    /// RFC 5780: the server's alternate address (alternate IP and alternate port).
+    OtherAddress(SocketAddr),
+    /// RFC 5780: the address the response was sent from.
+    ResponseOrigin(SocketAddr),
+    /// RFC 5780: ask the server to answer to this port (at the request's source IP).
+    ResponsePort(u16),
+    /// Only produced by [`Message::decode`]; use [`Message::encode`]'s `integrity_key` to add one.
+    MessageIntegrity([u8; INTEGRITY_LEN]),
+    /// Only produced by [`Message::decode`], after the CRC has been checked.
+    Fingerprint(u32),
+    Unknown { kind: u16, value: Vec<u8> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// Combined method and class, e.g. [`BINDING_REQUEST`].
+    pub kind: u16,
+    pub transaction_id: TransactionId,
+    pub attributes: Vec<Attribute>,
+}
+
+impl Message {
+    pub fn new(kind: u16, transaction_id: TransactionId) -> Self {
+        Self {
+            kind,
+            transaction_id,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// A request with a fresh random transaction id.
+    pub fn request(kind: u16) -> Self {
+        Self::new(kind, rand::random())
+    }
+
+    pub fn binding_request() -> Self {
+        Self::request(BINDING_REQUEST)
+    }
+
+    pub fn is_indication(&self) -> bool {
+        self.kind & CLASS_MASK == CLASS_INDICATION
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.kind & CLASS_MASK == CLASS_SUCCESS
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.kind & CLASS_MASK == CLASS_ERROR
+    }
+
+    /// Turns an error response into an error carrying its ERROR-CODE.
+    pub fn into_success(self) -> Result<Self> {
+        if self.is_error() {
+            let (code, reason) = self.error_code().unwrap_or((0, ""));
+            bail!("stun request {:#06x} rejected: {code} {reason}", self.kind & !CLASS_MASK);
+        }
+        ensure!(self.is_success(), "unexpected stun message type {:#06x}", self.kind);
+        Ok(self)
+    }
+
+    /// First attribute `pick` accepts.
+    pub fn find<T>(&self, pick: impl FnMut(&Attribute) -> Option<T>) -> Option<T> {
+        self.attributes.iter().find_map(pick)
+    }
+
+    pub fn with(mut self, attribute: Attribute) -> Self {
+        self.attributes.push(attribute);
+        self
+    }
+
+    /// XOR-MAPPED-ADDRESS, falling back to the legacy MAPPED-ADDRESS of RFC 3489 servers.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        let xor = self.attributes.iter().find_map(|attr| match attr {
+            Attribute::XorMappedAddress(addr) => Some(*addr),
+            _ => None,
+        });
+        xor.or_else(|| {
+            self.attributes.iter().find_map(|attr| match attr {
+                Attribute::MappedAddress(addr) => Some(*addr),
+                _ => None,
+            })
+        })
+    }
+
+    pub fn other_address(&self) -> Option<SocketAddr> {
+        self.attributes.iter().find_map(|attr| match attr {
+            Attribute::OtherAddress(addr) => Some(*addr),
+            _ => None,
+        })
+    }
+
+    pub fn error_code(&self) -> Option<(u16, &str)> {
+        self.attributes.iter().find_map(|attr| match attr {
+            Attribute::ErrorCode { code, reason } => Some((*code, reason.as_str())),
+            _ => None,
+        })
+    }
+
+    /// Serializes the message. MESSAGE-INTEGRITY (HMAC-SHA1 with `integrity_key`) and
+    /// FINGERPRINT are appended last, in that order, as the RFC requires.
+    pub fn encode(&self, integrity_key: Option<&[u8]>, fingerprint: bool) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&self.kind.to_be_bytes());
+        buf.extend_from_slice(&0_u16.to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction_id);
+
+        for attr in &self.attributes {
+            let (kind, value) = match attr {
+                Attribute::MappedAddress(addr) => (ATTR_MAPPED_ADDRESS, encode_address(*addr, None)),
+                Attribute::XorMappedAddress(addr) => {
+                    (ATTR_XOR_MAPPED_ADDRESS, encode_address(*addr, Some(&self.transaction_id)))
+                }
+                Attribute::Username(name) => (ATTR_USERNAME, name.as_bytes().to_vec()),
+                Attribute::Software(name) => (ATTR_SOFTWARE, name.as_bytes().to_vec()),
+                Attribute::ErrorCode { code, reason } => {
+                    let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+                    value.extend_from_slice(reason.as_bytes());
+                    (ATTR_ERROR_CODE, value)
+                }
+                Attribute::Realm(realm) => (ATTR_REALM, realm.as_bytes().to_vec()),
+                Attribute::Nonce(nonce) => (ATTR_NONCE, nonce.as_bytes().to_vec()),
+                Attribute::ChannelNumber(number) => {
+                    let mut value = number.to_be_bytes().to_vec();
+                    value.extend_from_slice(&[0, 0]);
+                    (ATTR_CHANNEL_NUMBER, value)
+                }
+                Attribute::Lifetime(secs) => (ATTR_LIFETIME, secs.to_be_bytes().to_vec()),
+                Attribute::XorPeerAddress(addr) => {
+                    (ATTR_XOR_PEER_ADDRESS, encode_address(*addr, Some(&self.transaction_id)))
+                }
+                Attribute::XorRelayedAddress(addr) => {
+                    (ATTR_XOR_RELAYED_ADDRESS, encode_address(*addr, Some(&self.transaction_id)))
+                }
+                Attribute::Data(data) => (ATTR_DATA, data.clone()),
+                Attribute::RequestedTransport(protocol) => (ATTR_REQUESTED_TRANSPORT, vec![*protocol, 0, 0, 0]),
+                Attribute::ChangeRequest { change_ip, change_port } => {
+                    let flags = (u32::from(*change_ip) << 2) | (u32::from(*change_port) << 1);
+                    (ATTR_CHANGE_REQUEST, flags.to_be_bytes().to_vec())
+                }
+                Attribute::OtherAddress(addr) => (ATTR_OTHER_ADDRESS, encode_address(*addr, None)),
+                Attribute::ResponseOrigin(addr) => (ATTR_RESPONSE_ORIGIN, encode_address(*addr, None)),
+                Attribute::ResponsePort(port) => {
+                    let mut value = port.to_be_bytes().to_vec();
+                    value.extend_from_slice(&[0, 0]);
+                    (ATTR_RESPONSE_PORT, value)
+                }
+                // Trailers are computed below, never copied.
+                Attribute::MessageIntegrity(_) | Attribute::Fingerprint(_) => continue,
+                Attribute::Unknown { kind, value } => (*kind, value.clone()),
+            };
+            put_attribute(&mut buf, kind, &value);
+        }
+
+        if let Some(key) = integrity_key {
+            let length = buf.len() - HEADER_LEN + 4 + INTEGRITY_LEN;
+            set_length(&mut buf, length);
+            let mac = hmac_sha1(key, &buf);
+            put_attribute(&mut buf, ATTR_MESSAGE_INTEGRITY, &mac);
+        }
+        if fingerprint {
+            let length = buf.len() - HEADER_LEN + 8;
+            set_length(&mut buf, length);
+            let crc = crc32fast::hash(&buf) ^ FINGERPRINT_XOR;
+            put_attribute(&mut buf, ATTR_FINGERPRINT, &crc.to_be_bytes());
+        }
+        let length = buf.len() - HEADER_LEN;
+        set_length(&mut buf, length);
+        buf
+    }
+
+    /// Parses a message, rejecting it if a FINGERPRINT is present and doesn't match.
+    ///
+    /// MESSAGE-INTEGRITY needs the key, so it is checked separately with [`verify_integrity`].
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        ensure!(buf.len() >= HEADER_LEN, "stun message shorter than its header");
+        let kind = u16::from_be_bytes([buf[0], buf[1]]);
+        ensure!(kind & 0xc000 == 0, "not a stun message (leading bits set)");
+        let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        ensure!(
+            u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) == MAGIC_COOKIE,
+            "not a stun message (bad magic cookie)"
+        );
+        ensure!(
+            length.is_multiple_of(4) && HEADER_LEN + length == buf.len(),
+            "stun length {length} does not match {} byte datagram",
+            buf.len()
+        );
+        let mut transaction_id = [0_u8; 12];
+        transaction_id.copy_from_slice(&buf[8..HEADER_LEN]);
+
+        let mut message = Self::new(kind, transaction_id);
+        for (offset, attr_kind, value) in attributes(buf)? {
+            let attribute = match attr_kind {
+                ATTR_MAPPED_ADDRESS => Attribute::MappedAddress(decode_address(value, None)?),
+                ATTR_XOR_MAPPED_ADDRESS => Attribute::XorMappedAddress(decode_address(value, Some(&transaction_id))?),
+                ATTR_USERNAME => Attribute::Username(utf8(value, "USERNAME")?),
+                ATTR_SOFTWARE => Attribute::Software(utf8(value, "SOFTWARE")?),
+                ATTR_ERROR_CODE => {
+                    ensure!(value.len() >= 4, "truncated ERROR-CODE");
+                    Attribute::ErrorCode {
+                        code: u16::from(value[2] & 0x07) * 100 + u16::from(value[3]),
+                        reason: utf8(&value[4..], "ERROR-CODE reason")?,
+                    }
+                }
+                ATTR_REALM => Attribute::Realm(utf8(value, "REALM")?),
+                ATTR_NONCE => Attribute::Nonce(utf8(value, "NONCE")?),
+                ATTR_CHANNEL_NUMBER => {
+                    ensure!(value.len() == 4, "CHANNEL-NUMBER must be 4 bytes");
+                    Attribute::ChannelNumber(u16::from_be_bytes([value[0], value[1]]))
+                }
+                ATTR_LIFETIME => Attribute::Lifetime(u32::from_be_bytes(
+                    value.try_into().map_err(|_| anyhow!("LIFETIME must be 4 bytes"))?,
+                )),
+                ATTR_XOR_PEER_ADDRESS => Attribute::XorPeerAddress(decode_address(value, Some(&transaction_id))?),
+                ATTR_XOR_RELAYED_ADDRESS => {
+                    Attribute::XorRelayedAddress(decode_address(value, Some(&transaction_id))?)
+                }
+                ATTR_DATA => Attribute::Data(value.to_vec()),
+                ATTR_REQUESTED_TRANSPORT => {
+                    ensure!(value.len() == 4, "REQUESTED-TRANSPORT must be 4 bytes");
+                    Attribute::RequestedTransport(value[0])
+                }
+                ATTR_CHANGE_REQUEST => {
+                    let flags = u32::from_be_bytes(value.try_into().map_err(|_| anyhow!("CHANGE-REQUEST must be 4 bytes"))?);
+                    Attribute::ChangeRequest {
+                        change_ip: flags & 0x04 != 0,
+                        change_port: flags & 0x02 != 0,
+                    }
+                }
+                ATTR_OTHER_ADDRESS => Attribute::OtherAddress(decode_address(value, None)?),
+                ATTR_RESPONSE_ORIGIN => Attribute::ResponseOrigin(decode_address(value, None)?),
+                ATTR_RESPONSE_PORT => {
+                    ensure!(value.len() == 4, "RESPONSE-PORT must be 4 bytes");
+                    Attribute::ResponsePort(u16::from_be_bytes([value[0], value[1]]))
+                }
+                ATTR_MESSAGE_INTEGRITY => Attribute::MessageIntegrity(
+                    value.try_into().map_err(|_| anyhow!("MESSAGE-INTEGRITY must be 20 bytes"))?,
+                ),
+                ATTR_FINGERPRINT => {
+                    let crc = u32::from_be_bytes(value.try_into().map_err(|_| anyhow!("FINGERPRINT must be 4 bytes"))?);
+                    ensure!(
+                        crc == crc32fast::hash(&buf[..offset]) ^ FINGERPRINT_XOR,
+                        "stun FINGERPRINT mismatch"
+                    );
+                    message.attributes.push(Attribute::Fingerprint(crc));
+                    // Nothing may follow the fingerprint.
+                    break;
+                }
+                _ => Attribute::Unknown {
+                    kind: attr_kind,
+                    value: value.to_vec(),
+                },
+            };
+            message.attributes.push(attribute);
+        }
+        Ok(message)
+    }
+}
+
+/// Checks the MESSAGE-INTEGRITY of a raw message against `key` (the password for short-term
+/// credentials). Fails if the attribute is missing or the HMAC doesn't match.
+pub fn verify_integrity(buf: &[u8], key: &[u8]) -> Result<()> {
+    let (offset, _, value) = attributes(buf)?
+        .into_iter()
+        .find(|(_, kind, _)| *kind == ATTR_MESSAGE_INTEGRITY)
+        .context("stun message has no MESSAGE-INTEGRITY")?;
+    // The HMAC covers everything before the attribute, with the length field pointing at its end.
+    let mut signed = buf[..offset].to_vec();
+    set_length(&mut signed, offset - HEADER_LEN + 4 + INTEGRITY_LEN);
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&signed);
+    mac.verify_slice(value)
+        .map_err(|_| anyhow!("stun MESSAGE-INTEGRITY mismatch"))
+}
+
+/// Walks the attribute list, yielding `(offset of attribute header, type, value)`.
+fn attributes(buf: &[u8]) -> Result<Vec<(usize, u16, &[u8])>> {
+    let mut out = Vec::new();
+    let mut offset = HEADER_LEN;
+    while offset < buf.len() {
+        ensure!(offset + 4 <= buf.len(), "truncated stun attribute header");
+        let kind = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+        let len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+        let start = offset + 4;
+        ensure!(start + len <= buf.len(), "stun attribute {kind:#06x} overruns the message");
+        out.push((offset, kind, &buf[start..start + len]));
+        offset = start + len.next_multiple_of(4);
+    }
+    Ok(out)
+}
+
+fn put_attribute(buf: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn set_length(buf: &mut [u8], length: usize) {
+    buf[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; INTEGRITY_LEN] {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Address attribute value; `xor` holds the transaction id for the XOR-ed variant.
+fn encode_address(addr: SocketAddr, xor: Option<&TransactionId>) -> Vec<u8> {
+    let mask = xor_mask(xor);
+    let port = addr.port() ^ if xor.is_some() { (MAGIC_COOKIE >> 16) as u16 } else { 0 };
+    let (family, octets): (u8, Vec<u8>) = match addr.ip() {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(octets.iter().zip(mask).map(|(b, m)| b ^ m));
+    value
+}
+
+fn decode_address(value: &[u8], xor: Option<&TransactionId>) -> Result<SocketAddr> {
+    ensure!(value.len() >= 4, "truncated address attribute");
+    let mask = xor_mask(xor);
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ if xor.is_some() { (MAGIC_COOKIE >> 16) as u16 } else { 0 };
+    let raw: Vec<u8> = value[4..].iter().zip(mask).map(|(b, m)| b ^ m).collect();
+    let ip = match (value[1], raw.len()) {
+        (0x01, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(raw.as_slice())?)),
+        (0x02, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(raw.as_slice())?)),
+        (family, len) => bail!("unsupported address family {family:#04x} with {len} address bytes"),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Magic cookie followed by the transaction id (XOR variant) or all zeroes.
+fn xor_mask(xor: Option<&TransactionId>) -> [u8; 16] {
+    let mut mask = [0_u8; 16];
+    if let Some(transaction_id) = xor {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction_id);
+    }
+    mask
+}
+
+fn utf8(value: &[u8], what: &str) -> Result<String> {
+    String::from_utf8(value.to_vec()).with_context(|| format!("{what} is not valid utf-8"))
+}
+
+/// How a Binding request is sent and what the response must carry.
+#[derive(Clone, Debug)]
+pub struct BindingOptions {
+    /// Initial retransmission timeout; doubled after every unanswered attempt.
+    pub rto: Duration,
+    /// Total number of transmissions (RFC 8489's `Rc`).
+    pub attempts: u32,
+    /// Short-term credentials: USERNAME is sent, and both directions carry MESSAGE-INTEGRITY.
+    pub credentials: Option<(String, Vec<u8>)>,
+    pub fingerprint: bool,
+}
+
+impl Default for BindingOptions {
+    fn default() -> Self {
+        Self {
+            rto: Duration::from_millis(500),
+            attempts: 7,
+            credentials: None,
+            fingerprint: true,
+        }
+    }
+}
+
+/// Sends Binding requests to `server` until a matching response arrives and returns the
+/// reflexive (public) address the server saw.
+///
+/// Datagrams that aren't a response to this transaction are discarded, so don't run this on
+/// a socket that is already carrying peer traffic.
+pub async fn binding_request(socket: &UdpSocket, server: SocketAddr, options: &BindingOptions) -> Result<SocketAddr> {
+    let response = transact(socket, server, Message::binding_request(), options)
+        .await?
+        .with_context(|| format!("no stun binding response from {server}"))?;
+    response
+        .mapped_address()
+        .context("stun binding response carries no mapped address")
+}
+
+/// Runs one request/response transaction, retransmitting per `options`.
+///
+/// Returns `Ok(None)` if every attempt went unanswered, which RFC 5780 probes treat as a
+/// result rather than an error. Responses are matched by transaction id alone because
+/// CHANGE-REQUEST answers legitimately come from a different server address. Error
+/// responses are turned into errors.
+pub async fn transact(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    mut request: Message,
+    options: &BindingOptions,
+) -> Result<Option<Message>> {
+    let key = options.credentials.as_ref().map(|(username, key)| {
+        request.attributes.push(Attribute::Username(username.clone()));
+        key.as_slice()
+    });
+    match exchange(socket, server, &request, key, options).await? {
+        Some(response) => response.into_success().map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Lower-level [`transact`]: returns success and error responses alike.
+///
+/// `request` is sent as is (plus MESSAGE-INTEGRITY when `key` is set; `options.credentials`
+/// is ignored). With a key, success responses must carry a valid MESSAGE-INTEGRITY and error
+/// responses are checked if they carry one: servers answer 401/438 without it.
+pub async fn exchange(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    request: &Message,
+    key: Option<&[u8]>,
+    options: &BindingOptions,
+) -> Result<Option<Message>> {
+    let packet = request.encode(key, options.fingerprint);
+
+    let mut rto = options.rto;
+    let mut buf = vec![0_u8; 1500];
+    for _ in 0..options.attempts.max(1) {
+        socket
+            .send_to(&packet, server)
+            .await
+            .context("failed to send stun request")?;
+        let deadline = Instant::now() + rto;
+        while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (n, _) = received.context("stun recv_from failed")?;
+            let Ok(response) = Message::decode(&buf[..n]) else {
+                continue;
+            };
+            if response.transaction_id != request.transaction_id || response.is_indication() {
+                continue;
+            }
+            verify_response(&buf[..n], &response, key)?;
+            return Ok(Some(response));
+        }
+        rto *= 2;
+    }
+    Ok(None)
+}
+
+/// Checks `response` (decoded from `buf`) to a request signed with `key`: success responses
+/// must carry a valid MESSAGE-INTEGRITY, error responses only if they carry one at all.
+pub fn verify_response(buf: &[u8], response: &Message, key: Option<&[u8]>) -> Result<()> {
+    let signed = response
+        .attributes
+        .iter()
+        .any(|attr| matches!(attr, Attribute::MessageIntegrity(_)));
+    match key {
+        Some(key) if response.is_success() || signed => verify_integrity(buf, key),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Attribute, BINDING_REQUEST, BINDING_SUCCESS, Message, verify_integrity};
+    use std::net::SocketAddr;
+
+    const TRANSACTION_ID: [u8; 12] = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+
+    // RFC 5769 section 2.1: request with USERNAME, MESSAGE-INTEGRITY and FINGERPRINT.
+    const SAMPLE_REQUEST: &[u8] = &[
+        0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87,
+        0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74, 0x65, 0x73, 0x74, 0x20, 0x63, 0x6c,
+        0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e, 0x00, 0x01, 0xff, 0x80, 0x29, 0x00, 0x08, 0x93, 0x2f,
+        0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36, 0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76,
+        0x59, 0x20, 0x20, 0x20, 0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e,
+        0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5, 0x7a, 0x3b, 0xcf,
+    ];
+
+    // RFC 5769 section 2.2: IPv4 response with XOR-MAPPED-ADDRESS 192.0.2.1:32853.
+    const SAMPLE_RESPONSE: &[u8] = &[
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87,
+        0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20,
+        0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91,
+        0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7,
+        0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+    #[test]
+    fn decodes_rfc5769_sample_request() {
+        let message = Message::decode(SAMPLE_REQUEST).expect("sample request should decode");
+        assert_eq!(message.kind, BINDING_REQUEST);
+        assert_eq!(message.transaction_id, TRANSACTION_ID);
+        assert!(message.attributes.contains(&Attribute::Username("evtj:h6vY".to_string())));
+        assert!(matches!(message.attributes.last(), Some(Attribute::Fingerprint(_))));
+        verify_integrity(SAMPLE_REQUEST, PASSWORD).expect("integrity should verify");
+        assert!(verify_integrity(SAMPLE_REQUEST, b"wrong").is_err());
+    }
+
+    #[test]
+    fn decodes_rfc5769_sample_response() {
+        let message = Message::decode(SAMPLE_RESPONSE).expect("sample response should decode");
+        assert_eq!(message.kind, BINDING_SUCCESS);
+        assert_eq!(message.mapped_address(), Some("192.0.2.1:32853".parse().unwrap()));
+        verify_integrity(SAMPLE_RESPONSE, PASSWORD).expect("integrity should verify");
+    }
+
+    #[test]
+    fn encoded_trailers_verify() {
+        let message = Message::new(BINDING_SUCCESS, TRANSACTION_ID)
+            .with(Attribute::Software("test vector".to_string()))
+            .with(Attribute::XorMappedAddress("192.0.2.1:32853".parse().unwrap()));
+        let encoded = message.encode(Some(PASSWORD), true);
+        // Everything up to the HMAC matches the RFC vector except the SOFTWARE padding byte.
+        assert_eq!(&encoded[..35], &SAMPLE_RESPONSE[..35]);
+        assert_eq!(&encoded[36..48], &SAMPLE_RESPONSE[36..48]);
+
+        verify_integrity(&encoded, PASSWORD).expect("integrity should verify");
+        let decoded = Message::decode(&encoded).expect("fingerprint should verify");
+        assert_eq!(decoded.mapped_address(), message.mapped_address());
+    }
+
+    #[test]
+    fn round_trips_ipv6_and_rejects_corrupted_fingerprint() {
+        let addr: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap();
+        let message = Message::new(BINDING_SUCCESS, TRANSACTION_ID)
+            .with(Attribute::XorMappedAddress(addr))
+            .with(Attribute::ErrorCode {
+                code: 420,
+                reason: "Unknown Attribute".to_string(),
+            });
+        let mut encoded = message.encode(None, true);
+        let decoded = Message::decode(&encoded).expect("message should decode");
+        assert_eq!(decoded.mapped_address(), Some(addr));
+        assert_eq!(decoded.error_code(), Some((420, "Unknown Attribute")));
+
+        // Flip a bit in the XOR-ed port.
+        encoded[26] ^= 1;
+        assert!(Message::decode(&encoded).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Linux TUN device for the client's VPN mode.
+//!
+//! The device is opened through `/dev/net/tun` without packet information, so every read and
+//! write is one bare IPv4 or IPv6 packet. Addresses and link state are set with `ip`, as the
+//! natlab scripts do. Elsewhere [`Tun::create`] fails and the client runs without a device.
+
+use crate::overlay::OverlayAddress;
+use anyhow::{Context, Result, bail};
+use std::process::Command;
+
+/// Leaves room for the peer-wire header and tags and the outer UDP/IP headers, so tunneled
+/// packets fit a 1500-byte path.
+pub const MTU: u16 = 1400;
+
+/// Sets `address` on the device and brings it up with [`MTU`].
+pub fn configure(name: &str, address: OverlayAddress) -> Result<()> {
+    run_ip(&["addr", "add", &address.to_string(), "dev", name])?;
+    run_ip(&["link", "set", name, "mtu", &MTU.to_string(), "up"])
+}
+
+/// Moves the device from `old` to `new`, after the overlay address was lost to another client.
+pub fn readdress(name: &str, old: OverlayAddress, new: OverlayAddress) -> Result<()> {
+    // Deleting a primary IPv4 address also deletes the secondaries in its subnet, so `new`
+    // cannot be added first.
+    run_ip(&["addr", "del", &old.to_string(), "dev", name])?;
+    run_ip(&["addr", "add", &new.to_string(), "dev", name])
+}
+
+fn run_ip(args: &[&str]) -> Result<()> {
+    let status = Command::new("ip")
+        .args(args)
+        .status()
+        .context("failed to run ip")?;
+    if !status.success() {
+        bail!("ip command failed: ip {}", args.join(" "));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::Tun;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use anyhow::{Context, Result, ensure};
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+    use tokio::io::Interest;
+    use tokio::io::unix::AsyncFd;
+
+    pub struct Tun {
+        file: AsyncFd<File>,
+        name: String,
+    }
+
+    impl Tun {
+        /// Opens (creating if needed) the TUN device `name`. Needs CAP_NET_ADMIN.
+        pub fn create(name: &str) -> Result<Self> {
+            ensure!(
+                !name.is_empty() && name.len() < libc::IFNAMSIZ,
+                "tun device name {name:?} must be 1 to {} bytes",
+                libc::IFNAMSIZ - 1
+            );
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open("/dev/net/tun")
+                .context("failed to open /dev/net/tun")?;
+
+            // SAFETY: `ifreq` is plain data, and TUNSETIFF only reads the name and flags we set.
+            let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+            for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+                *dst = src as libc::c_char;
+            }
+            request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+            // SAFETY: the fd is open and `request` outlives the call.
+            if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &request) } < 0 {
+                return Err(io::Error::last_os_error()).with_context(|| format!("failed to create tun device {name}"));
+            }
+
+            // SAFETY: the `File` owns the fd and moves into the `AsyncFd`, so the fd stays open and
+            // the same for as long as the registration.
+            let file = unsafe { AsyncFd::register(file) }
+                .map_err(io::Error::from)
+                .context("failed to register tun device")?;
+            Ok(Self {
+                file,
+                name: name.to_string(),
+            })
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// Reads the next packet the kernel routed into the device.
+        pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.file
+                .async_io(Interest::READABLE, |mut file| file.read(buf))
+                .await
+        }
+
+        /// Hands `packet` to the kernel as if it arrived on the device.
+        pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+            self.file
+                .async_io(Interest::WRITABLE, |mut file| file.write(packet))
+                .await
+                .map(|_| ())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use other::Tun;
+
+#[cfg(not(target_os = "linux"))]
+mod other {
+    use anyhow::{Result, bail};
+    use std::io;
+
+    pub struct Tun {
+        name: String,
+    }
+
+    impl Tun {
+        pub fn create(name: &str) -> Result<Self> {
+            bail!("tun device {name}: TUN mode is only supported on Linux")
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        pub async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        pub async fn send(&self, _packet: &[u8]) -> io::Result<()> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
@@ -0,0 +1,533 @@
+//! TURN (RFC 8656) client over UDP, used as the relay path when direct punching fails.
+//!
+//! The client allocates a relayed address on the server, installs permissions for the peer's
+//! IP and binds a channel to the peer so data goes out as 4-byte-header ChannelData instead
+//! of Send indications. Requests use long-term credentials: the first Allocate is rejected
+//! with 401 and a realm/nonce, and everything after that is signed with
+//! `MD5(username:realm:password)`.
+
+use crate::stun::{Attribute, BindingOptions, Message, TransactionId, verify_response};
+use anyhow::{Context, Result, bail, ensure};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+pub const ALLOCATE: u16 = 0x0003;
+pub const REFRESH: u16 = 0x0004;
+pub const SEND_INDICATION: u16 = 0x0016;
+pub const DATA_INDICATION: u16 = 0x0017;
+pub const CREATE_PERMISSION: u16 = 0x0008;
+pub const CHANNEL_BIND: u16 = 0x0009;
+
+/// Channel numbers a client may bind (RFC 8656 section 12).
+pub const CHANNEL_MIN: u16 = 0x4000;
+pub const CHANNEL_MAX: u16 = 0x4fff;
+
+const UDP: u8 = 17;
+/// Permissions expire after five minutes; refresh well before that.
+const PERMISSION_REFRESH: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Debug)]
+pub struct TurnConfig {
+    pub server: SocketAddr,
+    pub username: String,
+    pub password: String,
+    /// Requested allocation lifetime; the server may grant less.
+    pub lifetime: Duration,
+    /// Retransmission schedule for TURN requests.
+    pub options: BindingOptions,
+}
+
+impl TurnConfig {
+    pub fn new(server: SocketAddr, username: &str, password: &str) -> Self {
+        Self {
+            server,
+            username: username.to_string(),
+            password: password.to_string(),
+            lifetime: Duration::from_secs(600),
+            options: BindingOptions {
+                attempts: 4,
+                ..BindingOptions::default()
+            },
+        }
+    }
+}
+
+/// An allocation on a TURN server. Owns the socket it talks to the server on.
+///
+/// Clones share the allocation, so a refresh or permission request can run on one clone while
+/// another keeps relaying: a reader task hands the server's responses to the request waiting
+/// for them and queues everything else for [`TurnClient::recv_from`].
+#[derive(Clone)]
+pub struct TurnClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    link: ServerLink,
+    config: TurnConfig,
+    realm: String,
+    key: Vec<u8>,
+    relayed: SocketAddr,
+    mapped: Option<SocketAddr>,
+    state: Mutex<State>,
+}
+
+/// What the allocation's requests update as it lives on.
+struct State {
+    nonce: String,
+    lifetime: Duration,
+    refreshed: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<SocketAddr, u16>,
+    peers_by_channel: HashMap<u16, SocketAddr>,
+    /// Taken when a bind starts, so binds in flight side by side never share a number.
+    next_channel: u16,
+}
+
+impl State {
+    fn note_lifetime(&mut self, response: &Message) {
+        if let Some(secs) = response.find(|attr| match attr {
+            Attribute::Lifetime(secs) => Some(*secs),
+            _ => None,
+        }) {
+            self.lifetime = Duration::from_secs(u64::from(secs));
+        }
+        self.refreshed = Instant::now();
+    }
+}
+
+impl TurnClient {
+    /// Allocates a UDP relay, answering the server's 401 challenge with `config`'s credentials.
+    pub async fn allocate(socket: UdpSocket, config: TurnConfig) -> Result<Self> {
+        let link = ServerLink::spawn(socket, config.server);
+        let lifetime_secs = config.lifetime.as_secs() as u32;
+        let probe = Message::request(ALLOCATE)
+            .with(Attribute::RequestedTransport(UDP))
+            .with(Attribute::Lifetime(lifetime_secs));
+        let challenge = link
+            .exchange(&probe, None, &config.options)
+            .await?
+            .with_context(|| format!("no answer from turn server {}", config.server))?;
+        ensure!(
+            challenge.error_code().map(|(code, _)| code) == Some(401),
+            "turn server {} did not ask for credentials",
+            config.server
+        );
+        let realm = challenge
+            .find(|attr| match attr {
+                Attribute::Realm(realm) => Some(realm.clone()),
+                _ => None,
+            })
+            .context("turn 401 carries no REALM")?;
+        let nonce = nonce_of(&challenge).context("turn 401 carries no NONCE")?;
+        let key = long_term_key(&config.username, &realm, &config.password);
+
+        let mut inner = Inner {
+            link,
+            realm,
+            key,
+            relayed: config.server,
+            mapped: None,
+            state: Mutex::new(State {
+                nonce,
+                lifetime: config.lifetime,
+                refreshed: Instant::now(),
+                permissions: HashMap::new(),
+                channels: HashMap::new(),
+                peers_by_channel: HashMap::new(),
+                next_channel: CHANNEL_MIN,
+            }),
+            config,
+        };
+        let allocated = inner
+            .request(
+                Message::request(ALLOCATE)
+                    .with(Attribute::RequestedTransport(UDP))
+                    .with(Attribute::Lifetime(lifetime_secs)),
+            )
+            .await
+            .context("turn allocate failed")?;
+        inner.relayed = allocated
+            .find(|attr| match attr {
+                Attribute::XorRelayedAddress(addr) => Some(*addr),
+                _ => None,
+            })
+            .context("turn allocate response carries no XOR-RELAYED-ADDRESS")?;
+        inner.mapped = allocated.mapped_address();
+        inner.state().note_lifetime(&allocated);
+        Ok(Self { inner: Arc::new(inner) })
+    }
+
+    /// The address peers send to in order to reach us through the relay.
+    pub fn relayed_address(&self) -> SocketAddr {
+        self.inner.relayed
+    }
+
+    /// Our reflexive address as the TURN server saw it, if it reported one.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        self.inner.mapped
+    }
+
+    /// Lets datagrams from `peer` (any port) through the relay.
+    pub async fn create_permission(&self, peer: IpAddr) -> Result<()> {
+        self.inner
+            .request(Message::request(CREATE_PERMISSION).with(Attribute::XorPeerAddress(SocketAddr::new(peer, 0))))
+            .await
+            .with_context(|| format!("turn create-permission for {peer} failed"))?;
+        self.inner.state().permissions.insert(peer, Instant::now());
+        Ok(())
+    }
+
+    /// Binds a channel to `peer` (which also installs its permission) and returns its number.
+    pub async fn channel_bind(&self, peer: SocketAddr) -> Result<u16> {
+        let number = {
+            let mut state = self.inner.state();
+            match state.channels.get(&peer) {
+                Some(number) => *number,
+                None => {
+                    let next = state.next_channel;
+                    ensure!(next <= CHANNEL_MAX, "turn channel numbers exhausted");
+                    state.next_channel += 1;
+                    next
+                }
+            }
+        };
+        self.inner
+            .request(
+                Message::request(CHANNEL_BIND)
+                    .with(Attribute::ChannelNumber(number))
+                    .with(Attribute::XorPeerAddress(peer)),
+            )
+            .await
+            .with_context(|| format!("turn channel-bind to {peer} failed"))?;
+        let mut state = self.inner.state();
+        state.channels.insert(peer, number);
+        state.peers_by_channel.insert(number, peer);
+        state.permissions.insert(peer.ip(), Instant::now());
+        Ok(number)
+    }
+
+    /// Relays `data` to `peer`: ChannelData if a channel is bound, otherwise a Send indication.
+    pub async fn send_to(&self, data: &[u8], peer: SocketAddr) -> Result<()> {
+        let channel = self.inner.state().channels.get(&peer).copied();
+        let packet = match channel {
+            Some(number) => encode_channel_data(number, data),
+            None => Message::request(SEND_INDICATION)
+                .with(Attribute::XorPeerAddress(peer))
+                .with(Attribute::Data(data.to_vec()))
+                .encode(None, false),
+        };
+        self.inner
+            .link
+            .socket
+            .send_to(&packet, self.inner.config.server)
+            .await
+            .context("failed to send to turn server")?;
+        Ok(())
+    }
+
+    /// Next datagram a peer sent to our relayed address, including those that arrived while a
+    /// request was waiting for its response. Anything else from the server (stray responses,
+    /// unknown channels) is skipped.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut datagrams = self.inner.link.datagrams.lock().await;
+        loop {
+            let datagram = datagrams
+                .recv()
+                .await
+                .context("turn reader stopped")?
+                .context("turn recv_from failed")?;
+            if let Some((number, range)) = decode_channel_data(&datagram) {
+                let Some(peer) = self.inner.state().peers_by_channel.get(&number).copied() else {
+                    continue;
+                };
+                let len = range.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram[range][..len]);
+                return Ok((len, peer));
+            }
+            let Ok(message) = Message::decode(&datagram) else {
+                continue;
+            };
+            if message.kind != DATA_INDICATION {
+                continue;
+            }
+            let peer = message.find(|attr| match attr {
+                Attribute::XorPeerAddress(addr) => Some(*addr),
+                _ => None,
+            });
+            let data = message.find(|attr| match attr {
+                Attribute::Data(data) => Some(data.clone()),
+                _ => None,
+            });
+            if let (Some(peer), Some(data)) = (peer, data) {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                return Ok((len, peer));
+            }
+        }
+    }
+
+    /// Refreshes whatever is due: the allocation at half its lifetime, permissions and
+    /// channel bindings every couple of minutes. Cheap to call on every keepalive tick.
+    pub async fn keep_alive(&self) -> Result<()> {
+        let (refresh, channels, stale) = {
+            let state = self.inner.state();
+            let stale = |at: &Instant| at.elapsed() >= PERMISSION_REFRESH;
+            let channels: Vec<SocketAddr> = state
+                .channels
+                .keys()
+                .copied()
+                .filter(|peer| state.permissions.get(&peer.ip()).is_some_and(stale))
+                .collect();
+            let permissions: Vec<IpAddr> = state
+                .permissions
+                .iter()
+                .filter(|(ip, at)| stale(at) && !channels.iter().any(|peer| peer.ip() == **ip))
+                .map(|(ip, _)| *ip)
+                .collect();
+            (state.refreshed.elapsed() >= state.lifetime / 2, channels, permissions)
+        };
+        if refresh {
+            let refreshed = self
+                .inner
+                .request(
+                    Message::request(REFRESH).with(Attribute::Lifetime(self.inner.config.lifetime.as_secs() as u32)),
+                )
+                .await
+                .context("turn refresh failed")?;
+            self.inner.state().note_lifetime(&refreshed);
+        }
+        for peer in channels {
+            self.channel_bind(peer).await?;
+        }
+        for ip in stale {
+            self.create_permission(ip).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the allocation (Refresh with lifetime 0).
+    pub async fn release(self) -> Result<()> {
+        self.inner
+            .request(Message::request(REFRESH).with(Attribute::Lifetime(0)))
+            .await
+            .map(drop)
+    }
+}
+
+impl Inner {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Sends an authenticated request, retrying once with the new nonce on 438 Stale Nonce.
+    async fn request(&self, request: Message) -> Result<Message> {
+        for _ in 0..2 {
+            let mut signed = request.clone();
+            signed.transaction_id = rand::random();
+            signed.attributes.extend([
+                Attribute::Username(self.config.username.clone()),
+                Attribute::Realm(self.realm.clone()),
+                Attribute::Nonce(self.state().nonce.clone()),
+            ]);
+            let response = self
+                .link
+                .exchange(&signed, Some(&self.key), &self.config.options)
+                .await?
+                .with_context(|| format!("no answer from turn server {}", self.config.server))?;
+            if response.error_code().map(|(code, _)| code) == Some(438)
+                && let Some(nonce) = nonce_of(&response)
+            {
+                self.state().nonce = nonce;
+                continue;
+            }
+            return response.into_success();
+        }
+        bail!("turn server keeps rejecting our nonce")
+    }
+}
+
+type Transactions = Arc<Mutex<HashMap<TransactionId, oneshot::Sender<Vec<u8>>>>>;
+
+/// The socket to the TURN server, read by one task so that requests and relayed data can be
+/// waited for at the same time without stealing each other's datagrams.
+struct ServerLink {
+    socket: Arc<UdpSocket>,
+    server: SocketAddr,
+    /// Requests waiting for their response, by transaction id.
+    transactions: Transactions,
+    /// Everything else the server sent, in order: ChannelData, Data indications, late responses.
+    datagrams: tokio::sync::Mutex<mpsc::UnboundedReceiver<std::io::Result<Vec<u8>>>>,
+    reader: JoinHandle<()>,
+}
+
+impl ServerLink {
+    fn spawn(socket: UdpSocket, server: SocketAddr) -> Self {
+        let socket = Arc::new(socket);
+        let transactions = Transactions::default();
+        let (datagrams_tx, datagrams) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_server(socket.clone(), server, transactions.clone(), datagrams_tx));
+        Self {
+            socket,
+            server,
+            transactions,
+            datagrams: tokio::sync::Mutex::new(datagrams),
+            reader,
+        }
+    }
+
+    /// [`crate::stun::exchange`] over the shared socket: sends `request` (signed with `key`)
+    /// per `options`' retransmission schedule and returns the response, or `None` if every
+    /// attempt went unanswered.
+    async fn exchange(&self, request: &Message, key: Option<&[u8]>, options: &BindingOptions) -> Result<Option<Message>> {
+        let packet = request.encode(key, options.fingerprint);
+        let (response_tx, mut response) = oneshot::channel();
+        let _waiting = Waiting::register(&self.transactions, request.transaction_id, response_tx);
+
+        let mut rto = options.rto;
+        for _ in 0..options.attempts.max(1) {
+            self.socket
+                .send_to(&packet, self.server)
+                .await
+                .context("failed to send stun request")?;
+            if let Ok(received) = timeout(rto, &mut response).await {
+                let buf = received.context("turn reader stopped")?;
+                let response = Message::decode(&buf)?;
+                verify_response(&buf, &response, key)?;
+                return Ok(Some(response));
+            }
+            rto *= 2;
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for ServerLink {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// A request's entry in [`Transactions`], removed however the request ends.
+struct Waiting<'a> {
+    transactions: &'a Transactions,
+    id: TransactionId,
+}
+
+impl<'a> Waiting<'a> {
+    fn register(transactions: &'a Transactions, id: TransactionId, response: oneshot::Sender<Vec<u8>>) -> Self {
+        transactions.lock().unwrap().insert(id, response);
+        Self { transactions, id }
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.transactions.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Routes the server's datagrams: responses to the request waiting for them, the rest to
+/// [`TurnClient::recv_from`]. Stops after the first socket error, which it passes on.
+async fn read_server(
+    socket: Arc<UdpSocket>,
+    server: SocketAddr,
+    transactions: Transactions,
+    datagrams: mpsc::UnboundedSender<std::io::Result<Vec<u8>>>,
+) {
+    let mut buf = vec![0_u8; 2048];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                let _ = datagrams.send(Err(err));
+                return;
+            }
+        };
+        if from != server {
+            continue;
+        }
+        let datagram = buf[..n].to_vec();
+        // ChannelData never decodes as STUN, so only responses can be waited for.
+        let waiting = Message::decode(&datagram)
+            .ok()
+            .filter(|message| !message.is_indication())
+            .and_then(|message| transactions.lock().unwrap().remove(&message.transaction_id));
+        match waiting {
+            Some(response) => {
+                let _ = response.send(datagram);
+            }
+            None => {
+                if datagrams.send(Ok(datagram)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// `MD5(username ":" realm ":" password)`, the long-term credential HMAC key.
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    Md5::digest(format!("{username}:{realm}:{password}")).to_vec()
+}
+
+/// ChannelData message: channel number, payload length, payload (unpadded over UDP).
+pub fn encode_channel_data(number: u16, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(4 + data.len());
+    packet.extend_from_slice(&number.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// Channel number and payload range of a ChannelData message; `None` for STUN messages.
+pub fn decode_channel_data(packet: &[u8]) -> Option<(u16, std::ops::Range<usize>)> {
+    if packet.len() < 4 {
+        return None;
+    }
+    let number = u16::from_be_bytes([packet[0], packet[1]]);
+    let len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    ((CHANNEL_MIN..=CHANNEL_MAX).contains(&number) && 4 + len <= packet.len()).then_some((number, 4..4 + len))
+}
+
+fn nonce_of(message: &Message) -> Option<String> {
+    message.find(|attr| match attr {
+        Attribute::Nonce(nonce) => Some(nonce.clone()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_channel_data, encode_channel_data, long_term_key};
+
+    #[test]
+    fn long_term_key_is_md5_of_user_realm_password() {
+        // printf 'user:example.org:pass' | md5sum
+        assert_eq!(
+            long_term_key("user", "example.org", "pass"),
+            [
+                0xab, 0xca, 0x35, 0x35, 0x6f, 0x4b, 0x00, 0xfb, 0xc3, 0x3e, 0x2d, 0x8c, 0x2c, 0x43, 0xb9, 0xd6
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_data_round_trips_and_rejects_stun() {
+        let packet = encode_channel_data(0x4001, b"hello");
+        let (number, range) = decode_channel_data(&packet).expect("channel data should decode");
+        assert_eq!(number, 0x4001);
+        assert_eq!(&packet[range], b"hello");
+
+        // A STUN header starts with two zero bits, so it is never taken for a channel.
+        assert_eq!(decode_channel_data(&[0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42]), None);
+        assert_eq!(decode_channel_data(&[0x40, 0x01, 0x00, 0x09, 0x00]), None, "truncated payload");
+    }
+}
@@ -0,0 +1,49 @@
+use rendezvous_client::lifetime::{BindingLifetime, LifetimeOptions, discover};
+use rendezvous_client::stun::BindingOptions;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+#[path = "support/expiring_nat.rs"]
+mod expiring_nat;
+
+fn options(max_gap: Duration) -> LifetimeOptions {
+    LifetimeOptions {
+        first_gap: Duration::from_millis(50),
+        max_gap,
+        binding: BindingOptions {
+            rto: Duration::from_millis(20),
+            attempts: 2,
+            ..BindingOptions::default()
+        },
+    }
+}
+
+#[tokio::test]
+async fn finds_the_gap_a_binding_no_longer_survives() -> anyhow::Result<()> {
+    let nat = expiring_nat::ExpiringNat::spawn(Duration::from_millis(300)).await?;
+    let lifetime = discover(IpAddr::V4(Ipv4Addr::LOCALHOST), nat.address, &options(Duration::from_secs(5))).await?;
+    // Gaps of 50, 100 and 200 ms survive; 400 ms does not.
+    assert_eq!(
+        lifetime,
+        BindingLifetime {
+            survived: Duration::from_millis(200),
+            expired: Some(Duration::from_millis(400)),
+        }
+    );
+    assert_eq!(lifetime.keepalive_interval(), Duration::from_millis(150));
+    Ok(())
+}
+
+#[tokio::test]
+async fn stops_at_the_largest_gap() -> anyhow::Result<()> {
+    let nat = expiring_nat::ExpiringNat::spawn(Duration::from_secs(60)).await?;
+    let lifetime = discover(IpAddr::V4(Ipv4Addr::LOCALHOST), nat.address, &options(Duration::from_millis(150))).await?;
+    assert_eq!(
+        lifetime,
+        BindingLifetime {
+            survived: Duration::from_millis(150),
+            expired: None,
+        }
+    );
+    Ok(())
+}
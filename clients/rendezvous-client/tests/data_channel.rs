@@ -0,0 +1,55 @@
+use rendezvous_client::channel::DataChannel;
+use rendezvous_client::noise::StaticKeypair;
+use rendezvous_client::probe::PairKey;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+const WAIT: Duration = Duration::from_secs(2);
+
+#[tokio::test]
+async fn peers_exchange_datagrams_over_the_handshake() -> anyhow::Result<()> {
+    let (a_keys, b_keys) = (StaticKeypair::generate(), StaticKeypair::generate());
+    let key = PairKey::derive("a", &a_keys, "b", &b_keys.public()).unwrap();
+    let a_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let b_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let (a_addr, b_addr) = (a_socket.local_addr()?, b_socket.local_addr()?);
+
+    // b has the larger id and initiates; a answers.
+    let (a, b) = tokio::try_join!(
+        DataChannel::connect(a_socket, b_addr, key, ("a", "b"), a_keys.clone(), b_keys.public(), WAIT),
+        DataChannel::connect(b_socket, a_addr, key, ("b", "a"), b_keys, a_keys.public(), WAIT),
+    )?;
+    assert_eq!(a.peer(), b_addr);
+
+    a.send(b"hello b").await?;
+    assert_eq!(timeout(WAIT, b.recv()).await??, b"hello b");
+    b.send(b"hello a").await?;
+    b.send(b"again").await?;
+    assert_eq!(timeout(WAIT, a.recv()).await??, b"hello a");
+    assert_eq!(timeout(WAIT, a.recv()).await??, b"again");
+
+    // Control traffic has its own message types, so no payload is mistaken for it.
+    a.send(b"hello from a").await?;
+    assert_eq!(timeout(WAIT, b.recv()).await??, b"hello from a");
+    Ok(())
+}
+
+#[tokio::test]
+async fn handshake_fails_against_the_wrong_static_key() -> anyhow::Result<()> {
+    let (a_keys, b_keys, other) = (StaticKeypair::generate(), StaticKeypair::generate(), StaticKeypair::generate());
+    let key = PairKey::derive("a", &a_keys, "b", &b_keys.public()).unwrap();
+    let a_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let b_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let (a_addr, b_addr) = (a_socket.local_addr()?, b_socket.local_addr()?);
+
+    // a expects another key than the one b holds, so b's initiations are refused.
+    let wait = Duration::from_millis(600);
+    let (a, b) = tokio::join!(
+        DataChannel::connect(a_socket, b_addr, key, ("a", "b"), a_keys.clone(), other.public(), wait),
+        DataChannel::connect(b_socket, a_addr, key, ("b", "a"), b_keys, a_keys.public(), wait),
+    );
+    assert!(a.is_err());
+    assert!(b.is_err());
+    Ok(())
+}
@@ -0,0 +1,259 @@
+#[cfg(target_os = "linux")]
+#[path = "support/room_coordinator.rs"]
+mod room_coordinator;
+
+#[cfg(target_os = "linux")]
+mod linux_only {
+    use super::room_coordinator::RoomCoordinator;
+    use anyhow::{anyhow, bail};
+    use rendezvous_client::channel::DataChannel;
+    use rendezvous_client::noise::StaticKeypair;
+    use rendezvous_client::overlay::OverlayAddress;
+    use rendezvous_client::probe::PairKey;
+    use rendezvous_client::rendezvous::{self, Config};
+    use rendezvous_client::report::RunReport;
+    use rendezvous_client::tun::Tun;
+    use std::fs::File;
+    use std::io;
+    use std::net::{IpAddr, SocketAddr, UdpSocket as StdUdpSocket};
+    use std::os::fd::AsRawFd;
+    use std::process::Command;
+    use std::sync::{Arc, mpsc};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::net::UdpSocket;
+    use tokio::sync::oneshot;
+
+    /// A network namespace holding one end of the overlay, so the kernel routes between the two
+    /// TUN devices through our tunnel instead of locally.
+    struct Netns(&'static str);
+
+    impl Netns {
+        fn create(name: &'static str) -> io::Result<Self> {
+            run_ip(&["netns", "add", name])?;
+            let netns = Self(name);
+            run_ip(&["-n", name, "link", "set", "lo", "up"])?;
+            Ok(netns)
+        }
+
+        /// A socket bound to `address` inside the namespace; it stays there whichever thread uses it.
+        fn bind(&self, address: &str) -> io::Result<StdUdpSocket> {
+            let address = address.to_string();
+            self.spawn(move || {
+                let socket = StdUdpSocket::bind(address)?;
+                socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+                Ok(socket)
+            })
+            .join()
+            .map_err(|_| io::Error::other("netns thread panicked"))?
+        }
+
+        /// Runs `f` on a thread inside the namespace: the sockets, devices and `ip` commands it
+        /// creates belong there.
+        fn spawn<T, F>(&self, f: F) -> thread::JoinHandle<io::Result<T>>
+        where
+            T: Send + 'static,
+            F: FnOnce() -> io::Result<T> + Send + 'static,
+        {
+            let path = format!("/var/run/netns/{}", self.0);
+            thread::spawn(move || {
+                let netns = File::open(path)?;
+                // SAFETY: only this thread switches namespace, and it exits with `f`.
+                if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                f()
+            })
+        }
+
+        /// Moves `tun` in here and gives it `address`.
+        fn adopt(&self, tun: &Tun, address: &str) -> io::Result<()> {
+            run_ip(&["link", "set", tun.name(), "netns", self.0])?;
+            run_ip(&["-n", self.0, "addr", "add", address, "dev", tun.name()])?;
+            run_ip(&["-n", self.0, "link", "set", tun.name(), "up"])
+        }
+    }
+
+    impl Drop for Netns {
+        fn drop(&mut self) {
+            let _ = Command::new("ip").args(["netns", "del", self.0]).status();
+        }
+    }
+
+    fn run_ip(args: &[&str]) -> io::Result<()> {
+        let status = Command::new("ip").args(args).status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("ip command failed: ip {}", args.join(" "))));
+        }
+        Ok(())
+    }
+
+    /// Runs a rendezvous client in `netns` until `stop` fires; failing if the run ends first.
+    fn serve(netns: &Netns, config: Config, stop: oneshot::Receiver<()>) -> thread::JoinHandle<io::Result<()>> {
+        netns.spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(async {
+                let mut report = RunReport::new(&config.client_id, &config.room);
+                tokio::select! {
+                    outcome = rendezvous::run(config, &mut report) => {
+                        Err(io::Error::other(format!("run ended before it was cancelled: {outcome:?}")))
+                    }
+                    _ = stop => Ok(()),
+                }
+            })
+        })
+    }
+
+    /// Retries `attempt` for a few seconds while the mesh comes up.
+    fn eventually<T>(mut attempt: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            match attempt() {
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(200)),
+                result => return result,
+            }
+        }
+    }
+
+    /// Forwards packets between `tun` and `channel` both ways until the test ends.
+    fn bridge(tun: Tun, channel: DataChannel) {
+        let (tun, channel) = (Arc::new(tun), Arc::new(channel));
+        let (tun_out, channel_out) = (tun.clone(), channel.clone());
+        tokio::spawn(async move {
+            let mut buf = vec![0_u8; 2048];
+            while let Ok(n) = tun_out.recv(&mut buf).await {
+                let _ = channel_out.send(&buf[..n]).await;
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok(packet) = channel.recv().await {
+                let _ = tun.send(&packet).await;
+            }
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn routes_udp_across_the_encrypted_tunnel_between_namespaces() -> anyhow::Result<()> {
+        // Requires:
+        // - Linux with /dev/net/tun
+        // - root (or equivalent privileges) for network namespaces + TUN devices
+        // Run: sudo -E cargo test --test linux_tun -- --ignored
+        let (ns_a, ns_b) = (Netns::create("rvtun-a")?, Netns::create("rvtun-b")?);
+        // The devices are opened here and moved; their fds keep working from this namespace.
+        let (tun_a, tun_b) = (Tun::create("rvtun-a")?, Tun::create("rvtun-b")?);
+        ns_a.adopt(&tun_a, "10.99.0.1/24")?;
+        ns_b.adopt(&tun_b, "10.99.0.2/24")?;
+
+        let (a_keys, b_keys) = (StaticKeypair::generate(), StaticKeypair::generate());
+        let key = PairKey::derive("a", &a_keys, "b", &b_keys.public()).unwrap();
+        let a_socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let b_socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let (a_addr, b_addr) = (a_socket.local_addr()?, b_socket.local_addr()?);
+        let wait = Duration::from_secs(2);
+        let (a, b) = tokio::try_join!(
+            DataChannel::connect(a_socket, b_addr, key, ("a", "b"), a_keys.clone(), b_keys.public(), wait),
+            DataChannel::connect(b_socket, a_addr, key, ("b", "a"), b_keys, a_keys.public(), wait),
+        )?;
+        bridge(tun_a, a);
+        bridge(tun_b, b);
+
+        // Each namespace only reaches the other's overlay address through the tunnel.
+        let (client, server) = (ns_a.bind("10.99.0.1:0")?, ns_b.bind("10.99.0.2:7000")?);
+        let mut buf = [0_u8; 64];
+        client.send_to(b"over the overlay", "10.99.0.2:7000")?;
+        let (n, from) = server.recv_from(&mut buf)?;
+        assert_eq!(&buf[..n], b"over the overlay");
+        assert_eq!(from, client.local_addr()?);
+
+        server.send_to(b"and back", from)?;
+        let (n, from) = client.recv_from(&mut buf)?;
+        assert_eq!(&buf[..n], b"and back");
+        assert_eq!(from, "10.99.0.2:7000".parse::<SocketAddr>()?);
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    fn rendezvous_run_routes_the_overlay_until_cancelled() -> anyhow::Result<()> {
+        // Requires:
+        // - Linux with /dev/net/tun
+        // - root (or equivalent privileges) for network namespaces, veth and TUN devices
+        // Run: sudo -E cargo test --test linux_tun -- --ignored
+        let (ns_a, ns_b) = (Netns::create("rvrun-a")?, Netns::create("rvrun-b")?);
+        run_ip(&[
+            "link", "add", "rvrun-va", "netns", "rvrun-a", "type", "veth", "peer", "name", "rvrun-vb", "netns", "rvrun-b",
+        ])?;
+        for (netns, link, address) in [(&ns_a, "rvrun-va", "10.98.0.1/24"), (&ns_b, "rvrun-vb", "10.98.0.2/24")] {
+            run_ip(&["-n", netns.0, "addr", "add", address, "dev", link])?;
+            run_ip(&["-n", netns.0, "link", "set", link, "up"])?;
+        }
+
+        // The coordinator lives next to a, on its veth address.
+        let (ports_tx, ports) = mpsc::channel();
+        let (stop_coordinator, coordinator_stopped) = oneshot::channel::<()>();
+        let coordinator = ns_a.spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(async {
+                let coordinator = RoomCoordinator::spawn(IpAddr::from([10, 98, 0, 1])).await?;
+                let _ = ports_tx.send((coordinator.http_port, coordinator.udp_port));
+                let _ = coordinator_stopped.await;
+                Ok(())
+            })
+        });
+        let (http_port, udp_port) = ports.recv()?;
+        // Both ask for 10.99.0.1: a keeps it, b has to move its device to its next candidate.
+        let overlay: OverlayAddress = "10.99.0.1/24".parse()?;
+        let config = |client_id: &str| Config {
+            coordinator_http_port: http_port,
+            coordinator_udp_port: udp_port,
+            overlay_address: Some(overlay),
+            tun_device: format!("rvrun-{client_id}"),
+            log: true,
+            ..Config::new("10.98.0.1", "lab", client_id)
+        };
+        let b_ip = overlay.candidates("b").find(|ip| *ip != overlay.ip).expect("b has candidates");
+        let ((stop_a, a_stopped), (stop_b, b_stopped)) = (oneshot::channel(), oneshot::channel());
+        let (a, b) = (serve(&ns_a, config("a"), a_stopped), serve(&ns_b, config("b"), b_stopped));
+
+        // b's new address only exists once the rendezvous readdressed its device.
+        let server = eventually(|| ns_b.bind(&SocketAddr::new(b_ip, 7000).to_string()))?;
+        let client = ns_a.bind("10.99.0.1:0")?;
+        let mut buf = [0_u8; 64];
+        let (n, from) = eventually(|| {
+            client.send_to(b"over the overlay", (b_ip, 7000))?;
+            server.recv_from(&mut buf)
+        })?;
+        assert_eq!(&buf[..n], b"over the overlay");
+        assert_eq!(from, client.local_addr()?);
+
+        // A packet b routes to a with a source that isn't b's overlay address is dropped at a.
+        run_ip(&["-n", ns_b.0, "addr", "add", "10.99.0.77/32", "dev", "rvrun-b"])?;
+        let listener = ns_a.bind("10.99.0.1:7001")?;
+        let spoofed = ns_b.bind("10.99.0.77:0")?;
+        spoofed.send_to(b"spoofed", "10.99.0.1:7001")?;
+        let genuine = ns_b.bind(&SocketAddr::new(b_ip, 0).to_string())?;
+        genuine.send_to(b"genuine", "10.99.0.1:7001")?;
+        let (n, from) = listener.recv_from(&mut buf)?;
+        assert_eq!((&buf[..n], from.ip()), (&b"genuine"[..], b_ip));
+
+        // Both runs were still serving the overlay when we stopped them.
+        let _ = (stop_a.send(()), stop_b.send(()));
+        for run in [a, b] {
+            if let Err(e) = run.join().map_err(|_| anyhow!("client thread panicked"))? {
+                bail!("client failed: {e}");
+            }
+        }
+        let _ = stop_coordinator.send(());
+        coordinator.join().map_err(|_| anyhow!("coordinator thread panicked"))??;
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[test]
+#[ignore]
+fn linux_tun_test_is_linux_only() {
+    // Keep `cargo test -- --ignored` behavior explicit on non-Linux hosts.
+    eprintln!("linux tun test is only supported on Linux");
+}
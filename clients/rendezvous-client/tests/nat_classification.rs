@@ -0,0 +1,47 @@
+use rendezvous_client::nat::{Behavior, classify};
+use rendezvous_client::stun::BindingOptions;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+#[path = "support/nat_emulator.rs"]
+mod nat_emulator;
+
+async fn classify_behind(mapping: Behavior, filtering: Behavior) -> anyhow::Result<(Behavior, Behavior)> {
+    let nat = nat_emulator::NatEmulator::spawn(mapping, filtering).await?;
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let options = BindingOptions {
+        rto: Duration::from_millis(50),
+        attempts: 2,
+        ..BindingOptions::default()
+    };
+    let behavior = classify(&socket, nat.primary, &options).await?;
+    assert_eq!(behavior.mapped.ip(), nat_emulator::PUBLIC_IP);
+    Ok((behavior.mapping, behavior.filtering))
+}
+
+#[tokio::test]
+async fn classifies_full_cone() -> anyhow::Result<()> {
+    let got = classify_behind(Behavior::EndpointIndependent, Behavior::EndpointIndependent).await?;
+    assert_eq!(got, (Behavior::EndpointIndependent, Behavior::EndpointIndependent));
+    Ok(())
+}
+
+#[tokio::test]
+async fn classifies_restricted_cones() -> anyhow::Result<()> {
+    let got = classify_behind(Behavior::EndpointIndependent, Behavior::AddressDependent).await?;
+    assert_eq!(got, (Behavior::EndpointIndependent, Behavior::AddressDependent));
+
+    let got = classify_behind(Behavior::EndpointIndependent, Behavior::AddressAndPortDependent).await?;
+    assert_eq!(got, (Behavior::EndpointIndependent, Behavior::AddressAndPortDependent));
+    Ok(())
+}
+
+#[tokio::test]
+async fn classifies_dependent_mappings() -> anyhow::Result<()> {
+    let got = classify_behind(Behavior::AddressDependent, Behavior::AddressAndPortDependent).await?;
+    assert_eq!(got, (Behavior::AddressDependent, Behavior::AddressAndPortDependent));
+
+    let got = classify_behind(Behavior::AddressAndPortDependent, Behavior::AddressAndPortDependent).await?;
+    assert_eq!(got, (Behavior::AddressAndPortDependent, Behavior::AddressAndPortDependent));
+    Ok(())
+}
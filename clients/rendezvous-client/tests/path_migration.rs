@@ -0,0 +1,160 @@
+use peer_wire::{Message, Packet};
+use rendezvous_client::noise::StaticKeypair;
+use rendezvous_client::probe::PairKey;
+use rendezvous_client::session::{Session, SessionState, Via};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+#[path = "support/nat_simulator.rs"]
+mod nat_simulator;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+fn packet(key: &PairKey, sequence: u32, message: Message) -> Vec<u8> {
+    Packet {
+        session: key.session_id(),
+        sequence,
+        message,
+    }
+    .encode(key.as_bytes())
+    .unwrap()
+}
+
+async fn recv(socket: &UdpSocket, key: &PairKey) -> anyhow::Result<(Message, SocketAddr)> {
+    let mut buf = [0_u8; 256];
+    let (n, from) = timeout(WAIT, socket.recv_from(&mut buf)).await??;
+    Ok((Packet::decode(&buf[..n], key.as_bytes())?.message, from))
+}
+
+fn pair_key() -> PairKey {
+    PairKey::derive("a", &StaticKeypair::generate(), "b", &StaticKeypair::generate().public()).unwrap()
+}
+
+/// `b`'s session with `a`, established on `a`'s current public mapping.
+fn established(key: PairKey, endpoint: SocketAddr) -> Session {
+    let mut session = Session::new(true, &[]);
+    session.key = Some(key);
+    session.state = SessionState::Established;
+    session.endpoint = Some(endpoint);
+    session
+}
+
+#[tokio::test]
+async fn follows_a_rebound_nat_mapping_once_the_new_path_answers() -> anyhow::Result<()> {
+    let key = pair_key();
+    let b = UdpSocket::bind("127.0.0.1:0").await?;
+    let a = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut nat = nat_simulator::NatSimulator::spawn(b.local_addr()?).await?;
+    let old = nat.public();
+    let mut session = established(key, old);
+
+    // Keepalives from the established path change nothing.
+    a.send_to(&packet(&key, 0, Message::Keepalive { nonce: [1; 16], timestamp: 1 }), nat.inside).await?;
+    let (_, from) = recv(&b, &key).await?;
+    assert_eq!(from, old);
+    assert_eq!(session.observe_path(Via::Socket(None), from), None);
+
+    // The NAT rebinds: a's next keepalive shows up from a new port, and b challenges it there.
+    let new = nat.rebind().await?;
+    assert_ne!(new, old);
+    a.send_to(&packet(&key, 1, Message::Keepalive { nonce: [2; 16], timestamp: 2 }), nat.inside).await?;
+    let (_, from) = recv(&b, &key).await?;
+    assert_eq!(from, new);
+    let challenge = session.observe_path(Via::Socket(None), from).expect("a new path is challenged");
+    assert!(session.is_validating_path());
+    assert!(session.is_known_path(Via::Socket(None), from), "challenges on the new path are answered");
+    assert_eq!(session.observe_path(Via::Socket(None), from), None, "one validation per path");
+    let sealed = session.seal(challenge.clone()).unwrap();
+    session.send(&b, None, Via::Socket(None), &sealed, from).await;
+
+    // Until the response comes back, the session stays where it was.
+    assert_eq!(session.endpoint, Some(old));
+    let (received, _) = recv(&a, &key).await?;
+    assert_eq!(received, challenge);
+    let Message::PathChallenge { data } = received else {
+        unreachable!()
+    };
+
+    // A response with the wrong data, or from another address, moves nothing.
+    assert_eq!(session.confirm_path(Via::Socket(None), new, [0xff; 8]), None);
+    assert_eq!(session.confirm_path(Via::Socket(None), old, data), None);
+
+    a.send_to(&packet(&key, 2, Message::PathResponse { data }), nat.inside).await?;
+    let (response, from) = recv(&b, &key).await?;
+    assert_eq!(response, Message::PathResponse { data });
+    assert_eq!(session.confirm_path(Via::Socket(None), from, data), Some(old));
+    assert_eq!(session.endpoint, Some(new));
+    assert!(!session.is_validating_path());
+
+    // Traffic to the migrated endpoint reaches a again.
+    let keepalive = session.seal(Message::Keepalive { nonce: [3; 16], timestamp: 3 }).unwrap();
+    session.send(&b, None, Via::Socket(None), &keepalive, new).await;
+    let (received, _) = recv(&a, &key).await?;
+    assert_eq!(received, Message::Keepalive { nonce: [3; 16], timestamp: 3 });
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_room_member_cannot_pull_the_path_to_itself() -> anyhow::Result<()> {
+    let (a, b, mallory) = (StaticKeypair::generate(), StaticKeypair::generate(), StaticKeypair::generate());
+    let key = PairKey::derive("b", &b, "a", &a.public()).unwrap();
+    let b_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let mallory_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let mallory_addr = mallory_socket.local_addr()?;
+    let old: SocketAddr = "198.51.100.7:40000".parse()?;
+    let session = established(key, old);
+
+    // Presence gives mallory both ids and public keys; no key it derives from them is the pair's,
+    // so its keepalives (which would start a migration) fail the tag, even under the pair's
+    // session id.
+    let guesses = [
+        PairKey::derive("a", &mallory, "b", &b.public()),
+        PairKey::derive("mallory", &mallory, "b", &b.public()),
+        PairKey::derive("mallory", &mallory, "a", &a.public()),
+    ];
+    for guess in guesses.into_iter().flatten() {
+        for session_id in [guess.session_id(), key.session_id()] {
+            let keepalive = Packet {
+                session: session_id,
+                sequence: 0,
+                message: Message::Keepalive { nonce: [4; 16], timestamp: 4 },
+            };
+            mallory_socket.send_to(&keepalive.encode(guess.as_bytes())?, b_socket.local_addr()?).await?;
+            let mut buf = [0_u8; 256];
+            let (n, from) = timeout(WAIT, b_socket.recv_from(&mut buf)).await??;
+            assert_eq!(from, mallory_addr);
+            assert!(Packet::decode(&buf[..n], key.as_bytes()).is_err());
+        }
+    }
+
+    // A challenge from mallory's address is not answered either, and the path stays put.
+    assert!(!session.is_known_path(Via::Socket(None), mallory_addr));
+    assert!(session.is_known_path(Via::Socket(None), old));
+    assert_eq!(session.endpoint, Some(old));
+    assert!(!session.is_validating_path());
+    Ok(())
+}
+
+#[tokio::test]
+async fn gives_up_on_a_path_that_never_answers() -> anyhow::Result<()> {
+    let key = pair_key();
+    let old: SocketAddr = "198.51.100.7:40000".parse()?;
+    let spoofed: SocketAddr = "203.0.113.9:50000".parse()?;
+    let mut session = established(key, old);
+
+    let Some(Message::PathChallenge { data }) = session.observe_path(Via::Socket(None), spoofed) else {
+        panic!("a new path is challenged");
+    };
+    let mut retransmissions = 0;
+    while let Some((via, to, challenge)) = session.path_challenge_due() {
+        assert_eq!((via, to, challenge), (Via::Socket(None), spoofed, Message::PathChallenge { data }));
+        retransmissions += 1;
+    }
+    assert!(retransmissions > 0);
+    assert!(!session.is_validating_path());
+    assert_eq!(session.confirm_path(Via::Socket(None), spoofed, data), None, "too late");
+    assert_eq!(session.endpoint, Some(old));
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use rendezvous_client::phoenix::{Backoff, ChannelEvent, Socket, SocketOptions};
+use serde_json::json;
+use std::time::Duration;
+
+#[path = "support/mock_coordinator.rs"]
+mod mock_coordinator;
+
+const WAIT: Duration = Duration::from_secs(2);
+
+#[tokio::test]
+async fn joins_routes_events_and_awaits_push_replies() -> anyhow::Result<()> {
+    let mut coordinator =
+        mock_coordinator::MockCoordinator::spawn(json!({ "b": { "metas": [{ "udp": "10.0.0.2:1234" }] } })).await?;
+
+    let socket = Socket::connect(&coordinator.url()).await?;
+    let (mut channel, reply) = socket
+        .join("rendezvous:demo", json!({ "client_id": "a" }), WAIT)
+        .await?;
+    assert!(reply.is_ok());
+    assert_eq!(reply.response, json!({ "client_id": "a" }));
+
+    let Some(ChannelEvent::Message(event)) = tokio::time::timeout(WAIT, channel.recv()).await? else {
+        panic!("expected presence_state");
+    };
+    assert_eq!(event.event, "presence_state");
+    assert_eq!(event.payload["b"]["metas"][0]["udp"], "10.0.0.2:1234");
+
+    let reply = channel.push("echo", json!({ "n": 1 }), WAIT).await?;
+    assert_eq!(reply.response, json!({ "n": 1 }));
+
+    let join = coordinator.frames.recv().await.expect("join frame");
+    let push = coordinator.frames.recv().await.expect("push frame");
+    assert_eq!(join.event, "phx_join");
+    assert_eq!(push.join_ref, join.join_ref, "pushes carry the channel's join ref");
+    assert_ne!(push.msg_ref, join.msg_ref, "every push gets a fresh ref");
+
+    channel.leave(WAIT).await?;
+    let leave = coordinator.frames.recv().await.expect("leave frame");
+    assert_eq!(leave.event, "phx_leave");
+    Ok(())
+}
+
+#[tokio::test]
+async fn sends_heartbeats_on_the_phoenix_topic() -> anyhow::Result<()> {
+    let mut coordinator = mock_coordinator::MockCoordinator::spawn(json!({})).await?;
+    let options = SocketOptions {
+        heartbeat: Duration::from_millis(50),
+        ..SocketOptions::default()
+    };
+    let _socket = Socket::connect_with(&coordinator.url(), options).await?;
+
+    let frame = tokio::time::timeout(WAIT, coordinator.frames.recv())
+        .await?
+        .expect("heartbeat frame");
+    assert_eq!(frame.topic, "phoenix");
+    assert_eq!(frame.event, "heartbeat");
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnects_and_rejoins_after_the_connection_drops() -> anyhow::Result<()> {
+    let mut coordinator =
+        mock_coordinator::MockCoordinator::spawn(json!({ "b": { "metas": [{ "udp": "10.0.0.2:1234" }] } })).await?;
+    let options = SocketOptions {
+        reconnect: Some(Backoff {
+            initial: Duration::from_millis(20),
+            max: Duration::from_millis(100),
+        }),
+        ..SocketOptions::default()
+    };
+    let socket = Socket::connect_with(&coordinator.url(), options).await?;
+    let (mut channel, _) = socket
+        .join("rendezvous:demo", json!({ "client_id": "a" }), WAIT)
+        .await?;
+    let first_join = coordinator.frames.recv().await.expect("join frame");
+    assert!(matches!(
+        tokio::time::timeout(WAIT, channel.recv()).await?,
+        Some(ChannelEvent::Message(_))
+    ));
+
+    coordinator.drop_connections();
+    assert_eq!(
+        tokio::time::timeout(WAIT, channel.recv()).await?,
+        Some(ChannelEvent::Disconnected)
+    );
+    let Some(ChannelEvent::Rejoined(reply)) = tokio::time::timeout(WAIT, channel.recv()).await? else {
+        panic!("channel should be rejoined");
+    };
+    assert!(reply.is_ok());
+
+    let rejoin = coordinator.frames.recv().await.expect("rejoin frame");
+    assert_eq!(rejoin.event, "phx_join");
+    assert_eq!(rejoin.payload, json!({ "client_id": "a" }));
+    assert_ne!(rejoin.join_ref, first_join.join_ref);
+
+    let Some(ChannelEvent::Message(event)) = tokio::time::timeout(WAIT, channel.recv()).await? else {
+        panic!("presence should be pushed again after the rejoin");
+    };
+    assert_eq!(event.event, "presence_state");
+
+    let reply = channel.push("echo", json!({ "n": 2 }), WAIT).await?;
+    assert_eq!(reply.response, json!({ "n": 2 }));
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use rendezvous_client::punch::{PunchConfig, Puncher, Strategy};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn birthday_hit_is_reported_with_its_extra_socket() -> anyhow::Result<()> {
+    let primary = UdpSocket::bind("127.0.0.1:0").await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let config = PunchConfig {
+        probes_per_second: 1000,
+        birthday_sockets: 4,
+        ..PunchConfig::default()
+    };
+    let mut puncher = Puncher::new(
+        Strategy::Birthday { sockets: 4 },
+        peer.local_addr()?,
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        &config,
+    )
+    .await?;
+
+    // The advertised endpoint is probed from the primary socket first.
+    puncher.send_probes(&primary, b"probe from a").await?;
+    let mut buf = [0_u8; 64];
+    let (_, from) = timeout(Duration::from_secs(1), peer.recv_from(&mut buf)).await??;
+    assert_eq!(from, primary.local_addr()?);
+
+    // The peer's own spray lands on one of our extra sockets: that's the hole to keep using.
+    let hit = puncher.socket(&primary, Some(2)).local_addr()?;
+    peer.send_to(b"probe from b", hit).await?;
+    let (n, from, index) = timeout(Duration::from_secs(1), puncher.recv_from_any(&primary, &mut buf)).await??;
+    assert_eq!(&buf[..n], b"probe from b");
+    assert_eq!(from, peer.local_addr()?);
+    assert_eq!(index, Some(2));
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use rendezvous_client::rendezvous::{self, Config};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::time::timeout;
+
+#[path = "support/room_coordinator.rs"]
+mod room_coordinator;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn config(coordinator: &room_coordinator::RoomCoordinator, client_id: &str) -> Config {
+    Config {
+        coordinator_http_port: coordinator.http_port,
+        coordinator_udp_port: coordinator.udp_port,
+        timeout: WAIT,
+        ..Config::new("127.0.0.1", "lab", client_id)
+    }
+}
+
+#[tokio::test]
+async fn connect_returns_a_punched_socket_with_an_open_channel() -> anyhow::Result<()> {
+    let coordinator = room_coordinator::RoomCoordinator::spawn(Ipv4Addr::LOCALHOST.into()).await?;
+    let (a, b) = tokio::try_join!(
+        rendezvous::connect_with(config(&coordinator, "a"), "b"),
+        rendezvous::connect_with(config(&coordinator, "b"), "a"),
+    )?;
+    // The sockets are bound to the wildcard address, and the peers meet on loopback.
+    assert_eq!(a.peer.port(), b.socket.local_addr()?.port(), "a reaches b's socket directly");
+    assert_eq!(b.peer.port(), a.socket.local_addr()?.port());
+
+    let peer = a.report.peers.iter().find(|peer| peer.peer == "b").expect("b in a's report");
+    assert!(a.report.ok);
+    assert_eq!(peer.state, "established");
+    assert_eq!(peer.path, Some("direct"));
+    assert!(peer.encrypted && peer.reached);
+    assert!(peer.punch_attempts > 0);
+    assert!(peer.first_contact_ms.is_some());
+
+    let (a, b) = (a.into_channel(), b.into_channel());
+    a.send(b"over the rendezvous").await?;
+    // Hellos and acks still in flight from the rendezvous never reach the application.
+    assert_eq!(timeout(WAIT, b.recv()).await??, b"over the rendezvous");
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_times_out_without_the_peer() -> anyhow::Result<()> {
+    let coordinator = room_coordinator::RoomCoordinator::spawn(Ipv4Addr::LOCALHOST.into()).await?;
+    let config = Config {
+        timeout: Duration::from_millis(500),
+        ..config(&coordinator, "a")
+    };
+    let Err(e) = rendezvous::connect_with(config, "b").await else {
+        panic!("connected without a peer");
+    };
+    assert!(e.to_string().contains("timed out"), "{e:#}");
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use rendezvous_client::stun::{BindingOptions, binding_request};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+#[path = "support/stun_responder.rs"]
+mod stun_responder;
+
+fn fast_options() -> BindingOptions {
+    BindingOptions {
+        rto: Duration::from_millis(50),
+        attempts: 4,
+        ..BindingOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn discovers_mapped_address_after_retransmission() -> anyhow::Result<()> {
+    let server = stun_responder::StunResponder::spawn(2, None).await?;
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mapped = binding_request(&socket, server.address, &fast_options()).await?;
+    assert_eq!(mapped, socket.local_addr()?);
+    assert_eq!(server.requests(), 3, "two lost requests, then the answered retransmission");
+    Ok(())
+}
+
+#[tokio::test]
+async fn short_term_credentials_sign_both_directions() -> anyhow::Result<()> {
+    let server = stun_responder::StunResponder::spawn(0, Some(b"secret")).await?;
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let options = BindingOptions {
+        credentials: Some(("alice".to_string(), b"secret".to_vec())),
+        ..fast_options()
+    };
+    assert_eq!(binding_request(&socket, server.address, &options).await?, socket.local_addr()?);
+
+    let wrong = BindingOptions {
+        credentials: Some(("alice".to_string(), b"nope".to_vec())),
+        ..fast_options()
+    };
+    // The error response is signed with the server's key, so the client rejects it as forged.
+    assert!(binding_request(&socket, server.address, &wrong).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn gives_up_when_the_server_never_answers() -> anyhow::Result<()> {
+    let server = stun_responder::StunResponder::spawn(usize::MAX, None).await?;
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let err = binding_request(&socket, server.address, &fast_options()).await.unwrap_err();
+    assert!(err.to_string().contains("no stun binding response"));
+    assert_eq!(server.requests(), 4);
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use rendezvous_client::stun::{Attribute, BINDING_REQUEST, BINDING_SUCCESS, Message};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Public address the emulated NAT maps clients onto.
+pub const PUBLIC_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+/// STUN server with RESPONSE-PORT support, behind which every client appears to sit behind an
+/// endpoint-independent NAT whose bindings expire after `lifetime` without outbound traffic.
+///
+/// Like `NatEmulator`, the NAT is emulated at the server: each client socket gets a public port
+/// that is replaced once it has been idle too long, and a response aimed at a public port is
+/// only delivered if that binding is still alive. Inbound traffic does not refresh a binding.
+pub struct ExpiringNat {
+    pub address: SocketAddr,
+}
+
+struct Binding {
+    port: u16,
+    last_outbound: Instant,
+}
+
+impl ExpiringNat {
+    pub async fn spawn(lifetime: Duration) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let address = socket.local_addr()?;
+
+        tokio::spawn(async move {
+            let mut bindings: HashMap<SocketAddr, Binding> = HashMap::new();
+            let mut next_port = 40000;
+            let mut buf = vec![0_u8; 1500];
+            while let Ok((n, client)) = socket.recv_from(&mut buf).await {
+                let Ok(request) = Message::decode(&buf[..n]) else { continue };
+                if request.kind != BINDING_REQUEST {
+                    continue;
+                }
+                let now = Instant::now();
+                bindings.retain(|_, binding| now.duration_since(binding.last_outbound) < lifetime);
+                let binding = bindings.entry(client).or_insert_with(|| {
+                    next_port += 1;
+                    Binding {
+                        port: next_port,
+                        last_outbound: now,
+                    }
+                });
+                binding.last_outbound = now;
+                let mapped = SocketAddr::new(PUBLIC_IP, binding.port);
+
+                let target = match request.find(|attr| match attr {
+                    Attribute::ResponsePort(port) => Some(*port),
+                    _ => None,
+                }) {
+                    // Whoever holds that public port, if anyone still does.
+                    Some(port) => bindings
+                        .iter()
+                        .find(|(_, binding)| binding.port == port)
+                        .map(|(owner, _)| *owner),
+                    None => Some(client),
+                };
+                let Some(target) = target else { continue };
+                let response = Message::new(BINDING_SUCCESS, request.transaction_id).with(Attribute::XorMappedAddress(mapped));
+                let _ = socket.send_to(&response.encode(None, true), target).await;
+            }
+        });
+
+        Ok(Self { address })
+    }
+}
@@ -0,0 +1,83 @@
+use futures_util::{SinkExt, StreamExt};
+use rendezvous_client::phoenix::Frame;
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::Message;
+
+/// In-process stand-in for the Phoenix coordinator's websocket endpoint.
+///
+/// Every `phx_join` is acknowledged and followed by a `presence_state` push with
+/// `presence`. Every other push is answered with `{"status": "ok", "response": <payload>}`.
+/// Frames received from clients are forwarded to `frames` so tests can assert on them.
+/// [`MockCoordinator::drop_connections`] simulates a coordinator restart.
+pub struct MockCoordinator {
+    address: SocketAddr,
+    pub frames: mpsc::UnboundedReceiver<Frame>,
+    kick: broadcast::Sender<()>,
+}
+
+impl MockCoordinator {
+    pub async fn spawn(presence: Value) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let (frames_tx, frames) = mpsc::unbounded_channel();
+        let (kick, _) = broadcast::channel(1);
+
+        let kick_tx = kick.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let frames_tx = frames_tx.clone();
+                let presence = presence.clone();
+                let mut kicked = kick_tx.subscribe();
+                tokio::spawn(async move {
+                    let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    loop {
+                        let msg = tokio::select! {
+                            msg = ws.next() => msg,
+                            _ = kicked.recv() => return,
+                        };
+                        let Some(Ok(msg)) = msg else { return };
+                        let Message::Text(txt) = msg else { continue };
+                        let Some(frame) = Frame::decode(&txt) else { continue };
+                        let _ = frames_tx.send(frame.clone());
+
+                        let reply = Frame {
+                            event: "phx_reply".to_string(),
+                            payload: json!({ "status": "ok", "response": frame.payload }),
+                            ..frame.clone()
+                        };
+                        if ws.send(Message::Text(reply.encode())).await.is_err() {
+                            return;
+                        }
+                        if frame.event == "phx_join" {
+                            let push = Frame {
+                                msg_ref: None,
+                                event: "presence_state".to_string(),
+                                payload: presence.clone(),
+                                ..frame
+                            };
+                            if ws.send(Message::Text(push.encode())).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(Self { address, frames, kick })
+    }
+
+    /// Closes every open websocket without a close frame; new connections are still accepted.
+    pub fn drop_connections(&self) {
+        let _ = self.kick.send(());
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://{}/socket/websocket?vsn=2.0.0", self.address)
+    }
+}
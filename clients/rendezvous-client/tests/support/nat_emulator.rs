@@ -0,0 +1,116 @@
+use rendezvous_client::nat::Behavior;
+use rendezvous_client::stun::{Attribute, BINDING_REQUEST, BINDING_SUCCESS, Message};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+/// Public address the emulated NAT maps clients onto.
+pub const PUBLIC_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+/// RFC 5780 STUN server on 127.0.0.1 / 127.0.0.2 (two ports each) behind which every client
+/// appears to sit behind a NAT with the given mapping and filtering behaviour.
+///
+/// The NAT is emulated at the server: reported mapped ports are allocated per the mapping
+/// rule, and responses the NAT's filter would drop are never sent.
+pub struct NatEmulator {
+    pub primary: SocketAddr,
+}
+
+#[derive(Default)]
+struct NatState {
+    /// (client, mapping key) -> public port; the key is `None` for endpoint-independent mapping.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// (client, server address) pairs the client has sent to, i.e. holes in the filter.
+    contacted: HashSet<(SocketAddr, SocketAddr)>,
+    next_port: u16,
+}
+
+impl NatEmulator {
+    pub async fn spawn(mapping: Behavior, filtering: Behavior) -> std::io::Result<Self> {
+        let sockets = Arc::new(bind_server_sockets().await?);
+        let primary = sockets[0].local_addr()?;
+        let state = Arc::new(Mutex::new(NatState {
+            next_port: 40000,
+            ..NatState::default()
+        }));
+
+        for index in 0..sockets.len() {
+            let sockets = Arc::clone(&sockets);
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let addrs: Vec<SocketAddr> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+                let mut buf = vec![0_u8; 1500];
+                while let Ok((n, client)) = sockets[index].recv_from(&mut buf).await {
+                    let Ok(request) = Message::decode(&buf[..n]) else { continue };
+                    if request.kind != BINDING_REQUEST {
+                        continue;
+                    }
+                    let (change_ip, change_port) = request
+                        .attributes
+                        .iter()
+                        .find_map(|attr| match attr {
+                            Attribute::ChangeRequest { change_ip, change_port } => Some((*change_ip, *change_port)),
+                            _ => None,
+                        })
+                        .unwrap_or((false, false));
+                    // Index bit 1 selects the IP, bit 0 the port.
+                    let origin = index ^ (usize::from(change_ip) << 1) ^ usize::from(change_port);
+
+                    let mapped = {
+                        let mut state = state.lock().unwrap();
+                        state.contacted.insert((client, addrs[index]));
+                        let key = match mapping {
+                            Behavior::EndpointIndependent => None,
+                            Behavior::AddressDependent => Some(SocketAddr::new(addrs[index].ip(), 0)),
+                            Behavior::AddressAndPortDependent => Some(addrs[index]),
+                        };
+                        let next = state.next_port;
+                        let port = *state.mappings.entry((client, key)).or_insert(next);
+                        if port == next {
+                            state.next_port += 1;
+                        }
+                        let open = match filtering {
+                            Behavior::EndpointIndependent => true,
+                            Behavior::AddressDependent => state
+                                .contacted
+                                .iter()
+                                .any(|(c, dst)| *c == client && dst.ip() == addrs[origin].ip()),
+                            Behavior::AddressAndPortDependent => state.contacted.contains(&(client, addrs[origin])),
+                        };
+                        if !open {
+                            continue;
+                        }
+                        SocketAddr::new(PUBLIC_IP, port)
+                    };
+
+                    let response = Message::new(BINDING_SUCCESS, request.transaction_id)
+                        .with(Attribute::XorMappedAddress(mapped))
+                        .with(Attribute::OtherAddress(addrs[3]))
+                        .with(Attribute::ResponseOrigin(addrs[origin]));
+                    let _ = sockets[origin].send_to(&response.encode(None, true), client).await;
+                }
+            });
+        }
+
+        Ok(Self { primary })
+    }
+}
+
+/// A1:P1, A1:P2, A2:P1, A2:P2 with A1 = 127.0.0.1 and A2 = 127.0.0.2.
+async fn bind_server_sockets() -> std::io::Result<Vec<UdpSocket>> {
+    let alternate = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    loop {
+        let a1p1 = UdpSocket::bind("127.0.0.1:0").await?;
+        let a1p2 = UdpSocket::bind("127.0.0.1:0").await?;
+        let (p1, p2) = (a1p1.local_addr()?.port(), a1p2.local_addr()?.port());
+        // The same port numbers may be taken on the alternate IP; just try another pair.
+        let (Ok(a2p1), Ok(a2p2)) = (
+            UdpSocket::bind(SocketAddr::new(alternate, p1)).await,
+            UdpSocket::bind(SocketAddr::new(alternate, p2)).await,
+        ) else {
+            continue;
+        };
+        return Ok(vec![a1p1, a1p2, a2p1, a2p2]);
+    }
+}
@@ -0,0 +1,86 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// In-process NAT between one inside client and one outside peer.
+///
+/// The client sends to [`NatSimulator::inside`]; datagrams leave towards the peer from the current
+/// public mapping, and whatever the peer sends to that mapping is delivered back to the client.
+/// [`NatSimulator::rebind`] drops the mapping and opens a new one on another port, as a NAT does
+/// when a binding times out or the client roams; traffic to the old mapping is lost.
+pub struct NatSimulator {
+    pub inside: SocketAddr,
+    peer: SocketAddr,
+    inside_socket: Arc<UdpSocket>,
+    /// The inside client, learned from its first datagram.
+    client: Arc<Mutex<Option<SocketAddr>>>,
+    public: Arc<Mutex<Arc<UdpSocket>>>,
+    inbound: JoinHandle<()>,
+}
+
+impl NatSimulator {
+    pub async fn spawn(peer: SocketAddr) -> std::io::Result<Self> {
+        let inside_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let inside = inside_socket.local_addr()?;
+        let client = Arc::new(Mutex::new(None));
+        let public = Arc::new(Mutex::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await?)));
+
+        {
+            let (inside_socket, client, public) = (Arc::clone(&inside_socket), Arc::clone(&client), Arc::clone(&public));
+            tokio::spawn(async move {
+                let mut buf = vec![0_u8; 2048];
+                while let Ok((n, from)) = inside_socket.recv_from(&mut buf).await {
+                    *client.lock().unwrap() = Some(from);
+                    let socket = Arc::clone(&public.lock().unwrap());
+                    let _ = socket.send_to(&buf[..n], peer).await;
+                }
+            });
+        }
+
+        let inbound = inbound(Arc::clone(&public.lock().unwrap()), Arc::clone(&inside_socket), Arc::clone(&client), peer);
+        Ok(Self {
+            inside,
+            peer,
+            inside_socket,
+            client,
+            public,
+            inbound,
+        })
+    }
+
+    /// The client's current public endpoint, as the peer sees it.
+    pub fn public(&self) -> SocketAddr {
+        self.public.lock().unwrap().local_addr().unwrap()
+    }
+
+    /// Replaces the public mapping with one on a fresh port; returns the new endpoint.
+    pub async fn rebind(&mut self) -> std::io::Result<SocketAddr> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        *self.public.lock().unwrap() = Arc::clone(&socket);
+        self.inbound.abort();
+        self.inbound = inbound(socket, Arc::clone(&self.inside_socket), Arc::clone(&self.client), self.peer);
+        Ok(self.public())
+    }
+}
+
+/// Forwards the peer's datagrams on `socket` to the inside client.
+fn inbound(
+    socket: Arc<UdpSocket>,
+    inside_socket: Arc<UdpSocket>,
+    client: Arc<Mutex<Option<SocketAddr>>>,
+    peer: SocketAddr,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut buf = vec![0_u8; 2048];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            // Address-and-port-dependent filtering: only the peer gets through.
+            let client = *client.lock().unwrap();
+            if from == peer
+                && let Some(client) = client
+            {
+                let _ = inside_socket.send_to(&buf[..n], client).await;
+            }
+        }
+    })
+}
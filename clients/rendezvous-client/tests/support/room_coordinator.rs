@@ -0,0 +1,182 @@
+use futures_util::{SinkExt, StreamExt};
+use rendezvous_client::phoenix::Frame;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+/// In-process stand-in for the coordinator's rendezvous channel and UDP server, one room per
+/// topic.
+///
+/// As in the real one, join params become the presence meta (with `udp` if the client already
+/// registered), `candidates` pushes are broadcast to the others as `candidates_seen`, an
+/// `overlay_ip` push updates the meta as `Presence.update` does and every UDP registration is
+/// answered and broadcast as `udp_seen`. Clients that disconnect leave.
+pub struct RoomCoordinator {
+    pub http_port: u16,
+    pub udp_port: u16,
+}
+
+#[derive(Default)]
+struct Rooms {
+    /// Joined clients per topic: their meta and where their channel's frames go.
+    members: HashMap<String, HashMap<String, (Value, mpsc::UnboundedSender<Frame>)>>,
+    /// Registered UDP endpoints per topic and client.
+    udp: HashMap<String, HashMap<String, String>>,
+}
+
+impl Rooms {
+    fn broadcast(&self, topic: &str, event: &str, payload: Value, except: Option<&str>) {
+        for (client_id, (_, frames)) in self.members.get(topic).into_iter().flatten() {
+            if Some(client_id.as_str()) != except {
+                let _ = frames.send(push(topic, event, payload.clone()));
+            }
+        }
+    }
+}
+
+fn push(topic: &str, event: &str, payload: Value) -> Frame {
+    Frame {
+        join_ref: None,
+        msg_ref: None,
+        topic: topic.to_string(),
+        event: event.to_string(),
+        payload,
+    }
+}
+
+impl RoomCoordinator {
+    /// Listens on `ip`, on ports the OS picks.
+    pub async fn spawn(ip: IpAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+        let udp = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+        let coordinator = Self {
+            http_port: listener.local_addr()?.port(),
+            udp_port: udp.local_addr()?.port(),
+        };
+        let rooms = Arc::new(Mutex::new(Rooms::default()));
+
+        let udp_rooms = rooms.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0_u8; 2048];
+            while let Ok((n, from)) = udp.recv_from(&mut buf).await {
+                let Ok(registration) = serde_json::from_slice::<Value>(&buf[..n]) else {
+                    continue;
+                };
+                let (Some(room), Some(client_id)) = (registration["room"].as_str(), registration["client_id"].as_str())
+                else {
+                    continue;
+                };
+                let topic = format!("rendezvous:{room}");
+                {
+                    let mut rooms = udp_rooms.lock().unwrap();
+                    rooms.udp.entry(topic.clone()).or_default().insert(client_id.to_string(), from.to_string());
+                    let seen = json!({ "client_id": client_id, "udp": from.to_string() });
+                    rooms.broadcast(&topic, "udp_seen", seen, None);
+                }
+                let _ = udp.send_to(from.to_string().as_bytes(), from).await;
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, rooms.clone()));
+            }
+        });
+        Ok(coordinator)
+    }
+}
+
+async fn serve(stream: tokio::net::TcpStream, rooms: Arc<Mutex<Rooms>>) {
+    let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    let (frames_tx, mut frames) = mpsc::unbounded_channel::<Frame>();
+    let mut joined: Vec<(String, String)> = Vec::new();
+    loop {
+        let frame = tokio::select! {
+            msg = ws.next() => {
+                let Some(Ok(msg)) = msg else { break };
+                let Message::Text(txt) = msg else { continue };
+                let Some(frame) = Frame::decode(&txt) else { continue };
+                frame
+            }
+            Some(out) = frames.recv() => {
+                if ws.send(Message::Text(out.encode())).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let reply = Frame {
+            event: "phx_reply".to_string(),
+            payload: json!({ "status": "ok", "response": {} }),
+            ..frame.clone()
+        };
+        if ws.send(Message::Text(reply.encode())).await.is_err() {
+            break;
+        }
+        let topic = frame.topic.as_str();
+        let mut rooms = rooms.lock().unwrap();
+        match frame.event.as_str() {
+            "phx_join" => {
+                let Some(client_id) = frame.payload["client_id"].as_str() else { continue };
+                let mut meta: Map<String, Value> = frame.payload.as_object().cloned().unwrap_or_default();
+                meta.remove("client_id");
+                meta.retain(|_, value| !value.is_null());
+                meta.insert("phx_ref".to_string(), json!(format!("{client_id}-{}", joined.len())));
+                if let Some(udp) = rooms.udp.get(topic).and_then(|udp| udp.get(client_id)) {
+                    meta.insert("udp".to_string(), json!(udp));
+                }
+                let meta = Value::Object(meta);
+                let diff = json!({ "joins": { client_id: { "metas": [meta.clone()] } }, "leaves": {} });
+                rooms.broadcast(topic, "presence_diff", diff, None);
+                let members = rooms.members.entry(topic.to_string()).or_default();
+                members.insert(client_id.to_string(), (meta, frames_tx.clone()));
+                let state: Map<String, Value> = members
+                    .iter()
+                    .map(|(id, (meta, _))| (id.clone(), json!({ "metas": [meta] })))
+                    .collect();
+                let _ = frames_tx.send(push(topic, "presence_state", Value::Object(state)));
+                joined.push((topic.to_string(), client_id.to_string()));
+            }
+            "candidates" => {
+                let Some((_, client_id)) = joined.iter().find(|(joined, _)| joined == topic) else { continue };
+                let seen = json!({ "client_id": client_id, "candidates": frame.payload["candidates"] });
+                rooms.broadcast(topic, "candidates_seen", seen, Some(client_id));
+            }
+            "overlay_ip" => {
+                let Some((_, client_id)) = joined.iter().find(|(joined, _)| joined == topic) else { continue };
+                let (Some(ip), Some((meta, _))) = (
+                    frame.payload["overlay_ip"].as_str(),
+                    rooms.members.get_mut(topic).and_then(|members| members.get_mut(client_id)),
+                ) else {
+                    continue;
+                };
+                // The updated meta replaces the old one under a fresh phx_ref.
+                let old = meta.clone();
+                meta["overlay_ip"] = json!(ip);
+                meta["phx_ref"] = json!(format!("{client_id}-{ip}"));
+                let diff = json!({
+                    "joins": { client_id: { "metas": [meta.clone()] } },
+                    "leaves": { client_id: { "metas": [old] } },
+                });
+                rooms.broadcast(topic, "presence_diff", diff, None);
+            }
+            _ => {}
+        }
+    }
+
+    let mut rooms = rooms.lock().unwrap();
+    for (topic, client_id) in joined {
+        let Some((meta, _)) = rooms.members.get_mut(&topic).and_then(|members| members.remove(&client_id)) else {
+            continue;
+        };
+        let diff = json!({ "joins": {}, "leaves": { client_id: { "metas": [meta] } } });
+        rooms.broadcast(&topic, "presence_diff", diff, None);
+    }
+}
@@ -0,0 +1,49 @@
+use rendezvous_client::stun::{Attribute, BINDING_ERROR, BINDING_REQUEST, BINDING_SUCCESS, Message, verify_integrity};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::UdpSocket;
+
+/// In-process STUN server answering Binding requests with the source address it saw.
+///
+/// The first `drop_first` requests are ignored to exercise retransmission. With `password`
+/// set, requests must carry a valid MESSAGE-INTEGRITY and responses are signed with it.
+pub struct StunResponder {
+    pub address: SocketAddr,
+    requests: Arc<AtomicUsize>,
+}
+
+impl StunResponder {
+    pub async fn spawn(drop_first: usize, password: Option<&'static [u8]>) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let address = socket.local_addr()?;
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let seen = Arc::clone(&requests);
+        tokio::spawn(async move {
+            let mut buf = vec![0_u8; 1500];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                let Ok(request) = Message::decode(&buf[..n]) else { continue };
+                if request.kind != BINDING_REQUEST || seen.fetch_add(1, Ordering::SeqCst) < drop_first {
+                    continue;
+                }
+                let response = match password {
+                    Some(key) if verify_integrity(&buf[..n], key).is_err() => {
+                        Message::new(BINDING_ERROR, request.transaction_id).with(Attribute::ErrorCode {
+                            code: 401,
+                            reason: "Unauthorized".to_string(),
+                        })
+                    }
+                    _ => Message::new(BINDING_SUCCESS, request.transaction_id).with(Attribute::XorMappedAddress(from)),
+                };
+                let _ = socket.send_to(&response.encode(password, true), from).await;
+            }
+        });
+
+        Ok(Self { address, requests })
+    }
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
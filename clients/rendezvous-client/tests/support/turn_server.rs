@@ -0,0 +1,157 @@
+use rendezvous_client::stun::{Attribute, Message, verify_integrity};
+use rendezvous_client::turn::{
+    ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, DATA_INDICATION, REFRESH, SEND_INDICATION, decode_channel_data,
+    encode_channel_data, long_term_key,
+};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+pub const REALM: &str = "rendezvous.test";
+pub const USERNAME: &str = "alice";
+pub const PASSWORD: &str = "wonderland";
+const NONCE: &str = "f00dface";
+
+/// Minimal single-allocation-per-client TURN server on 127.0.0.1.
+///
+/// Implements what the client uses: long-term auth with a fixed nonce, Allocate, Refresh,
+/// CreatePermission, ChannelBind, Send indications and ChannelData. Each allocation gets its
+/// own relay socket; inbound peer datagrams are delivered only if the peer IP has a permission.
+pub struct TurnServer {
+    pub address: SocketAddr,
+}
+
+struct Allocation {
+    relay: Arc<UdpSocket>,
+    permissions: HashSet<IpAddr>,
+    channels: HashMap<u16, SocketAddr>,
+}
+
+type Allocations = Arc<Mutex<HashMap<SocketAddr, Allocation>>>;
+
+impl TurnServer {
+    pub async fn spawn() -> std::io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let address = socket.local_addr()?;
+        let allocations: Allocations = Arc::default();
+        let key = long_term_key(USERNAME, REALM, PASSWORD);
+
+        tokio::spawn(async move {
+            let mut buf = vec![0_u8; 1500];
+            while let Ok((n, client)) = socket.recv_from(&mut buf).await {
+                let packet = &buf[..n];
+                if let Some((number, range)) = decode_channel_data(packet) {
+                    let allocations = allocations.lock().unwrap();
+                    if let Some(alloc) = allocations.get(&client)
+                        && let Some(peer) = alloc.channels.get(&number)
+                    {
+                        let _ = alloc.relay.try_send_to(&packet[range], *peer);
+                    }
+                    continue;
+                }
+                let Ok(request) = Message::decode(packet) else { continue };
+                if request.kind == SEND_INDICATION {
+                    let peer = peer_of(&request);
+                    let data = request.find(|attr| match attr {
+                        Attribute::Data(data) => Some(data.clone()),
+                        _ => None,
+                    });
+                    let allocations = allocations.lock().unwrap();
+                    if let (Some(alloc), Some(peer), Some(data)) = (allocations.get(&client), peer, data)
+                        && alloc.permissions.contains(&peer.ip())
+                    {
+                        let _ = alloc.relay.try_send_to(&data, peer);
+                    }
+                    continue;
+                }
+
+                let authenticated = request.attributes.contains(&Attribute::Nonce(NONCE.to_string()))
+                    && request.attributes.contains(&Attribute::Username(USERNAME.to_string()))
+                    && verify_integrity(packet, &key).is_ok();
+                if !authenticated {
+                    let challenge = Message::new(request.kind | 0x0110, request.transaction_id)
+                        .with(Attribute::ErrorCode {
+                            code: 401,
+                            reason: "Unauthorized".to_string(),
+                        })
+                        .with(Attribute::Realm(REALM.to_string()))
+                        .with(Attribute::Nonce(NONCE.to_string()));
+                    let _ = socket.send_to(&challenge.encode(None, true), client).await;
+                    continue;
+                }
+
+                let mut response = Message::new(request.kind | 0x0100, request.transaction_id);
+                match request.kind {
+                    ALLOCATE => {
+                        let Ok(relay) = UdpSocket::bind("127.0.0.1:0").await else { continue };
+                        let relay = Arc::new(relay);
+                        let relayed = relay.local_addr().unwrap();
+                        allocations.lock().unwrap().insert(
+                            client,
+                            Allocation {
+                                relay: Arc::clone(&relay),
+                                permissions: HashSet::new(),
+                                channels: HashMap::new(),
+                            },
+                        );
+                        tokio::spawn(relay_inbound(relay, Arc::clone(&socket), client, Arc::clone(&allocations)));
+                        response = response
+                            .with(Attribute::XorRelayedAddress(relayed))
+                            .with(Attribute::XorMappedAddress(client))
+                            .with(Attribute::Lifetime(600));
+                    }
+                    REFRESH => {
+                        response = response.with(Attribute::Lifetime(600));
+                    }
+                    CREATE_PERMISSION | CHANNEL_BIND => {
+                        let mut allocations = allocations.lock().unwrap();
+                        let (Some(alloc), Some(peer)) = (allocations.get_mut(&client), peer_of(&request)) else {
+                            continue;
+                        };
+                        alloc.permissions.insert(peer.ip());
+                        if let Some(number) = request.find(|attr| match attr {
+                            Attribute::ChannelNumber(number) => Some(*number),
+                            _ => None,
+                        }) {
+                            alloc.channels.insert(number, peer);
+                        }
+                    }
+                    _ => continue,
+                }
+                let _ = socket.send_to(&response.encode(Some(&key), true), client).await;
+            }
+        });
+
+        Ok(Self { address })
+    }
+}
+
+fn peer_of(message: &Message) -> Option<SocketAddr> {
+    message.find(|attr| match attr {
+        Attribute::XorPeerAddress(addr) => Some(*addr),
+        _ => None,
+    })
+}
+
+/// Delivers peer datagrams arriving on a relay socket to the allocation's client.
+async fn relay_inbound(relay: Arc<UdpSocket>, server: Arc<UdpSocket>, client: SocketAddr, allocations: Allocations) {
+    let mut buf = vec![0_u8; 1500];
+    while let Ok((n, peer)) = relay.recv_from(&mut buf).await {
+        let packet = {
+            let allocations = allocations.lock().unwrap();
+            let Some(alloc) = allocations.get(&client) else { return };
+            if !alloc.permissions.contains(&peer.ip()) {
+                continue;
+            }
+            match alloc.channels.iter().find(|(_, bound)| **bound == peer) {
+                Some((number, _)) => encode_channel_data(*number, &buf[..n]),
+                None => Message::request(DATA_INDICATION)
+                    .with(Attribute::XorPeerAddress(peer))
+                    .with(Attribute::Data(buf[..n].to_vec()))
+                    .encode(None, false),
+            }
+        };
+        let _ = server.send_to(&packet, client).await;
+    }
+}
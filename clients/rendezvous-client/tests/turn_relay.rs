@@ -0,0 +1,108 @@
+use rendezvous_client::turn::{TurnClient, TurnConfig};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+#[path = "support/turn_server.rs"]
+mod turn_server;
+
+const WAIT: Duration = Duration::from_secs(1);
+
+async fn allocate(server: SocketAddr, password: &str) -> anyhow::Result<TurnClient> {
+    let mut config = TurnConfig::new(server, turn_server::USERNAME, password);
+    config.options.rto = Duration::from_millis(50);
+    TurnClient::allocate(UdpSocket::bind("127.0.0.1:0").await?, config).await
+}
+
+#[tokio::test]
+async fn relays_both_ways_over_indications_then_channels() -> anyhow::Result<()> {
+    let server = turn_server::TurnServer::spawn().await?;
+    let client = allocate(server.address, turn_server::PASSWORD).await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let relayed = client.relayed_address();
+    assert_ne!(relayed, server.address);
+
+    // Without a permission the relay drops the peer's datagrams.
+    peer.send_to(b"too early", relayed).await?;
+    client.create_permission(peer.local_addr()?.ip()).await?;
+
+    // Send/Data indications.
+    client.send_to(b"ping 1", peer.local_addr()?).await?;
+    let mut buf = [0_u8; 64];
+    let (n, from) = timeout(WAIT, peer.recv_from(&mut buf)).await??;
+    assert_eq!((&buf[..n], from), (&b"ping 1"[..], relayed));
+    peer.send_to(b"pong 1", relayed).await?;
+    let (n, from) = timeout(WAIT, client.recv_from(&mut buf)).await??;
+    assert_eq!((&buf[..n], from), (&b"pong 1"[..], peer.local_addr()?));
+
+    // ChannelData once a channel is bound.
+    client.channel_bind(peer.local_addr()?).await?;
+    client.send_to(b"ping 2", peer.local_addr()?).await?;
+    let (n, _) = timeout(WAIT, peer.recv_from(&mut buf)).await??;
+    assert_eq!(&buf[..n], b"ping 2");
+    peer.send_to(b"pong 2", relayed).await?;
+    let (n, from) = timeout(WAIT, client.recv_from(&mut buf)).await??;
+    assert_eq!((&buf[..n], from), (&b"pong 2"[..], peer.local_addr()?));
+
+    client.keep_alive().await?;
+    client.release().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_relayed_data_that_arrives_around_requests() -> anyhow::Result<()> {
+    let server = turn_server::TurnServer::spawn().await?;
+    let client = allocate(server.address, turn_server::PASSWORD).await?;
+    let peer = UdpSocket::bind("127.0.0.1:0").await?;
+    let relayed = client.relayed_address();
+    client.create_permission(peer.local_addr()?.ip()).await?;
+
+    // Relayed before a request whose response then arrives behind it.
+    peer.send_to(b"before bind", relayed).await?;
+    client.channel_bind(peer.local_addr()?).await?;
+    let mut buf = [0_u8; 64];
+    let (n, from) = timeout(WAIT, client.recv_from(&mut buf)).await??;
+    assert_eq!((&buf[..n], from), (&b"before bind"[..], peer.local_addr()?));
+
+    // A request on one clone while another waits for data.
+    let refresher = client.clone();
+    let (permitted, received) = tokio::join!(refresher.create_permission(peer.local_addr()?.ip()), async {
+        peer.send_to(b"during permission", relayed).await?;
+        timeout(WAIT, client.recv_from(&mut buf)).await?
+    });
+    permitted?;
+    let (n, _) = received?;
+    assert_eq!(&buf[..n], b"during permission");
+    Ok(())
+}
+
+#[tokio::test]
+async fn binds_in_flight_together_take_their_own_channels() -> anyhow::Result<()> {
+    let server = turn_server::TurnServer::spawn().await?;
+    let client = allocate(server.address, turn_server::PASSWORD).await?;
+    let (a, b) = (UdpSocket::bind("127.0.0.1:0").await?, UdpSocket::bind("127.0.0.1:0").await?);
+
+    // The mesh binds trickled candidates from beside its loop, so binds can overlap.
+    let (bound_a, bound_b) = tokio::join!(client.channel_bind(a.local_addr()?), client.channel_bind(b.local_addr()?));
+    let (bound_a, bound_b) = (bound_a?, bound_b?);
+    assert_ne!(bound_a, bound_b);
+    assert_eq!(client.channel_bind(a.local_addr()?).await?, bound_a);
+
+    let mut buf = [0_u8; 64];
+    for (peer, data) in [(&a, &b"to a"[..]), (&b, &b"to b"[..])] {
+        client.send_to(data, peer.local_addr()?).await?;
+        let (n, _) = timeout(WAIT, peer.recv_from(&mut buf)).await??;
+        assert_eq!(&buf[..n], data);
+    }
+    client.release().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejects_wrong_credentials() -> anyhow::Result<()> {
+    let server = turn_server::TurnServer::spawn().await?;
+    let err = allocate(server.address, "not the password").await.err().expect("allocation must fail");
+    assert!(format!("{err:#}").contains("401"), "{err:#}");
+    Ok(())
+}
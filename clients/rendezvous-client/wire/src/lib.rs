@@ -0,0 +1,413 @@
+//! Binary peer-to-peer wire protocol.
+//!
+//! Every datagram between two peers is one packet:
+//!
+//! ```text
+//!  0       1       2               10          14      16          16+len      32+len
+//! +-------+-------+---------------+-----------+-------+-----------+-----------+
+//! |version| type  |  session id   | sequence  |  len  |   body    |  tag      |
+//! |  u8   |  u8   |     u64       |   u32     |  u16  | len bytes | 16 bytes  |
+//! +-------+-------+---------------+-----------+-------+-----------+-----------+
+//! ```
+//!
+//! Integers are big-endian. The session id names the peer pair (both sides derive the same
+//! one), the sequence number counts packets per sender and session, and the tag is
+//! HMAC-SHA256 over everything before it, truncated to 128 bits, keyed with the pair's key.
+//! A receiver reads the session id with [`session_of`] to pick the key, then [`Packet::decode`]s.
+//!
+//! Probes and keepalives carry the sender's clock in microseconds (any epoch; only differences
+//! are meaningful) and acks echo it, so the sender gets an RTT sample without keeping send times,
+//! and the receiver can measure jitter from the sender's spacing.
+//!
+//! Data is end-to-end encrypted: handshake messages carry a Noise IK exchange and data bodies
+//! carry a counter and ChaCha20-Poly1305 ciphertext under the resulting keys. Hellos and their
+//! acks are laid out like data, but are the channel's own control traffic and never application
+//! datagrams. The tag only authenticates the framing; this crate treats all of them as opaque
+//! bytes.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 16;
+pub const TAG_LEN: usize = 16;
+pub const NONCE_LEN: usize = 16;
+pub const PATH_DATA_LEN: usize = 8;
+pub const TIMESTAMP_LEN: usize = 8;
+/// Largest body the length field can describe.
+pub const MAX_BODY: usize = u16::MAX as usize;
+
+/// Set on a [`Message::Probe`] to nominate the pair it travels on, and echoed on its
+/// [`Message::ProbeAck`].
+pub const FLAG_NOMINATE: u8 = 0x01;
+const KNOWN_FLAGS: u8 = FLAG_NOMINATE;
+
+pub type Nonce = [u8; NONCE_LEN];
+
+/// Type byte of each message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    Probe = 1,
+    ProbeAck = 2,
+    Keepalive = 3,
+    Data = 4,
+    Close = 5,
+    PathChallenge = 6,
+    PathResponse = 7,
+    HandshakeInit = 8,
+    HandshakeResponse = 9,
+    Hello = 10,
+    HelloAck = 11,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Probe,
+            2 => Self::ProbeAck,
+            3 => Self::Keepalive,
+            4 => Self::Data,
+            5 => Self::Close,
+            6 => Self::PathChallenge,
+            7 => Self::PathResponse,
+            8 => Self::HandshakeInit,
+            9 => Self::HandshakeResponse,
+            10 => Self::Hello,
+            11 => Self::HelloAck,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Connectivity check (or, with [`FLAG_NOMINATE`], a nomination) carrying a fresh nonce.
+    Probe { flags: u8, nonce: Nonce, timestamp: u64 },
+    /// Answers a probe or keepalive by echoing its nonce and timestamp.
+    ProbeAck { flags: u8, nonce: Nonce, timestamp: u64 },
+    /// Keeps NAT bindings open on an established path; answered with a probe-ack.
+    Keepalive { nonce: Nonce, timestamp: u64 },
+    /// Encrypted application datagram; `counter` is the AEAD nonce and must not repeat.
+    Data { counter: u64, payload: Vec<u8> },
+    /// The sender is tearing the session down; `code` 0 is a normal shutdown.
+    Close { code: u16 },
+    /// Asks the peer to prove it receives on the address the packet came from.
+    PathChallenge { data: [u8; PATH_DATA_LEN] },
+    /// Echoes a path challenge from the address being validated.
+    PathResponse { data: [u8; PATH_DATA_LEN] },
+    /// First handshake message of the data channel (initiator to responder).
+    HandshakeInit { payload: Vec<u8> },
+    /// Second handshake message, completing the data channel.
+    HandshakeResponse { payload: Vec<u8> },
+    /// Says hello over the open data channel, sealed like [`Message::Data`]; repeated until acked.
+    Hello { counter: u64, payload: Vec<u8> },
+    /// Answers a hello, sealed like [`Message::Data`].
+    HelloAck { counter: u64, payload: Vec<u8> },
+}
+
+impl Message {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Self::Probe { .. } => MessageType::Probe,
+            Self::ProbeAck { .. } => MessageType::ProbeAck,
+            Self::Keepalive { .. } => MessageType::Keepalive,
+            Self::Data { .. } => MessageType::Data,
+            Self::Close { .. } => MessageType::Close,
+            Self::PathChallenge { .. } => MessageType::PathChallenge,
+            Self::PathResponse { .. } => MessageType::PathResponse,
+            Self::HandshakeInit { .. } => MessageType::HandshakeInit,
+            Self::HandshakeResponse { .. } => MessageType::HandshakeResponse,
+            Self::Hello { .. } => MessageType::Hello,
+            Self::HelloAck { .. } => MessageType::HelloAck,
+        }
+    }
+
+    fn encode_body(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Probe { flags, nonce, timestamp } | Self::ProbeAck { flags, nonce, timestamp } => {
+                out.push(*flags);
+                out.extend_from_slice(nonce);
+                out.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            Self::Keepalive { nonce, timestamp } => {
+                out.extend_from_slice(nonce);
+                out.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            Self::Data { counter, payload }
+            | Self::Hello { counter, payload }
+            | Self::HelloAck { counter, payload } => {
+                out.extend_from_slice(&counter.to_be_bytes());
+                out.extend_from_slice(payload);
+            }
+            Self::HandshakeInit { payload } | Self::HandshakeResponse { payload } => out.extend_from_slice(payload),
+            Self::Close { code } => out.extend_from_slice(&code.to_be_bytes()),
+            Self::PathChallenge { data } | Self::PathResponse { data } => out.extend_from_slice(data),
+        }
+    }
+
+    fn decode_body(kind: MessageType, body: &[u8]) -> Result<Self, DecodeError> {
+        let exact = |len: usize| {
+            if body.len() == len {
+                Ok(())
+            } else {
+                Err(DecodeError::BodyLength {
+                    kind,
+                    expected: len,
+                    actual: body.len(),
+                })
+            }
+        };
+        Ok(match kind {
+            MessageType::Probe | MessageType::ProbeAck => {
+                exact(1 + NONCE_LEN + TIMESTAMP_LEN)?;
+                let flags = body[0];
+                if flags & !KNOWN_FLAGS != 0 {
+                    return Err(DecodeError::UnknownFlags(flags));
+                }
+                let nonce = body[1..1 + NONCE_LEN].try_into().expect("length checked");
+                let timestamp = u64::from_be_bytes(body[1 + NONCE_LEN..].try_into().expect("length checked"));
+                if kind == MessageType::Probe {
+                    Self::Probe { flags, nonce, timestamp }
+                } else {
+                    Self::ProbeAck { flags, nonce, timestamp }
+                }
+            }
+            MessageType::Keepalive => {
+                exact(NONCE_LEN + TIMESTAMP_LEN)?;
+                Self::Keepalive {
+                    nonce: body[..NONCE_LEN].try_into().expect("length checked"),
+                    timestamp: u64::from_be_bytes(body[NONCE_LEN..].try_into().expect("length checked")),
+                }
+            }
+            MessageType::Data | MessageType::Hello | MessageType::HelloAck => {
+                if body.len() < 8 {
+                    return Err(DecodeError::BodyLength {
+                        kind,
+                        expected: 8,
+                        actual: body.len(),
+                    });
+                }
+                let (counter, payload) = body.split_at(8);
+                let counter = u64::from_be_bytes(counter.try_into().expect("split at 8"));
+                let payload = payload.to_vec();
+                match kind {
+                    MessageType::Hello => Self::Hello { counter, payload },
+                    MessageType::HelloAck => Self::HelloAck { counter, payload },
+                    _ => Self::Data { counter, payload },
+                }
+            }
+            MessageType::HandshakeInit => Self::HandshakeInit { payload: body.to_vec() },
+            MessageType::HandshakeResponse => Self::HandshakeResponse { payload: body.to_vec() },
+            MessageType::Close => {
+                exact(2)?;
+                Self::Close {
+                    code: u16::from_be_bytes([body[0], body[1]]),
+                }
+            }
+            MessageType::PathChallenge | MessageType::PathResponse => {
+                exact(PATH_DATA_LEN)?;
+                let data = body.try_into().expect("length checked");
+                if kind == MessageType::PathChallenge {
+                    Self::PathChallenge { data }
+                } else {
+                    Self::PathResponse { data }
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub session: u64,
+    pub sequence: u32,
+    pub message: Message,
+}
+
+impl Packet {
+    pub fn encode(&self, key: &[u8]) -> Result<Vec<u8>, EncodeError> {
+        let mut out = Vec::with_capacity(HEADER_LEN + 1 + NONCE_LEN + TIMESTAMP_LEN + TAG_LEN);
+        out.push(VERSION);
+        out.push(self.message.message_type() as u8);
+        out.extend_from_slice(&self.session.to_be_bytes());
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&[0, 0]);
+        self.message.encode_body(&mut out);
+        let body_len = out.len() - HEADER_LEN;
+        let len = u16::try_from(body_len).map_err(|_| EncodeError::BodyTooLarge(body_len))?;
+        out[14..16].copy_from_slice(&len.to_be_bytes());
+        let tag = tag(key, &out);
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+
+    /// Decodes and authenticates a packet. Structure is checked before the tag, so malformed
+    /// input gets a specific error; a well-formed packet under the wrong key is
+    /// [`DecodeError::BadTag`].
+    pub fn decode(buf: &[u8], key: &[u8]) -> Result<Self, DecodeError> {
+        let session = session_of(buf)?;
+        if buf[0] != VERSION {
+            return Err(DecodeError::UnsupportedVersion(buf[0]));
+        }
+        let kind = MessageType::from_u8(buf[1]).ok_or(DecodeError::UnknownType(buf[1]))?;
+        let sequence = u32::from_be_bytes(buf[10..14].try_into().expect("header checked"));
+        let len = usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+        let expected = HEADER_LEN + len + TAG_LEN;
+        if buf.len() != expected {
+            return Err(DecodeError::Length {
+                expected,
+                actual: buf.len(),
+            });
+        }
+        let (signed, received) = buf.split_at(HEADER_LEN + len);
+        let message = Message::decode_body(kind, &signed[HEADER_LEN..])?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+        mac.update(signed);
+        mac.verify_truncated_left(received).map_err(|_| DecodeError::BadTag)?;
+        Ok(Self {
+            session,
+            sequence,
+            message,
+        })
+    }
+}
+
+/// The session id of a packet, to pick the key for [`Packet::decode`]. Only the header length
+/// is checked.
+pub fn session_of(buf: &[u8]) -> Result<u64, DecodeError> {
+    if buf.len() < HEADER_LEN + TAG_LEN {
+        return Err(DecodeError::Truncated {
+            needed: HEADER_LEN + TAG_LEN,
+            actual: buf.len(),
+        });
+    }
+    Ok(u64::from_be_bytes(buf[2..10].try_into().expect("length checked")))
+}
+
+fn tag(key: &[u8], signed: &[u8]) -> [u8; TAG_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(signed);
+    let full = mac.finalize().into_bytes();
+    full[..TAG_LEN].try_into().expect("sha256 output is longer than the tag")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    BodyTooLarge(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BodyTooLarge(len) => write!(f, "body of {len} bytes exceeds the {MAX_BODY} byte limit"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Shorter than an empty packet (header and tag).
+    Truncated { needed: usize, actual: usize },
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    /// The datagram is not exactly header + declared body + tag.
+    Length { expected: usize, actual: usize },
+    /// The body does not have the size its type requires (for data and hellos, its minimum).
+    BodyLength {
+        kind: MessageType,
+        expected: usize,
+        actual: usize,
+    },
+    UnknownFlags(u8),
+    /// Well-formed, but not authenticated by the key.
+    BadTag,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { needed, actual } => write!(f, "packet of {actual} bytes is shorter than {needed}"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported protocol version {version}"),
+            Self::UnknownType(kind) => write!(f, "unknown message type {kind}"),
+            Self::Length { expected, actual } => {
+                write!(f, "packet is {actual} bytes but its header describes {expected}")
+            }
+            Self::BodyLength { kind, expected, actual } => {
+                write!(f, "{kind:?} body is {actual} bytes, expected {expected}")
+            }
+            Self::UnknownFlags(flags) => write!(f, "unknown flags {flags:#04x}"),
+            Self::BadTag => f.write_str("authentication tag mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeError, FLAG_NOMINATE, HEADER_LEN, Message, MessageType, Packet, TAG_LEN, session_of};
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn probe_layout_is_stable() {
+        let packet = Packet {
+            session: 0x0102_0304_0506_0708,
+            sequence: 9,
+            message: Message::Probe {
+                flags: FLAG_NOMINATE,
+                nonce: [0xaa; 16],
+                timestamp: 0x1122,
+            },
+        };
+        let bytes = packet.encode(KEY).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 25 + TAG_LEN);
+        assert_eq!(&bytes[..HEADER_LEN], [1, 1, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 9, 0, 25]);
+        assert_eq!(bytes[HEADER_LEN], FLAG_NOMINATE);
+        assert_eq!(&bytes[HEADER_LEN + 17..HEADER_LEN + 25], [0, 0, 0, 0, 0, 0, 0x11, 0x22]);
+        assert_eq!(session_of(&bytes), Ok(0x0102_0304_0506_0708));
+        assert_eq!(Packet::decode(&bytes, KEY), Ok(packet));
+    }
+
+    #[test]
+    fn malformed_input_gets_a_specific_error() {
+        let close = Packet {
+            session: 1,
+            sequence: 0,
+            message: Message::Close { code: 0 },
+        };
+        let bytes = close.encode(KEY).unwrap();
+
+        assert_eq!(
+            Packet::decode(&bytes[..20], KEY),
+            Err(DecodeError::Truncated { needed: 32, actual: 20 })
+        );
+        let mut other = bytes.clone();
+        other[0] = 2;
+        assert_eq!(Packet::decode(&other, KEY), Err(DecodeError::UnsupportedVersion(2)));
+        other[0] = 1;
+        other[1] = 0x7f;
+        assert_eq!(Packet::decode(&other, KEY), Err(DecodeError::UnknownType(0x7f)));
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            Packet::decode(&longer, KEY),
+            Err(DecodeError::Length { expected: 34, actual: 35 })
+        );
+        let mut as_probe = bytes.clone();
+        as_probe[1] = MessageType::Probe as u8;
+        assert_eq!(
+            Packet::decode(&as_probe, KEY),
+            Err(DecodeError::BodyLength {
+                kind: MessageType::Probe,
+                expected: 25,
+                actual: 2
+            })
+        );
+        assert_eq!(Packet::decode(&bytes, b"another key"), Err(DecodeError::BadTag));
+    }
+}
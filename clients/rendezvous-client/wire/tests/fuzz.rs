@@ -0,0 +1,72 @@
+use peer_wire::{FLAG_NOMINATE, Message, Packet, session_of};
+use proptest::prelude::*;
+
+const KEY: &[u8] = b"fuzzing key, not a secret at all";
+
+fn message() -> impl Strategy<Value = Message> {
+    let flags = prop_oneof![Just(0), Just(FLAG_NOMINATE)];
+    prop_oneof![
+        (flags.clone(), any::<[u8; 16]>(), any::<u64>()).prop_map(|(flags, nonce, timestamp)| Message::Probe {
+            flags,
+            nonce,
+            timestamp
+        }),
+        (flags, any::<[u8; 16]>(), any::<u64>()).prop_map(|(flags, nonce, timestamp)| Message::ProbeAck {
+            flags,
+            nonce,
+            timestamp
+        }),
+        (any::<[u8; 16]>(), any::<u64>()).prop_map(|(nonce, timestamp)| Message::Keepalive { nonce, timestamp }),
+        (any::<u64>(), proptest::collection::vec(any::<u8>(), 0..1500))
+            .prop_map(|(counter, payload)| Message::Data { counter, payload }),
+        (any::<u64>(), proptest::collection::vec(any::<u8>(), 0..64))
+            .prop_map(|(counter, payload)| Message::Hello { counter, payload }),
+        (any::<u64>(), proptest::collection::vec(any::<u8>(), 0..64))
+            .prop_map(|(counter, payload)| Message::HelloAck { counter, payload }),
+        proptest::collection::vec(any::<u8>(), 0..200).prop_map(|payload| Message::HandshakeInit { payload }),
+        proptest::collection::vec(any::<u8>(), 0..200).prop_map(|payload| Message::HandshakeResponse { payload }),
+        any::<u16>().prop_map(|code| Message::Close { code }),
+        any::<[u8; 8]>().prop_map(|data| Message::PathChallenge { data }),
+        any::<[u8; 8]>().prop_map(|data| Message::PathResponse { data }),
+    ]
+}
+
+fn packet() -> impl Strategy<Value = Packet> {
+    (any::<u64>(), any::<u32>(), message()).prop_map(|(session, sequence, message)| Packet {
+        session,
+        sequence,
+        message,
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2000))]
+
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let _ = session_of(&bytes);
+        prop_assert!(Packet::decode(&bytes, KEY).is_err(), "random bytes must not authenticate");
+    }
+
+    #[test]
+    fn packets_round_trip(packet in packet()) {
+        let bytes = packet.encode(KEY).unwrap();
+        prop_assert_eq!(session_of(&bytes), Ok(packet.session));
+        prop_assert_eq!(Packet::decode(&bytes, KEY), Ok(packet));
+    }
+
+    #[test]
+    fn any_corruption_is_rejected(packet in packet(), index in any::<prop::sample::Index>(), flip in 1..=255_u8) {
+        let mut bytes = packet.encode(KEY).unwrap();
+        let index = index.index(bytes.len());
+        bytes[index] ^= flip;
+        prop_assert!(Packet::decode(&bytes, KEY).is_err());
+    }
+
+    #[test]
+    fn truncation_is_rejected(packet in packet(), cut in any::<prop::sample::Index>()) {
+        let bytes = packet.encode(KEY).unwrap();
+        let cut = cut.index(bytes.len());
+        prop_assert!(Packet::decode(&bytes[..cut], KEY).is_err());
+    }
+}
@@ -7,12 +7,15 @@ defmodule CoordinatorWeb.RendezvousChannel do
   alias CoordinatorWeb.Presence
 
   @impl true
-  def join("rendezvous:" <> room, %{"client_id" => client_id}, socket)
+  def join("rendezvous:" <> room, %{"client_id" => client_id} = params, socket)
       when is_binary(room) and is_binary(client_id) do
     socket =
       socket
       |> assign(:room, room)
       |> assign(:client_id, client_id)
+      |> assign(:nat, nat_meta(params))
+      |> assign(:static_key, static_key(params))
+      |> assign(:overlay_ip, overlay_ip(params))
 
     send(self(), :after_join)
     {:ok, socket}
@@ -32,9 +35,55 @@ defmodule CoordinatorWeb.RendezvousChannel do
         :error ->
           %{}
       end
+      |> put_nat(socket.assigns.nat)
+      |> put_static_key(socket.assigns.static_key)
+      |> put_overlay_ip(socket.assigns.overlay_ip)
 
     {:ok, _} = Presence.track(socket, client_id, meta)
     push(socket, "presence_state", Presence.list(topic))
     {:noreply, socket}
   end
+
+  # ICE-style candidates (host, server-reflexive, relay) a client gathered; peers pair them with
+  # their own and check the pairs. Clients re-announce when a peer joins, so nothing is stored.
+  @impl true
+  def handle_in("candidates", %{"candidates" => candidates}, socket) when is_list(candidates) do
+    broadcast_from!(socket, "candidates_seen", %{
+      "client_id" => socket.assigns.client_id,
+      "candidates" => candidates
+    })
+
+    {:reply, :ok, socket}
+  end
+
+  # A client that lost its overlay address to a smaller client id claims another one; peers see
+  # the new meta in a presence diff.
+  def handle_in("overlay_ip", %{"overlay_ip" => ip}, socket) when is_binary(ip) do
+    {:ok, _} = Presence.update(socket, socket.assigns.client_id, &Map.put(&1, "overlay_ip", ip))
+    {:reply, :ok, assign(socket, :overlay_ip, ip)}
+  end
+
+  # NAT behaviour the client classified itself (RFC 5780); passed through to peers untouched.
+  defp nat_meta(%{"nat" => nat}) when is_map(nat), do: nat
+  defp nat_meta(_params), do: nil
+
+  defp put_nat(meta, nil), do: meta
+  defp put_nat(meta, nat), do: Map.put(meta, "nat", nat)
+
+  # Long-term X25519 public key for the Noise IK handshake of the data channel, and from which
+  # peers derive the key that authenticates UDP probes; hex-encoded.
+  # Presence is keyed by client id, so peers learn which key belongs to which client from here.
+  defp static_key(%{"static_key" => key}) when is_binary(key), do: key
+  defp static_key(_params), do: nil
+
+  defp put_static_key(meta, nil), do: meta
+  defp put_static_key(meta, key), do: Map.put(meta, "static_key", key)
+
+  # Address of the client's TUN device in the overlay network; peers route packets for it to
+  # the client and only accept packets from it with this source.
+  defp overlay_ip(%{"overlay_ip" => ip}) when is_binary(ip), do: ip
+  defp overlay_ip(_params), do: nil
+
+  defp put_overlay_ip(meta, nil), do: meta
+  defp put_overlay_ip(meta, ip), do: Map.put(meta, "overlay_ip", ip)
 end
@@ -7,7 +7,7 @@ cleanup() {
     kill "${COORD_PID}" >/dev/null 2>&1 || true
     wait "${COORD_PID}" >/dev/null 2>&1 || true
   fi
-  for ns in cliA natA cliB natB; do
+  for ns in cliA natA cliB natB cliC natC; do
     ip netns del "${ns}" >/dev/null 2>&1 || true
   done
 }
@@ -49,109 +49,110 @@ if ! curl -fsS "http://127.0.0.1:${PORT}/healthz" >/dev/null 2>&1; then
   exit 1
 fi
 
-ip netns add natA
-ip netns add cliA
-ip netns add natB
-ip netns add cliB
-
 # Simulated "internet": a Linux bridge in the root namespace.
 ip link add br_wan type bridge
 ip addr add 100.64.0.1/24 dev br_wan
 ip link set br_wan up
 
-# natA WAN <-> root bridge
-ip link add veth_natA_root type veth peer name veth_natA_wan
-ip link set veth_natA_wan netns natA
-ip link set veth_natA_root master br_wan
-ip link set veth_natA_root up
-
-ip netns exec natA ip link set lo up
-ip netns exec natA ip addr add 100.64.0.2/24 dev veth_natA_wan
-ip netns exec natA ip link set veth_natA_wan up
-ip netns exec natA ip route add default via 100.64.0.1
-
-# natA LAN <-> cliA
-ip link add veth_natA_lan type veth peer name veth_cliA
-ip link set veth_natA_lan netns natA
-ip link set veth_cliA netns cliA
-ip netns exec natA ip addr add 10.0.1.1/24 dev veth_natA_lan
-ip netns exec natA ip link set veth_natA_lan up
-
-ip netns exec cliA ip link set lo up
-ip netns exec cliA ip addr add 10.0.1.2/24 dev veth_cliA
-ip netns exec cliA ip link set veth_cliA up
-ip netns exec cliA ip route add default via 10.0.1.1
-
-# NAT rules for natA.
-ip netns exec natA sysctl -w net.ipv4.ip_forward=1 >/dev/null
-snat_postrouting natA veth_natA_wan 100.64.0.2
-# Full-cone-ish inbound mapping for the single LAN host (cliA).
-# Any inbound UDP to the NAT WAN IP is forwarded to 10.0.1.2 preserving the port.
-ip netns exec natA iptables -t nat -A PREROUTING -i veth_natA_wan -p udp -j DNAT --to-destination 10.0.1.2
-ip netns exec natA iptables -A FORWARD -i veth_natA_lan -o veth_natA_wan -j ACCEPT
-ip netns exec natA iptables -A FORWARD -i veth_natA_wan -o veth_natA_lan -p udp -j ACCEPT
-ip netns exec natA iptables -A FORWARD -i veth_natA_wan -o veth_natA_lan -m state --state ESTABLISHED,RELATED -j ACCEPT
-
-# natB WAN <-> root bridge
-ip link add veth_natB_root type veth peer name veth_natB_wan
-ip link set veth_natB_wan netns natB
-ip link set veth_natB_root master br_wan
-ip link set veth_natB_root up
-
-ip netns exec natB ip link set lo up
-ip netns exec natB ip addr add 100.64.0.3/24 dev veth_natB_wan
-ip netns exec natB ip link set veth_natB_wan up
-ip netns exec natB ip route add default via 100.64.0.1
-
-# natB LAN <-> cliB
-ip link add veth_natB_lan type veth peer name veth_cliB
-ip link set veth_natB_lan netns natB
-ip link set veth_cliB netns cliB
-ip netns exec natB ip addr add 10.0.2.1/24 dev veth_natB_lan
-ip netns exec natB ip link set veth_natB_lan up
-
-ip netns exec cliB ip link set lo up
-ip netns exec cliB ip addr add 10.0.2.2/24 dev veth_cliB
-ip netns exec cliB ip link set veth_cliB up
-ip netns exec cliB ip route add default via 10.0.2.1
-
-# NAT rules for natB.
-ip netns exec natB sysctl -w net.ipv4.ip_forward=1 >/dev/null
-snat_postrouting natB veth_natB_wan 100.64.0.3
-# Full-cone-ish inbound mapping for the single LAN host (cliB).
-ip netns exec natB iptables -t nat -A PREROUTING -i veth_natB_wan -p udp -j DNAT --to-destination 10.0.2.2
-ip netns exec natB iptables -A FORWARD -i veth_natB_lan -o veth_natB_wan -j ACCEPT
-ip netns exec natB iptables -A FORWARD -i veth_natB_wan -o veth_natB_lan -p udp -j ACCEPT
-ip netns exec natB iptables -A FORWARD -i veth_natB_wan -o veth_natB_lan -m state --state ESTABLISHED,RELATED -j ACCEPT
+# One client namespace behind its own NAT namespace per site:
+#   add_site <name> <nat wan ip> <lan prefix, e.g. 10.0.1>
+# The client gets <prefix>.2, the NAT's LAN side <prefix>.1.
+add_site() {
+  local site="$1"
+  local wan_ip="$2"
+  local lan="$3"
+  local nat="nat${site}"
+  local cli="cli${site}"
+
+  ip netns add "${nat}"
+  ip netns add "${cli}"
+
+  # NAT WAN <-> root bridge
+  ip link add "veth_${nat}_root" type veth peer name "veth_${nat}_wan"
+  ip link set "veth_${nat}_wan" netns "${nat}"
+  ip link set "veth_${nat}_root" master br_wan
+  ip link set "veth_${nat}_root" up
+
+  ip netns exec "${nat}" ip link set lo up
+  ip netns exec "${nat}" ip addr add "${wan_ip}/24" dev "veth_${nat}_wan"
+  ip netns exec "${nat}" ip link set "veth_${nat}_wan" up
+  ip netns exec "${nat}" ip route add default via 100.64.0.1
+
+  # NAT LAN <-> client
+  ip link add "veth_${nat}_lan" type veth peer name "veth_${cli}"
+  ip link set "veth_${nat}_lan" netns "${nat}"
+  ip link set "veth_${cli}" netns "${cli}"
+  ip netns exec "${nat}" ip addr add "${lan}.1/24" dev "veth_${nat}_lan"
+  ip netns exec "${nat}" ip link set "veth_${nat}_lan" up
+
+  ip netns exec "${cli}" ip link set lo up
+  ip netns exec "${cli}" ip addr add "${lan}.2/24" dev "veth_${cli}"
+  ip netns exec "${cli}" ip link set "veth_${cli}" up
+  ip netns exec "${cli}" ip route add default via "${lan}.1"
+
+  # NAT rules.
+  ip netns exec "${nat}" sysctl -w net.ipv4.ip_forward=1 >/dev/null
+  snat_postrouting "${nat}" "veth_${nat}_wan" "${wan_ip}"
+  # Full-cone-ish inbound mapping for the single LAN host.
+  # Any inbound UDP to the NAT WAN IP is forwarded to the client preserving the port.
+  ip netns exec "${nat}" iptables -t nat -A PREROUTING -i "veth_${nat}_wan" -p udp -j DNAT --to-destination "${lan}.2"
+  ip netns exec "${nat}" iptables -A FORWARD -i "veth_${nat}_lan" -o "veth_${nat}_wan" -j ACCEPT
+  ip netns exec "${nat}" iptables -A FORWARD -i "veth_${nat}_wan" -o "veth_${nat}_lan" -p udp -j ACCEPT
+  ip netns exec "${nat}" iptables -A FORWARD -i "veth_${nat}_wan" -o "veth_${nat}_lan" -m state --state ESTABLISHED,RELATED -j ACCEPT
+}
+
+# Three sites, so every client holds a session with each of the other two (a small mesh).
+add_site A 100.64.0.2 10.0.1
+add_site B 100.64.0.3 10.0.2
+add_site C 100.64.0.4 10.0.3
 
 export ROOM="${ROOM:-demo}"
 export TIMEOUT_SECS="${TIMEOUT_SECS:-20}"
 
+run_client() {
+  local site="$1"
+  local id="$2"
+  local peers="$3"
+  ip netns exec "cli${site}" env \
+    COORDINATOR_HOST="100.64.0.1" \
+    COORDINATOR_HTTP_PORT="${PORT}" \
+    COORDINATOR_UDP_PORT="3478" \
+    ROOM="${ROOM}" \
+    CLIENT_ID="${id}" \
+    PEERS="${peers}" \
+    TIMEOUT_SECS="${TIMEOUT_SECS}" \
+    REPORT="json" \
+    rendezvous-client | tee "/tmp/client-${id}.log"
+}
+
+# The last line of each client's output is its JSON run report: it must have reached both peers
+# over a direct path.
+check_report() {
+  local id="$1"
+  tail -n 1 "/tmp/client-${id}.log" | jq -e '
+    .ok and ([.peers[] | select(.reached and .path == "direct")] | length) == 2
+  ' >/dev/null || {
+    echo "natlab: client ${id} report does not show two direct peers:" >&2
+    tail -n 1 "/tmp/client-${id}.log" >&2
+    exit 1
+  }
+}
+
 set +e
-ip netns exec cliA env \
-  COORDINATOR_HOST="100.64.0.1" \
-  COORDINATOR_HTTP_PORT="${PORT}" \
-  COORDINATOR_UDP_PORT="3478" \
-  ROOM="${ROOM}" \
-  CLIENT_ID="a" \
-  PEER_ID="b" \
-  TIMEOUT_SECS="${TIMEOUT_SECS}" \
-  rendezvous-client &
+run_client A a b,c &
 PID_A=$!
-
-ip netns exec cliB env \
-  COORDINATOR_HOST="100.64.0.1" \
-  COORDINATOR_HTTP_PORT="${PORT}" \
-  COORDINATOR_UDP_PORT="3478" \
-  ROOM="${ROOM}" \
-  CLIENT_ID="b" \
-  PEER_ID="a" \
-  TIMEOUT_SECS="${TIMEOUT_SECS}" \
-  rendezvous-client &
+run_client B b a,c &
 PID_B=$!
+run_client C c a,b &
+PID_C=$!
 set -e
 
 wait "${PID_A}"
 wait "${PID_B}"
+wait "${PID_C}"
+
+for id in a b c; do
+  check_report "${id}"
+done
 
-echo "natlab ok: both clients exchanged direct UDP messages"
+echo "natlab ok: all three clients exchanged direct UDP messages with each other"